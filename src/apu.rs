@@ -0,0 +1,453 @@
+use alloc::vec::Vec;
+
+use crate::state::StateReader;
+use crate::timing;
+
+// NTSC CPU clock; the APU produces one sample per CPU cycle
+pub const CPU_CLOCK_HZ: u32 = timing::NTSC.cpu_hz;
+
+// CPU cycles the DMC memory reader steals for each sample byte
+pub const DMC_DMA_STALL_CYCLES: u8 = 4;
+
+// Lookup tables shared by the channels, NTSC values.
+
+// DMC timer periods in CPU cycles, indexed by the low four bits of $4010
+pub const DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+// noise timer periods in CPU cycles, indexed by the low four bits of $400E
+pub const NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+// length counter loads, indexed by the top five bits of $4003/$4007/$400B/$400F
+pub const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// pulse 1, pulse 2, triangle and noise, in $4015 bit order
+const LENGTH_CHANNELS: usize = 4;
+
+const STATUS_DMC_ACTIVE: u8 = 1 << 4;
+const STATUS_DMC_IRQ: u8 = 1 << 7;
+
+// Delta modulation channel: plays 1-bit deltas fetched from CPU memory.
+#[derive(Debug)]
+struct Dmc {
+    is_irq_enabled: bool,
+    is_loop: bool,
+    period: u16,
+    timer: u16,
+    output_level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    is_silenced: bool,
+    is_irq_pending: bool,
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Self {
+            is_irq_enabled: false,
+            is_loop: false,
+            period: DMC_RATES[0],
+            timer: DMC_RATES[0],
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            is_silenced: true,
+            is_irq_pending: false,
+        }
+    }
+}
+
+impl Dmc {
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x4010 => {
+                self.is_irq_enabled = data & 0x80 != 0;
+                self.is_loop = data & 0x40 != 0;
+                self.period = DMC_RATES[(data & 0x0F) as usize];
+                if !self.is_irq_enabled {
+                    self.is_irq_pending = false;
+                }
+            }
+            0x4011 => self.output_level = data & 0x7F,
+            0x4012 => self.sample_address = 0xC000 | (data as u16) << 6,
+            0x4013 => self.sample_length = ((data as u16) << 4) + 1,
+            _ => (),
+        }
+    }
+
+    fn set_enabled(&mut self, is_enabled: bool) {
+        self.is_irq_pending = false;
+        if !is_enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn clock(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period;
+        if !self.is_silenced {
+            if self.shift & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+            self.shift >>= 1;
+        }
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.shift = sample;
+                    self.is_silenced = false;
+                }
+                None => self.is_silenced = true,
+            }
+        }
+    }
+
+    fn dma_address(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    fn load_sample(&mut self, sample: u8) {
+        self.sample_buffer = Some(sample);
+        // the address wraps into $8000, not $0000
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.is_loop {
+                self.restart();
+            } else if self.is_irq_enabled {
+                self.is_irq_pending = true;
+            }
+        }
+    }
+
+    fn save_state(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&[self.is_irq_enabled as u8, self.is_loop as u8]);
+        for value in [
+            self.period,
+            self.timer,
+            self.sample_address,
+            self.sample_length,
+            self.current_address,
+            self.bytes_remaining,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[
+            self.output_level,
+            self.sample_buffer.is_some() as u8,
+            self.sample_buffer.unwrap_or(0),
+            self.shift,
+            self.bits_remaining,
+            self.is_silenced as u8,
+            self.is_irq_pending as u8,
+        ]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Option<()> {
+        self.is_irq_enabled = state.bool()?;
+        self.is_loop = state.bool()?;
+        self.period = state.u16()?;
+        self.timer = state.u16()?;
+        self.sample_address = state.u16()?;
+        self.sample_length = state.u16()?;
+        self.current_address = state.u16()?;
+        self.bytes_remaining = state.u16()?;
+        self.output_level = state.u8()?;
+        let has_sample = state.bool()?;
+        let sample = state.u8()?;
+        self.sample_buffer = has_sample.then_some(sample);
+        self.shift = state.u8()?;
+        self.bits_remaining = state.u8()?;
+        self.is_silenced = state.bool()?;
+        self.is_irq_pending = state.bool()?;
+        // clock counts these down before checking them
+        if self.period == 0 || self.timer == 0 || self.bits_remaining == 0 {
+            return None;
+        }
+        Some(())
+    }
+}
+
+// Silences its channel when it reaches zero. Only loading and clearing are
+// modelled; there is no frame counter to count it down yet.
+#[derive(Debug, Default)]
+struct LengthCounter {
+    is_enabled: bool,
+    value: u8,
+}
+
+impl LengthCounter {
+    fn set_enabled(&mut self, is_enabled: bool) {
+        self.is_enabled = is_enabled;
+        if !is_enabled {
+            self.value = 0;
+        }
+    }
+
+    // Loads are ignored while the channel is disabled through $4015.
+    fn load(&mut self, index: u8) {
+        if self.is_enabled {
+            self.value = LENGTH_TABLE[index as usize];
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Apu {
+    length_counters: [LengthCounter; LENGTH_CHANNELS],
+    dmc: Dmc,
+    // when off, channels keep running but no samples are buffered
+    is_output_enabled: bool,
+    // mixer output at the CPU clock rate, not yet drained
+    samples: Vec<f32>,
+    // resampler state carried between drains so output stays continuous
+    phase: u64,
+    sum: f32,
+    count: u32,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self {
+            length_counters: Default::default(),
+            dmc: Dmc::default(),
+            is_output_enabled: true,
+            samples: Vec::new(),
+            phase: 0,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_output_enabled(&mut self, is_enabled: bool) {
+        self.is_output_enabled = is_enabled;
+    }
+
+    pub fn is_output_enabled(&self) -> bool {
+        self.is_output_enabled
+    }
+
+    pub fn write_register(&mut self, address: u16, data: u8) {
+        match address {
+            // the fourth register of each channel
+            0x4003 | 0x4007 | 0x400B | 0x400F => {
+                self.length_counters[(address as usize - 0x4000) / 4].load(data >> 3)
+            }
+            0x4010..=0x4013 => self.dmc.write(address, data),
+            0x4015 => {
+                for (channel, counter) in self.length_counters.iter_mut().enumerate() {
+                    counter.set_enabled(data & 1 << channel != 0);
+                }
+                self.dmc.set_enabled(data & STATUS_DMC_ACTIVE != 0);
+            }
+            _ => (),
+        }
+    }
+
+    // $4015: a bit per channel whose length counter is nonzero, DMC bytes
+    // left and the DMC IRQ flag
+    pub fn status(&self) -> u8 {
+        let mut status = 0;
+        for (channel, counter) in self.length_counters.iter().enumerate() {
+            if counter.value > 0 {
+                status |= 1 << channel;
+            }
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= STATUS_DMC_ACTIVE;
+        }
+        if self.dmc.is_irq_pending {
+            status |= STATUS_DMC_IRQ;
+        }
+        status
+    }
+
+    // Level the APU drives onto the IRQ line.
+    pub fn is_irq_asserted(&self) -> bool {
+        self.dmc.is_irq_pending
+    }
+
+    // Advances the channels by one CPU cycle.
+    pub fn clock(&mut self) {
+        self.dmc.clock();
+    }
+
+    // Address the DMC wants to read a sample byte from; the bus performs the
+    // read, stalling the CPU, and hands the byte back through load_dmc_sample.
+    pub fn dmc_dma_address(&self) -> Option<u16> {
+        self.dmc.dma_address()
+    }
+
+    pub fn load_dmc_sample(&mut self, sample: u8) {
+        self.dmc.load_sample(sample);
+    }
+
+    // Channel state for save states. Buffered samples and the resampler are
+    // output, so a loaded state keeps the ones already produced.
+    pub fn save_state(&self, bytes: &mut Vec<u8>) {
+        for counter in &self.length_counters {
+            bytes.extend_from_slice(&[counter.is_enabled as u8, counter.value]);
+        }
+        self.dmc.save_state(bytes);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Option<()> {
+        for counter in self.length_counters.iter_mut() {
+            counter.is_enabled = state.bool()?;
+            counter.value = state.u8()?;
+        }
+        self.dmc.load_state(state)
+    }
+
+    pub fn push_sample(&mut self, sample: f32) {
+        if self.is_output_enabled {
+            self.samples.push(sample);
+        }
+    }
+
+    // Downsamples everything produced since the last drain to target_hz.
+    // Each output sample is the average of the input samples in its period,
+    // a box filter that is enough to keep the channels from aliasing badly.
+    pub fn drain_samples(&mut self, target_hz: u32) -> Vec<f32> {
+        let mut output = Vec::with_capacity(
+            (self.samples.len() as u64 * target_hz as u64 / CPU_CLOCK_HZ as u64) as usize + 1,
+        );
+        for sample in self.samples.drain(..) {
+            self.sum += sample;
+            self.count += 1;
+            self.phase += target_hz as u64;
+            if self.phase >= CPU_CLOCK_HZ as u64 {
+                self.phase -= CPU_CLOCK_HZ as u64;
+                output.push(self.sum / self.count as f32);
+                self.sum = 0.0;
+                self.count = 0;
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tables() {
+        assert_eq!(LENGTH_TABLE[0x00], 10);
+        assert_eq!(LENGTH_TABLE[0x01], 254);
+        assert_eq!(LENGTH_TABLE[0x08], 160);
+        assert_eq!(LENGTH_TABLE[0x1F], 30);
+        assert_eq!((NOISE_PERIODS[0], NOISE_PERIODS[15]), (4, 4068));
+        assert_eq!((DMC_RATES[0], DMC_RATES[15]), (428, 54));
+    }
+
+    #[test]
+    fn test_dmc_sample_fetch() {
+        let mut apu = Apu::new();
+        // $C040, 17 bytes
+        apu.write_register(0x4012, 0x01);
+        apu.write_register(0x4013, 0x01);
+        assert_eq!(apu.dmc_dma_address(), None);
+        apu.write_register(0x4015, STATUS_DMC_ACTIVE);
+        assert_eq!(apu.dmc_dma_address(), Some(0xC040));
+        apu.load_dmc_sample(0xFF);
+        assert_eq!(apu.dmc_dma_address(), None);
+        assert_eq!(apu.dmc.bytes_remaining, 16);
+        // the buffer empties into the shifter once the silent cycle ends
+        for _ in 0..DMC_RATES[0] as usize * 8 {
+            apu.clock();
+        }
+        assert_eq!(apu.dmc_dma_address(), Some(0xC041));
+    }
+
+    #[test]
+    fn test_channel_enable_status() {
+        let mut apu = Apu::new();
+        // loads while disabled are dropped
+        apu.write_register(0x4003, 0x08);
+        assert_eq!(apu.status(), 0x00);
+        apu.write_register(0x4015, 0x0F);
+        apu.write_register(0x4003, 0x08);
+        apu.write_register(0x400B, 0x00);
+        assert_eq!(apu.status(), 0x05);
+        assert_eq!(apu.length_counters[0].value, 254);
+        assert_eq!(apu.length_counters[2].value, 10);
+        // disabling clears the counter
+        apu.write_register(0x4015, 0x0B);
+        assert_eq!(apu.status(), 0x01);
+
+        // a $4015 write also acknowledges the DMC IRQ
+        apu.dmc.is_irq_pending = true;
+        assert_eq!(apu.status() & STATUS_DMC_IRQ, STATUS_DMC_IRQ);
+        apu.write_register(0x4015, 0x01);
+        assert_eq!(apu.status(), 0x01);
+    }
+
+    #[test]
+    fn test_drain_samples() {
+        let mut apu = Apu::new();
+        for _ in 0..CPU_CLOCK_HZ {
+            apu.push_sample(0.5);
+        }
+        let samples = apu.drain_samples(44_100);
+        assert_eq!(samples.len(), 44_100);
+        assert!(samples.iter().all(|sample| *sample == 0.5));
+        assert!(apu.drain_samples(44_100).is_empty());
+    }
+
+    #[test]
+    fn test_drain_samples_across_calls() {
+        let mut apu = Apu::new();
+        let mut total = 0;
+        for _ in 0..4 {
+            for _ in 0..CPU_CLOCK_HZ / 4 {
+                apu.push_sample(0.25);
+            }
+            total += apu.drain_samples(48_000).len();
+        }
+        let cycles = CPU_CLOCK_HZ / 4 * 4;
+        assert_eq!(total as u64, cycles as u64 * 48_000 / CPU_CLOCK_HZ as u64);
+    }
+}
@@ -1,50 +1,720 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Debug};
+use core::ops::RangeInclusive;
+
+use crate::apu;
+use crate::controller;
+use crate::ppu;
 use crate::rom;
+use crate::signal;
+use crate::state::{self, StateReader};
+
+pub const RAM_SIZE: usize = 2048;
+// $2000-$401F, backed by RAM in BusMode::FlatIo
+const IO_SCRATCH_SIZE: usize = 0x2020;
+const FLAT_MEMORY_SIZE: usize = 0x10000;
+const RESET_VECTOR_ADDR: u16 = 0xFFFC;
+
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+
+    // Reads without side effects on devices, for debuggers and memory viewers.
+    fn peek(&self, address: u16) -> u8;
+
+    fn write(&mut self, address: u16, data: u8);
+
+    // Reads a little-endian word from address and the byte after it,
+    // wrapping from $FFFF to $0000. The 6502's page-wrapping indirect reads
+    // are not this.
+    fn read_u16_le(&mut self, address: u16) -> u16 {
+        u16::from_le_bytes([self.read(address), self.read(address.wrapping_add(1))])
+    }
+
+    fn peek_u16_le(&self, address: u16) -> u16 {
+        u16::from_le_bytes([self.peek(address), self.peek(address.wrapping_add(1))])
+    }
+
+    // Advances devices clocked alongside the CPU by the given cycles and
+    // returns how many extra cycles DMA stalled the CPU for.
+    fn tick(&mut self, _cycles: u8) -> u8 {
+        0
+    }
+
+    // Puts devices into their power-on state; runs before the CPU reads the reset vector.
+    fn power_on(&mut self) {}
+
+    // Level of the IRQ line as driven by devices, ORed with Cpu::set_irq_line.
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Whether a device raised NMI since the last call, ORed with Cpu::set_nmi_line.
+    fn take_nmi(&mut self) -> bool {
+        false
+    }
+}
+
+// Power-on contents of the internal RAM, which is undefined on hardware.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum RamPattern {
+    #[default]
+    Zero,
+    Fill(u8),
+    // four $00 bytes then four $FF bytes, as commonly seen on real consoles
+    Alternating,
+}
+
+// How CpuBus treats the PPU, APU and controller registers.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum BusMode {
+    #[default]
+    Nes,
+    // $2000-$401F is plain RAM, so CPU conformance ROMs and test harnesses
+    // can write anywhere and read it back
+    FlatIo,
+}
+
+// A user supplied device mapped into the CPU address space with
+// CpuBus::map_device. Addresses are passed through unchanged.
+pub trait BusDevice: Debug {
+    fn read(&mut self, address: u16) -> u8;
 
-const RAM_SIZE: usize = 2048;
+    // Like read, without side effects.
+    fn peek(&self, address: u16) -> u8;
+
+    fn write(&mut self, address: u16, data: u8);
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DeviceOverlapError {
+    pub range: RangeInclusive<u16>,
+    pub existing: RangeInclusive<u16>,
+}
+
+impl fmt::Display for DeviceOverlapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "device range {:#06X}-{:#06X} overlaps {:#06X}-{:#06X}",
+            self.range.start(),
+            self.range.end(),
+            self.existing.start(),
+            self.existing.end()
+        )
+    }
+}
 
 #[derive(Debug)]
 pub struct CpuBus {
     ram: [u8; RAM_SIZE],
     cartridge: rom::Cartridge,
+    ppu: ppu::Ppu,
+    apu: apu::Apu,
+    controllers: [controller::Controller; 2],
+    // consulted before the built-in routing
+    devices: Vec<(RangeInclusive<u16>, Box<dyn BusDevice>)>,
+    // stands in for the I/O registers in BusMode::FlatIo
+    io_scratch: Option<Vec<u8>>,
+    // when set, tick leaves the PPU and APU where they are
+    are_clocks_paused: bool,
+    // writes the cartridge ignores, collected while logging is on
+    rom_writes: Option<Vec<(u16, u8)>>,
+    is_dmc_controller_conflict: bool,
+    // port read since the last tick, while the conflict is modelled
+    controller_read: Option<usize>,
+    // coin slots and DIP switches, on VS. System cartridges only
+    vs_inputs: Option<controller::VsInputs>,
+    signals: signal::Signals,
 }
 
 impl CpuBus {
     pub fn new(cartridge: rom::Cartridge) -> Self {
+        Self::with_mode(cartridge, BusMode::default())
+    }
+
+    pub fn with_mode(cartridge: rom::Cartridge, mode: BusMode) -> Self {
+        let is_vs = cartridge.console_type() == rom::ConsoleType::VsUnisystem;
         Self {
             ram: [0; RAM_SIZE],
-            cartridge: cartridge,
+            cartridge,
+            ppu: ppu::Ppu::new(),
+            apu: apu::Apu::new(),
+            controllers: Default::default(),
+            devices: Vec::new(),
+            io_scratch: match mode {
+                BusMode::Nes => None,
+                BusMode::FlatIo => Some(vec![0; IO_SCRATCH_SIZE]),
+            },
+            are_clocks_paused: false,
+            rom_writes: None,
+            is_dmc_controller_conflict: false,
+            controller_read: None,
+            vs_inputs: is_vs.then(controller::VsInputs::default),
+            signals: signal::Signals::default(),
+        }
+    }
+
+    pub fn map_device(
+        &mut self,
+        range: RangeInclusive<u16>,
+        device: Box<dyn BusDevice>,
+    ) -> Result<(), DeviceOverlapError> {
+        let overlap = self.devices.iter().find(|(existing, _)| {
+            range.start() <= existing.end() && existing.start() <= range.end()
+        });
+        if let Some((existing, _)) = overlap {
+            return Err(DeviceOverlapError {
+                range,
+                existing: existing.clone(),
+            });
+        }
+        self.devices.push((range, device));
+        Ok(())
+    }
+
+    fn device_index(&self, address: u16) -> Option<usize> {
+        self.devices.iter().position(|(range, _)| range.contains(&address))
+    }
+
+    pub fn fill_ram(&mut self, pattern: RamPattern) {
+        for (address, byte) in self.ram.iter_mut().enumerate() {
+            *byte = match pattern {
+                RamPattern::Zero => 0x00,
+                RamPattern::Fill(value) => value,
+                RamPattern::Alternating if address & 0x04 == 0 => 0x00,
+                RamPattern::Alternating => 0xFF,
+            };
+        }
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn load_ram(&mut self, ram: &[u8]) {
+        self.ram.copy_from_slice(ram);
+    }
+
+    // Everything but RAM that a save state needs: the PPU, APU, controllers,
+    // interrupt lines, I/O scratch RAM and the cartridge's banks and RAM.
+    // Attached devices and diagnostics are not included.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.ppu.save_state(&mut bytes);
+        self.apu.save_state(&mut bytes);
+        for controller in &self.controllers {
+            controller.save_state(&mut bytes);
+        }
+        self.signals.save_state(&mut bytes);
+        state::write_sized(&mut bytes, self.io_scratch.as_deref().unwrap_or_default());
+        self.cartridge.save_state(&mut bytes);
+        bytes
+    }
+
+    // None if the state was saved from a different setup, in which case the
+    // bus may be left partly loaded.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Option<()> {
+        let mut state = StateReader::new(bytes);
+        self.ppu.load_state(&mut state)?;
+        self.apu.load_state(&mut state)?;
+        for controller in self.controllers.iter_mut() {
+            controller.load_state(&mut state)?;
+        }
+        self.signals.load_state(&mut state)?;
+        state.sized_into(self.io_scratch.as_deref_mut().unwrap_or_default())?;
+        self.cartridge.load_state(&mut state)?;
+        state.is_empty().then_some(())
+    }
+
+    pub fn set_clocks_paused(&mut self, is_paused: bool) {
+        self.are_clocks_paused = is_paused;
+    }
+
+    // Models the 2A03 quirk behind dropped inputs in some games: a DMC
+    // sample fetch in the instruction that read $4016/$4017 repeats the
+    // read, clocking an extra bit out of the controller. Off by default.
+    pub fn set_dmc_controller_conflict(&mut self, is_enabled: bool) {
+        self.is_dmc_controller_conflict = is_enabled;
+        self.controller_read = None;
+    }
+
+    // Diagnostic for programs that write to ROM by mistake: while on, writes
+    // the cartridge ignores are collected for take_rom_writes. They are
+    // still ignored either way.
+    pub fn set_rom_write_logging(&mut self, is_enabled: bool) {
+        self.rom_writes = if is_enabled { Some(Vec::new()) } else { None };
+    }
+
+    // The (address, data) of each ignored write since the last call.
+    pub fn take_rom_writes(&mut self) -> Vec<(u16, u8)> {
+        self.rom_writes.as_mut().map(core::mem::take).unwrap_or_default()
+    }
+
+    // Peeks len bytes from start for hex dumps and disassemblers. Stops at
+    // $FFFF rather than wrapping, so the result can be shorter than len.
+    pub fn read_range(&self, start: u16, len: usize) -> Vec<u8> {
+        let end = (start as usize + len).min(0x10000);
+        (start as usize..end).map(|address| self.peek(address as u16)).collect()
+    }
+
+    // What answers at address, as a label for memory viewers.
+    pub fn describe(&self, address: u16) -> &'static str {
+        if self.device_index(address).is_some() {
+            return "attached device";
+        }
+        if let (Some(_), 0x2000..=0x401F) = (&self.io_scratch, address) {
+            return "I/O scratch RAM";
+        }
+        match address {
+            0x0000..=0x07FF => "RAM",
+            0x0800..=0x1FFF => "RAM (mirrored)",
+            0x2000..=0x2007 => "PPU register",
+            0x2008..=0x3FFF => "PPU register (mirrored)",
+            0x4000..=0x4017 => "APU/IO",
+            0x4018..=0x401F => "APU/IO (disabled)",
+            0x6000..=0x7FFF if self.cartridge.is_writable(address) => "PRG RAM",
+            0x8000..=0xFFFF => "PRG ROM",
+            _ => "open bus",
+        }
+    }
+
+    pub fn cartridge(&self) -> &rom::Cartridge {
+        &self.cartridge
+    }
+
+    pub fn ppu(&self) -> &ppu::Ppu {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut ppu::Ppu {
+        &mut self.ppu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut apu::Apu {
+        &mut self.apu
+    }
+
+    // None unless the cartridge is for the VS. System.
+    pub fn vs_inputs(&self) -> Option<&controller::VsInputs> {
+        self.vs_inputs.as_ref()
+    }
+
+    pub fn vs_inputs_mut(&mut self) -> Option<&mut controller::VsInputs> {
+        self.vs_inputs.as_mut()
+    }
+
+    fn vs_port_bits(&self, port: usize) -> u8 {
+        self.vs_inputs.as_ref().map_or(0, |inputs| inputs.port_bits(port))
+    }
+
+    pub fn controller(&self, port: controller::Port) -> &controller::Controller {
+        &self.controllers[port as usize]
+    }
+
+    pub fn controller_mut(&mut self, port: controller::Port) -> &mut controller::Controller {
+        &mut self.controllers[port as usize]
+    }
+}
+
+impl Bus for CpuBus {
+    fn power_on(&mut self) {
+        self.cartridge.power_on();
+        self.signals = signal::Signals::default();
+    }
+
+    fn irq(&self) -> bool {
+        self.signals.irq.is_asserted()
+    }
+
+    fn take_nmi(&mut self) -> bool {
+        self.signals.nmi.take_edge()
+    }
+
+    fn tick(&mut self, cycles: u8) -> u8 {
+        if self.are_clocks_paused {
+            return 0;
+        }
+        let mut stall: u8 = 0;
+        let mut remaining = cycles;
+        while remaining > 0 {
+            remaining -= 1;
+            self.ppu.clock();
+            self.apu.clock();
+            self.cartridge.clock(&mut self.signals.irq);
+            self.signals.irq.set(signal::IrqSource::Apu, self.apu.is_irq_asserted());
+            self.signals.nmi.set(self.ppu.is_nmi_asserted());
+            if let Some(address) = self.apu.dmc_dma_address() {
+                if let Some(port) = self.controller_read.take() {
+                    self.controllers[port].read();
+                }
+                let sample = self.read(address);
+                self.apu.load_dmc_sample(sample);
+                // the APU keeps running while the CPU is stalled
+                stall += apu::DMC_DMA_STALL_CYCLES;
+                remaining += apu::DMC_DMA_STALL_CYCLES;
+            }
+        }
+        self.controller_read = None;
+        stall
+    }
+
+    fn read(&mut self, address: u16) -> u8 {
+        if let Some(index) = self.device_index(address) {
+            return self.devices[index].1.read(address);
+        }
+        if let (Some(_), 0x2000..=0x401F) = (&self.io_scratch, address) {
+            return self.peek(address);
+        }
+        match address {
+            // reading shifts the next button out
+            0x4016..=0x4017 => {
+                let port = address as usize - 0x4016;
+                if self.is_dmc_controller_conflict {
+                    self.controller_read = Some(port);
+                }
+                self.controllers[port].read() | self.vs_port_bits(port)
+            }
+            0x2000..=0x3FFF => self.ppu.read_register(address & 0x2007),
+            _ => self.peek(address),
         }
     }
 
-    pub fn read(&self, address: u16) -> u8 {
+    fn peek(&self, address: u16) -> u8 {
+        if let Some(index) = self.device_index(address) {
+            return self.devices[index].1.peek(address);
+        }
+        if let (Some(scratch), 0x2000..=0x401F) = (&self.io_scratch, address) {
+            return scratch[address as usize - 0x2000];
+        }
         match address {
             0x0000..=0x1FFF => self.ram[(address & 0x07FF) as usize],
             // PPU registers
-            0x2000..=0x3FFF => 0,
+            0x2000..=0x3FFF => self.ppu.peek_register(address & 0x2007),
+            0x4015 => self.apu.status(),
+            // controllers
+            0x4016..=0x4017 => {
+                let port = address as usize - 0x4016;
+                self.controllers[port].peek() | self.vs_port_bits(port)
+            }
             // APU and I/O registers
             0x4000..=0x4017 => 0,
             // APU and I/O functionality that is normally disabled
             0x4018..=0x401F => 0,
             // PRG ROM, PRG RAM and mapper registers
             0x4020..=0xFFFF => self.cartridge.read(address),
-            _ => panic!("invalid address: {address:#X}"),
         }
     }
 
-    pub fn write(&mut self, address: u16, data: u8) {
+    fn write(&mut self, address: u16, data: u8) {
+        if let Some(index) = self.device_index(address) {
+            return self.devices[index].1.write(address, data);
+        }
+        if let (Some(scratch), 0x2000..=0x401F) = (&mut self.io_scratch, address) {
+            scratch[address as usize - 0x2000] = data;
+            return;
+        }
         match address {
             0x0000..=0x1FFF => self.ram[address as usize & 0x07FF] = data,
             // PPU registers
-            0x2000..=0x3FFF => (),
+            0x2000..=0x3FFF => self.ppu.write_register(address & 0x2007, data),
             // DMA
             0x4014 => (),
-            // APU and I/O registers
-            0x4000..=0x4017 => (),
+            // controller strobe, latched by both ports
+            0x4016 => self.controllers.iter_mut().for_each(|controller| controller.write(data)),
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(address, data),
             // APU and I/O functionality that is normally disabled
             0x4018..=0x401F => (),
+            // VS. System coin counter
+            0x4020 if self.vs_inputs.is_some() => {
+                self.vs_inputs.as_mut().unwrap().coin_counter = data;
+            }
             // PRG ROM, PRG RAM and mapper registers
-            0x4020..=0xFFFF => (),
-            _ => (),
+            0x4020..=0xFFFF => {
+                if let Some(rom_writes) = &mut self.rom_writes {
+                    if !self.cartridge.is_writable(address) {
+                        rom_writes.push((address, data));
+                    }
+                }
+                self.cartridge.write(address, data)
+            }
         }
     }
 }
+
+// Plain 64KB RAM without any devices, for running raw 6502 code.
+#[derive(Debug)]
+pub struct FlatMemory {
+    memory: Vec<u8>,
+}
+
+impl FlatMemory {
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0; FLAT_MEMORY_SIZE],
+        }
+    }
+
+    pub fn load(&mut self, address: u16, program: &[u8]) {
+        for (offset, byte) in program.iter().enumerate() {
+            self.memory[address.wrapping_add(offset as u16) as usize] = *byte;
+        }
+    }
+
+    pub fn set_reset_vector(&mut self, address: u16) {
+        self.load(RESET_VECTOR_ADDR, &[address as u8, (address >> 8) as u8]);
+    }
+}
+
+impl Default for FlatMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for FlatMemory {
+    fn read(&mut self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.memory[address as usize] = data;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "std")]
+    const NESTEST_ROM_PATH: &str = "./rom/nestest.nes";
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_peek_matches_read() {
+        let mut bus = CpuBus::new(rom::read(NESTEST_ROM_PATH).unwrap());
+        bus.write(0x0010, 0x5A);
+        for address in [0x0010, 0x0810, 0x2002, 0x4016, 0xC000, 0xFFFC] {
+            assert_eq!(bus.peek(address), bus.read(address));
+        }
+        assert_eq!(bus.peek(0x0810), 0x5A);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_peek_ppustatus_keeps_write_toggle() {
+        let mut bus = CpuBus::new(rom::read(NESTEST_ROM_PATH).unwrap());
+        bus.write(0x2005, 0x00);
+        assert!(bus.ppu().is_second_write());
+        bus.peek(0x2002);
+        assert!(bus.ppu().is_second_write());
+        bus.read(0x2002);
+        assert!(!bus.ppu().is_second_write());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_peek_controller_keeps_shift_register() {
+        let mut bus = CpuBus::new(rom::read(NESTEST_ROM_PATH).unwrap());
+        // A and Select held
+        bus.controller_mut(controller::Port::One).set_buttons(0b0000_0101);
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+        assert_eq!(bus.peek(0x4016) & 1, 1);
+        assert_eq!(bus.peek(0x4016) & 1, 1);
+        assert_eq!(bus.read(0x4016) & 1, 1);
+        assert_eq!(bus.read(0x4016) & 1, 0);
+        assert_eq!(bus.peek(0x4016) & 1, 1);
+    }
+
+    // 4KB of RAM mirrored across its range
+    #[cfg(feature = "std")]
+    #[derive(Debug)]
+    struct DeviceRam {
+        memory: Vec<u8>,
+    }
+
+    #[cfg(feature = "std")]
+    impl BusDevice for DeviceRam {
+        fn read(&mut self, address: u16) -> u8 {
+            self.peek(address)
+        }
+
+        fn peek(&self, address: u16) -> u8 {
+            self.memory[address as usize & 0x0FFF]
+        }
+
+        fn write(&mut self, address: u16, data: u8) {
+            self.memory[address as usize & 0x0FFF] = data;
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_map_device() {
+        let mut bus = CpuBus::new(rom::read(NESTEST_ROM_PATH).unwrap());
+        let device = DeviceRam { memory: vec![0; 0x1000] };
+        bus.map_device(0x5000..=0x5FFF, Box::new(device)).unwrap();
+        bus.write(0x5000, 0x11);
+        bus.write(0x5FFF, 0x22);
+        assert_eq!(bus.read(0x5000), 0x11);
+        assert_eq!(bus.read(0x5FFF), 0x22);
+        assert_eq!(bus.peek(0x5FFF), 0x22);
+        // outside the range the default routing still applies
+        bus.write(0x0000, 0x33);
+        assert_eq!(bus.read(0x0000), 0x33);
+
+        let overlap = DeviceRam { memory: vec![0; 0x1000] };
+        assert_eq!(
+            bus.map_device(0x4800..=0x5000, Box::new(overlap)),
+            Err(DeviceOverlapError { range: 0x4800..=0x5000, existing: 0x5000..=0x5FFF })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_read_range() {
+        let mut bus = CpuBus::new(rom::read(NESTEST_ROM_PATH).unwrap());
+        for address in 0x0300..0x0400 {
+            bus.write(address, address as u8 ^ 0x5A);
+        }
+        let block = bus.read_range(0x0300, 0x100);
+        let reads: Vec<u8> = (0x0300..0x0400).map(|address| bus.read(address)).collect();
+        assert_eq!(block, reads);
+        assert_eq!(bus.read_range(0xFFFE, 4), [bus.peek(0xFFFE), bus.peek(0xFFFF)]);
+        assert!(bus.read_range(0x0000, 0).is_empty());
+
+        // I/O registers, open bus and PRG RAM
+        bus.write(0x6000, 0x42);
+        let block = bus.read_range(0x4000, 0x4000);
+        assert_eq!(block.len(), 0x4000);
+        assert!(block[0x0020..0x2000].iter().all(|&byte| byte == 0));
+        assert_eq!(block[0x2000], 0x42);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_vs_inputs() {
+        let mut buffer = std::fs::read(NESTEST_ROM_PATH).unwrap();
+        assert!(CpuBus::new(rom::from_bytes(&buffer).unwrap()).vs_inputs().is_none());
+        // flags 7: VS Unisystem
+        buffer[7] |= 0x01;
+        let mut bus = CpuBus::new(rom::from_bytes(&buffer).unwrap());
+        let inputs = bus.vs_inputs_mut().unwrap();
+        inputs.dip_switches = 0b1010_0110;
+        inputs.coins[0] = true;
+        bus.controller_mut(controller::Port::One).set_buttons(0x01);
+        bus.write(0x4016, 1);
+        assert_eq!(bus.read(0x4016), 0b0011_0001);
+        assert_eq!(bus.read(0x4017), 0b1010_0100);
+        assert_eq!(bus.peek(0x4017), 0b1010_0100);
+        bus.write(0x4020, 0x01);
+        assert_eq!(bus.vs_inputs().unwrap().coin_counter, 0x01);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_describe() {
+        let bus = CpuBus::new(rom::read(NESTEST_ROM_PATH).unwrap());
+        assert_eq!(bus.describe(0x0000), "RAM");
+        assert_eq!(bus.describe(0x1800), "RAM (mirrored)");
+        assert_eq!(bus.describe(0x2002), "PPU register");
+        assert_eq!(bus.describe(0x3FFA), "PPU register (mirrored)");
+        assert_eq!(bus.describe(0x4016), "APU/IO");
+        for address in [0x4020, 0x5000, 0x5FFF] {
+            assert_eq!(bus.describe(address), "open bus");
+            assert_eq!(bus.peek(address), 0);
+        }
+        assert_eq!(bus.describe(0x6000), "PRG RAM");
+        assert_eq!(bus.describe(0xC000), "PRG ROM");
+        let bus = CpuBus::with_mode(rom::read(NESTEST_ROM_PATH).unwrap(), BusMode::FlatIo);
+        assert_eq!(bus.describe(0x2002), "I/O scratch RAM");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_dmc_controller_conflict() {
+        let read_buttons = |is_conflict: bool| {
+            let mut bus = CpuBus::new(rom::read(NESTEST_ROM_PATH).unwrap());
+            bus.set_dmc_controller_conflict(is_conflict);
+            // A and Select
+            bus.controller_mut(controller::Port::One).set_buttons(0b0000_0101);
+            bus.write(0x4016, 1);
+            bus.write(0x4016, 0);
+            let mut bits = vec![bus.read(0x4016) & 1];
+            // the sample fetch is due as soon as the DMC is enabled
+            bus.write(0x4015, 0x10);
+            bits.push(bus.read(0x4016) & 1);
+            bus.tick(4);
+            bits.extend((0..3).map(|_| bus.read(0x4016) & 1));
+            bits
+        };
+        assert_eq!(read_buttons(false), [1, 0, 1, 0, 0]);
+        // the fetch repeats the read of B, so Select is lost
+        assert_eq!(read_buttons(true), [1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_rom_write_logging() {
+        let mut bus = CpuBus::new(rom::read(NESTEST_ROM_PATH).unwrap());
+        bus.write(0x8000, 0x01);
+        bus.set_rom_write_logging(true);
+        assert!(bus.take_rom_writes().is_empty());
+        bus.write(0x8000, 0x42);
+        bus.write(0x0000, 0x01);
+        bus.write(0xFFFF, 0x43);
+        assert_eq!(bus.take_rom_writes(), [(0x8000, 0x42), (0xFFFF, 0x43)]);
+        assert!(bus.take_rom_writes().is_empty());
+        // still ignored
+        assert_ne!(bus.read(0x8000), 0x42);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_flat_io_mode() {
+        let mut bus = CpuBus::with_mode(rom::read(NESTEST_ROM_PATH).unwrap(), BusMode::FlatIo);
+        bus.write(0x2000, 0x80);
+        bus.write(0x4016, 0x01);
+        bus.write(0x401F, 0x7F);
+        assert_eq!(bus.read(0x2000), 0x80);
+        assert_eq!(bus.read(0x4016), 0x01);
+        assert_eq!(bus.peek(0x401F), 0x7F);
+        // registers are not mirrored in flat mode
+        assert_eq!(bus.read(0x2008), 0x00);
+
+        let mut bus = CpuBus::new(rom::read(NESTEST_ROM_PATH).unwrap());
+        bus.ppu_mut().set_open_bus_decay(None);
+        bus.write(0x4000, 0x55);
+        assert_eq!(bus.read(0x4000), 0x00);
+    }
+
+    #[test]
+    fn test_read_u16_le() {
+        let mut memory = FlatMemory::new();
+        memory.load(0x1234, &[0xCD, 0xAB]);
+        memory.load(0xFFFF, &[0x34, 0x12]);
+        assert_eq!(memory.read_u16_le(0x1234), 0xABCD);
+        assert_eq!(memory.peek_u16_le(0x1234), 0xABCD);
+        assert_eq!(memory.read_u16_le(0xFFFF), 0x1234);
+        assert_eq!(memory.peek_u16_le(0xFFFF), 0x1234);
+    }
+
+    #[test]
+    fn test_flat_memory() {
+        let mut memory = FlatMemory::new();
+        memory.load(0xFFFF, &[0x01, 0x02]);
+        memory.write(0x2000, 0x33);
+        memory.set_reset_vector(0x0600);
+        assert_eq!(memory.read(0xFFFF), 0x01);
+        assert_eq!(memory.read(0x0000), 0x02);
+        assert_eq!(memory.read(0x2000), 0x33);
+        assert_eq!(memory.peek(0xFFFC), 0x00);
+        assert_eq!(memory.peek(0xFFFD), 0x06);
+    }
+}
@@ -0,0 +1,149 @@
+use alloc::vec::Vec;
+
+use crate::state::StateReader;
+
+// Standard controller, in the order the buttons are shifted out of $4016/$4017.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+// The two controller ports, read through $4016 and $4017.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Port {
+    One,
+    Two,
+}
+
+impl Port {
+    pub const ALL: [Port; 2] = [Port::One, Port::Two];
+}
+
+#[derive(Debug, Default)]
+pub struct Controller {
+    // one bit per button, A in bit 0
+    buttons: u8,
+    shift: u8,
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.buttons |= 1 << button as u8;
+        self.reload();
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.buttons &= !(1 << button as u8);
+        self.reload();
+    }
+
+    // Sets all buttons at once, bit 0 = A through bit 7 = Right.
+    pub fn set_buttons(&mut self, mask: u8) {
+        self.buttons = mask;
+        self.reload();
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    // While strobe is high the shift register keeps reloading, so reads see A.
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 != 0;
+        self.reload();
+    }
+
+    pub fn read(&mut self) -> u8 {
+        let bit = self.peek();
+        if !self.strobe {
+            // official controllers report 1 after the eighth read
+            self.shift = self.shift >> 1 | 0x80;
+        }
+        bit
+    }
+
+    pub fn peek(&self) -> u8 {
+        self.shift & 1
+    }
+
+    pub fn save_state(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&[self.buttons, self.shift, self.strobe as u8]);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Option<()> {
+        self.buttons = state.u8()?;
+        self.shift = state.u8()?;
+        self.strobe = state.bool()?;
+        Some(())
+    }
+
+    fn reload(&mut self) {
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+}
+
+// Cabinet inputs of a VS. System, read in the spare bits of $4016/$4017.
+#[derive(Debug, Default)]
+pub struct VsInputs {
+    // DIP switches 1 to 8 in bits 0 to 7, set when on
+    pub dip_switches: u8,
+    pub coins: [bool; 2],
+    pub is_service_pressed: bool,
+    // last value written to $4020; bit 0 drives the coin counter
+    pub coin_counter: u8,
+}
+
+impl VsInputs {
+    // Bits ORed into a read of the given port: service button, DIP 1-2 and
+    // the coin slots on $4016, DIP 3-8 on $4017.
+    pub fn port_bits(&self, port: usize) -> u8 {
+        match port {
+            0 => {
+                (self.is_service_pressed as u8) << 2
+                    | (self.dip_switches & 0x03) << 3
+                    | (self.coins[0] as u8) << 5
+                    | (self.coins[1] as u8) << 6
+            }
+            _ => self.dip_switches & 0xFC,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_press_release() {
+        let mut controller = Controller::new();
+        controller.press(Button::Start);
+        controller.press(Button::Left);
+        controller.release(Button::Start);
+        assert_eq!(controller.buttons(), 1 << 6);
+    }
+
+    #[test]
+    fn test_strobe_high_reads_a() {
+        let mut controller = Controller::new();
+        controller.write(1);
+        controller.press(Button::A);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 1);
+        controller.release(Button::A);
+        assert_eq!(controller.read(), 0);
+    }
+}
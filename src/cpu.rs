@@ -1,6 +1,13 @@
-use std::fmt::{Debug, Formatter};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{self, Debug, Formatter};
 
-use crate::bus;
+use crate::bus::{self, Bus};
+
+const NMI_VECTOR_ADDR: u16 = 0xFFFA;
+const RESET_VECTOR_ADDR: u16 = 0xFFFC;
+const IRQ_VECTOR_ADDR: u16 = 0xFFFE;
 
 #[derive(Copy, Clone)]
 enum AddressMode {
@@ -19,36 +26,245 @@ enum AddressMode {
     IndirectY,
 }
 
-struct Instruction<'a> {
+impl AddressMode {
+    const fn name(self) -> &'static str {
+        match self {
+            AddressMode::Accumulator => "accumulator",
+            AddressMode::Implied => "implied",
+            AddressMode::Immediate => "immediate",
+            AddressMode::Relative => "relative",
+            AddressMode::Zeropage => "zeropage",
+            AddressMode::ZeropageX => "zeropage,X",
+            AddressMode::ZeropageY => "zeropage,Y",
+            AddressMode::Absolute => "absolute",
+            AddressMode::AbsoluteX => "absolute,X",
+            AddressMode::AbsoluteY => "absolute,Y",
+            AddressMode::Indirect => "indirect",
+            AddressMode::IndirectX => "(indirect,X)",
+            AddressMode::IndirectY => "(indirect),Y",
+        }
+    }
+
+    // opcode plus operand bytes
+    const fn len(self) -> u8 {
+        match self {
+            AddressMode::Accumulator | AddressMode::Implied => 1,
+            AddressMode::Absolute
+            | AddressMode::AbsoluteX
+            | AddressMode::AbsoluteY
+            | AddressMode::Indirect => 3,
+            _ => 2,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Interrupt {
+    Nmi,
+    Irq,
+}
+
+impl Interrupt {
+    // stands in for the mnemonic in the trace entry of the interrupt sequence
+    fn marker(&self) -> &'static str {
+        match self {
+            Interrupt::Nmi => "[NMI]",
+            Interrupt::Irq => "[IRQ]",
+        }
+    }
+}
+
+struct Instruction<'a, B: Bus> {
     opcode: u8,
     mnemonic: &'a str,
     cycles: u8,
     address_mode: AddressMode,
     check_page_cross: bool,
-    func: fn(&mut Cpu),
+    func: fn(&mut Cpu<B>),
+}
+
+// Interrupt requests waiting to be serviced. Reset is never pending, it takes
+// effect as soon as reset is called.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Interrupts {
+    pub nmi: bool,
+    pub irq: bool,
+}
+
+// Public view of an instruction table entry; undefined opcodes have an
+// empty mnemonic.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InstructionInfo {
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub cycles: u8,
+    pub address_mode: &'static str,
+    pub bytes: u8,
+    // takes an extra cycle when indexing crosses a page
+    pub page_cross_penalty: bool,
+}
+
+// What the CPU does on an opcode that has no implementation.
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum IllegalOpcodePolicy {
+    #[default]
+    Panic,
+    // skip the byte as a 2-cycle single byte NOP
+    Nop,
+    // a 2-cycle NOP that also skips the operand bytes the opcode's slot in
+    // the 6502 opcode matrix implies, so the unofficial multi-byte NOPs
+    // ($04, $0C, $1C...) and other undefined opcodes resume on the next
+    // instruction
+    Skip,
+    // stop like a JAM opcode
+    Halt,
+    // halt and report the opcode through try_step
+    Error,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct IllegalOpcodeError {
+    pub opcode: u8,
+    pub pc: u16,
+}
+
+impl fmt::Display for IllegalOpcodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "illegal opcode {:#04X} at {:#06X}", self.opcode, self.pc)
+    }
 }
 
-pub struct Cpu {
+// Interrupt vector locations, the defaults are the NES (and common 6502)
+// ones, how undefined opcodes are handled, and whether reset counts its own
+// 7 cycles in total_cycles. Counting them lines traces up with nestest's
+// log, which starts at CYC:7; logs that start at 0 need it off.
+// stack_low_water is a debugging aid: a push that takes SP below it, or
+// wraps it past $00, is recorded for take_stack_overflow. The push itself
+// still wraps as on hardware.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CpuConfig {
+    pub nmi_vector: u16,
+    pub reset_vector: u16,
+    pub irq_vector: u16,
+    pub illegal_opcode_policy: IllegalOpcodePolicy,
+    pub seed_reset_cycles: bool,
+    pub stack_low_water: Option<u8>,
+}
+
+impl Default for CpuConfig {
+    fn default() -> Self {
+        Self {
+            nmi_vector: NMI_VECTOR_ADDR,
+            reset_vector: RESET_VECTOR_ADDR,
+            irq_vector: IRQ_VECTOR_ADDR,
+            illegal_opcode_policy: IllegalOpcodePolicy::default(),
+            seed_reset_cycles: true,
+            stack_low_water: None,
+        }
+    }
+}
+
+pub struct Cpu<B: Bus = bus::CpuBus> {
     a: u8,
     x: u8,
     y: u8,
     p: u8,
     sp: u8,
     pc: u16,
-    bus: bus::CpuBus,
+    bus: B,
+    config: CpuConfig,
     curr_cycles: u8,
     total_cycles: usize,
     operand: Option<u16>,
     operand_address: Option<i32>,
     address_mode: AddressMode,
     is_page_crossed: bool,
+    nmi_line: bool,
+    is_nmi_pending: bool,
+    irq_line: bool,
+    polled_interrupt: Option<Interrupt>,
+    is_halted: bool,
+    illegal_opcode: Option<IllegalOpcodeError>,
+    // SP after the first push that crossed the stack low-water mark
+    stack_overflow: Option<u8>,
+    write_count: usize,
+}
+
+// Register and interrupt state, for save states.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuState {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    pub pc: u16,
+    pub total_cycles: usize,
+    pub nmi_line: bool,
+    pub is_nmi_pending: bool,
+    pub irq_line: bool,
+    pub is_halted: bool,
+    polled_interrupt: Option<Interrupt>,
+}
+
+impl CpuState {
+    pub const ENCODED_SIZE: usize = 17;
+
+    // Fixed little-endian layout for on-disk save states: A, X, Y, P, SP,
+    // PC, total cycles as 64 bits, the line flags, the polled interrupt.
+    pub fn to_bytes(&self) -> [u8; Self::ENCODED_SIZE] {
+        let mut bytes = [0; Self::ENCODED_SIZE];
+        bytes[..5].copy_from_slice(&[self.a, self.x, self.y, self.p, self.sp]);
+        bytes[5..7].copy_from_slice(&self.pc.to_le_bytes());
+        bytes[7..15].copy_from_slice(&(self.total_cycles as u64).to_le_bytes());
+        bytes[15] = self.nmi_line as u8
+            | (self.is_nmi_pending as u8) << 1
+            | (self.irq_line as u8) << 2
+            | (self.is_halted as u8) << 3;
+        bytes[16] = match self.polled_interrupt {
+            None => 0,
+            Some(Interrupt::Nmi) => 1,
+            Some(Interrupt::Irq) => 2,
+        };
+        bytes
+    }
+
+    // None if the bytes don't hold a valid state.
+    pub fn from_bytes(bytes: &[u8; Self::ENCODED_SIZE]) -> Option<Self> {
+        let polled_interrupt = match bytes[16] {
+            0 => None,
+            1 => Some(Interrupt::Nmi),
+            2 => Some(Interrupt::Irq),
+            _ => return None,
+        };
+        Some(Self {
+            a: bytes[0],
+            x: bytes[1],
+            y: bytes[2],
+            p: bytes[3],
+            sp: bytes[4],
+            pc: u16::from_le_bytes([bytes[5], bytes[6]]),
+            total_cycles: u64::from_le_bytes(bytes[7..15].try_into().unwrap()) as usize,
+            nmi_line: bytes[15] & 1 != 0,
+            is_nmi_pending: bytes[15] & 1 << 1 != 0,
+            irq_line: bytes[15] & 1 << 2 != 0,
+            is_halted: bytes[15] & 1 << 3 != 0,
+            polled_interrupt,
+        })
+    }
 }
 
 pub struct TraceEntry {
-    pub opcode: u8,
+    // None for the entry of an interrupt sequence
+    pub opcode: Option<u8>,
     pub mnemonic: String,
     pub operand: Option<u16>,
+    // bytes of operand after the opcode, 0 to 2
+    pub operand_size: u8,
     pub operand_address: Option<i32>,
+    // byte at the effective address before the instruction ran, for
+    // instructions that read it; peeked, so tracing has no side effects
+    pub operand_value: Option<u8>,
     pub a: u8,
     pub x: u8,
     pub y: u8,
@@ -69,28 +285,35 @@ impl PartialEq for TraceEntry {
             && self.pc == other.pc
             && self.sp == other.sp
             && self.cycles == other.cycles
+            && match (self.operand_address, other.operand_address) {
+                // reference logs only annotate some instructions
+                (Some(addr), Some(other_addr)) => addr == other_addr,
+                _ => true,
+            }
     }
 }
 
 impl Debug for TraceEntry {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(f, "TraceEntry {{ \
-                opcode: {:02X}, mnemonic: \"{}\", operand: {:?}, operand_address: {:?}, \
-                a: {:02X}, x: {:02X}, y: {:02X}, p: {:02X}, pc: {:04X}, sp: {:04X}, cycles: {} \
+                opcode: {:02X?}, mnemonic: \"{}\", operand: {:?}, operand_address: {:?}, \
+                operand_value: {:?}, a: {:02X}, x: {:02X}, y: {:02X}, p: {:02X}, pc: {:04X}, sp: {:04X}, cycles: {} \
             }}", 
-               self.opcode, self.mnemonic, self.operand, self.operand_address, 
+               self.opcode, self.mnemonic, self.operand, self.operand_address, self.operand_value,
                self.a, self.x, self.y, self.p, self.pc, self.sp, self.cycles)
     }
 }
 
-impl<'a> Cpu {
+impl<B: Bus> Cpu<B> {
     const STACK_BASE_ADDR: u16 = 0x0100;
-    const NMI_VECTOR_ADDR: u16 = 0xFFFA;
-    const RESET_VECTOR_ADDR: u16 = 0xFFFC;
-    const IRQ_VECTOR_ADDR: u16 = 0xFFFE;
 
     const ACCUMULATOR_ADDR: i32 = -1;
 
+    const INTERRUPT_CYCLES: u8 = 7;
+    // P after power on and the reset sequence: I and the unused bit set,
+    // everything else clear. nestest's log starts from it.
+    pub const POWER_ON_STATUS: u8 = 0x24;
+
     const CARRY_FLAG: u8 = 1 << 0;
     const ZERO_FLAG: u8 = 1 << 1;
     const INTERRUPT_FLAG: u8 = 1 << 2;
@@ -100,7 +323,7 @@ impl<'a> Cpu {
     const OVERFLOW_FLAG: u8 = 1 << 6;
     const NEGATIVE_FLAG: u8 = 1 << 7;
 
-    const INVALID_INSTRUCTION: Instruction<'a> = Instruction {
+    const INVALID_INSTRUCTION: Instruction<'static, B> = Instruction {
         opcode: 0,
         mnemonic: "",
         cycles: 0,
@@ -108,7 +331,7 @@ impl<'a> Cpu {
         check_page_cross: false,
         func: Self::invalid_opcode,
     };
-    const INSTRUCTIONS: [Instruction<'a>; 0x100] = [
+    const INSTRUCTIONS: [Instruction<'static, B>; 0x100] = [
         Instruction {
             opcode: 0x00,
             mnemonic: "BRK",
@@ -125,7 +348,14 @@ impl<'a> Cpu {
             check_page_cross: false,
             func: Self::ora,
         },
-        Self::INVALID_INSTRUCTION,
+        Instruction {
+            opcode: 0x02,
+            mnemonic: "JAM",
+            cycles: 2,
+            address_mode: AddressMode::Implied,
+            check_page_cross: false,
+            func: Self::jam,
+        },
         Self::INVALID_INSTRUCTION,
         Self::INVALID_INSTRUCTION,
         Instruction {
@@ -204,7 +434,14 @@ impl<'a> Cpu {
             check_page_cross: true,
             func: Self::ora,
         },
-        Self::INVALID_INSTRUCTION,
+        Instruction {
+            opcode: 0x12,
+            mnemonic: "JAM",
+            cycles: 2,
+            address_mode: AddressMode::Implied,
+            check_page_cross: false,
+            func: Self::jam,
+        },
         Self::INVALID_INSTRUCTION,
         Self::INVALID_INSTRUCTION,
         Instruction {
@@ -276,7 +513,14 @@ impl<'a> Cpu {
             check_page_cross: false,
             func: Self::and,
         },
-        Self::INVALID_INSTRUCTION,
+        Instruction {
+            opcode: 0x22,
+            mnemonic: "JAM",
+            cycles: 2,
+            address_mode: AddressMode::Implied,
+            check_page_cross: false,
+            func: Self::jam,
+        },
         Self::INVALID_INSTRUCTION,
         Instruction {
             opcode: 0x24,
@@ -369,7 +613,14 @@ impl<'a> Cpu {
             check_page_cross: true,
             func: Self::and,
         },
-        Self::INVALID_INSTRUCTION,
+        Instruction {
+            opcode: 0x32,
+            mnemonic: "JAM",
+            cycles: 2,
+            address_mode: AddressMode::Implied,
+            check_page_cross: false,
+            func: Self::jam,
+        },
         Self::INVALID_INSTRUCTION,
         Self::INVALID_INSTRUCTION,
         Instruction {
@@ -441,7 +692,14 @@ impl<'a> Cpu {
             check_page_cross: false,
             func: Self::eor,
         },
-        Self::INVALID_INSTRUCTION,
+        Instruction {
+            opcode: 0x42,
+            mnemonic: "JAM",
+            cycles: 2,
+            address_mode: AddressMode::Implied,
+            check_page_cross: false,
+            func: Self::jam,
+        },
         Self::INVALID_INSTRUCTION,
         Self::INVALID_INSTRUCTION,
         Instruction {
@@ -527,7 +785,14 @@ impl<'a> Cpu {
             check_page_cross: true,
             func: Self::eor,
         },
-        Self::INVALID_INSTRUCTION,
+        Instruction {
+            opcode: 0x52,
+            mnemonic: "JAM",
+            cycles: 2,
+            address_mode: AddressMode::Implied,
+            check_page_cross: false,
+            func: Self::jam,
+        },
         Self::INVALID_INSTRUCTION,
         Self::INVALID_INSTRUCTION,
         Instruction {
@@ -599,7 +864,14 @@ impl<'a> Cpu {
             check_page_cross: false,
             func: Self::adc,
         },
-        Self::INVALID_INSTRUCTION,
+        Instruction {
+            opcode: 0x62,
+            mnemonic: "JAM",
+            cycles: 2,
+            address_mode: AddressMode::Implied,
+            check_page_cross: false,
+            func: Self::jam,
+        },
         Self::INVALID_INSTRUCTION,
         Self::INVALID_INSTRUCTION,
         Instruction {
@@ -685,7 +957,14 @@ impl<'a> Cpu {
             check_page_cross: true,
             func: Self::adc,
         },
-        Self::INVALID_INSTRUCTION,
+        Instruction {
+            opcode: 0x72,
+            mnemonic: "JAM",
+            cycles: 2,
+            address_mode: AddressMode::Implied,
+            check_page_cross: false,
+            func: Self::jam,
+        },
         Self::INVALID_INSTRUCTION,
         Self::INVALID_INSTRUCTION,
         Instruction {
@@ -836,7 +1115,14 @@ impl<'a> Cpu {
             check_page_cross: false,
             func: Self::sta,
         },
-        Self::INVALID_INSTRUCTION,
+        Instruction {
+            opcode: 0x92,
+            mnemonic: "JAM",
+            cycles: 2,
+            address_mode: AddressMode::Implied,
+            check_page_cross: false,
+            func: Self::jam,
+        },
         Self::INVALID_INSTRUCTION,
         Instruction {
             opcode: 0x94,
@@ -1015,7 +1301,14 @@ impl<'a> Cpu {
             check_page_cross: true,
             func: Self::lda,
         },
-        Self::INVALID_INSTRUCTION,
+        Instruction {
+            opcode: 0xB2,
+            mnemonic: "JAM",
+            cycles: 2,
+            address_mode: AddressMode::Implied,
+            check_page_cross: false,
+            func: Self::jam,
+        },
         Self::INVALID_INSTRUCTION,
         Instruction {
             opcode: 0xB4,
@@ -1201,7 +1494,14 @@ impl<'a> Cpu {
             check_page_cross: true,
             func: Self::cmp,
         },
-        Self::INVALID_INSTRUCTION,
+        Instruction {
+            opcode: 0xD2,
+            mnemonic: "JAM",
+            cycles: 2,
+            address_mode: AddressMode::Implied,
+            check_page_cross: false,
+            func: Self::jam,
+        },
         Self::INVALID_INSTRUCTION,
         Self::INVALID_INSTRUCTION,
         Instruction {
@@ -1366,7 +1666,14 @@ impl<'a> Cpu {
             check_page_cross: true,
             func: Self::sbc,
         },
-        Self::INVALID_INSTRUCTION,
+        Instruction {
+            opcode: 0xF2,
+            mnemonic: "JAM",
+            cycles: 2,
+            address_mode: AddressMode::Implied,
+            check_page_cross: false,
+            func: Self::jam,
+        },
         Self::INVALID_INSTRUCTION,
         Self::INVALID_INSTRUCTION,
         Instruction {
@@ -1424,7 +1731,11 @@ impl<'a> Cpu {
         Self::INVALID_INSTRUCTION,
     ];
 
-    pub fn new(bus: bus::CpuBus) -> Self {
+    pub fn new(bus: B) -> Self {
+        Self::with_config(bus, CpuConfig::default())
+    }
+
+    pub fn with_config(bus: B, config: CpuConfig) -> Self {
         Self {
             a: 0,
             x: 0,
@@ -1432,36 +1743,225 @@ impl<'a> Cpu {
             p: 0,
             sp: 0,
             pc: 0,
-            bus: bus,
+            bus,
+            config,
             curr_cycles: 0,
             total_cycles: 0,
             operand: None,
             operand_address: None,
             address_mode: AddressMode::Implied,
             is_page_crossed: false,
+            nmi_line: false,
+            is_nmi_pending: false,
+            irq_line: false,
+            polled_interrupt: None,
+            is_halted: false,
+            illegal_opcode: None,
+            stack_overflow: None,
+            write_count: 0,
         }
     }
 
-    pub fn reset(&mut self, pc: Option<u16>) {
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn total_cycles(&self) -> usize {
+        self.total_cycles
+    }
+
+    // Cycles the most recent step took, including page cross and branch
+    // penalties and DMA stalls.
+    pub fn last_instruction_cycles(&self) -> u8 {
+        self.curr_cycles
+    }
+
+    // Bus writes made since power on, including stack pushes.
+    pub fn write_count(&self) -> usize {
+        self.write_count
+    }
+
+    const INSTRUCTION_INFO: [InstructionInfo; 0x100] = {
+        let mut table = [InstructionInfo {
+            opcode: 0,
+            mnemonic: "",
+            cycles: 0,
+            address_mode: "",
+            bytes: 0,
+            page_cross_penalty: false,
+        }; 0x100];
+        let mut opcode = 0;
+        while opcode < table.len() {
+            let instruction = &Self::INSTRUCTIONS[opcode];
+            // catches an entry landing in the wrong slot of the table
+            assert!(instruction.mnemonic.is_empty() || instruction.opcode as usize == opcode);
+            table[opcode] = InstructionInfo {
+                opcode: opcode as u8,
+                mnemonic: instruction.mnemonic,
+                cycles: instruction.cycles,
+                address_mode: instruction.address_mode.name(),
+                bytes: instruction.address_mode.len(),
+                page_cross_penalty: instruction.check_page_cross,
+            };
+            opcode += 1;
+        }
+        table
+    };
+
+    pub fn instruction_table() -> &'static [InstructionInfo; 0x100] {
+        &Self::INSTRUCTION_INFO
+    }
+
+    pub fn is_illegal_opcode(opcode: u8) -> bool {
+        Self::INSTRUCTIONS[opcode as usize].mnemonic.is_empty()
+    }
+
+    // Opcodes still missing from the table, for compatibility reports.
+    pub fn unimplemented_opcodes() -> Vec<u8> {
+        (0..=0xFF).filter(|&opcode| Self::is_illegal_opcode(opcode)).collect()
+    }
+
+    pub fn save_state(&self) -> CpuState {
+        CpuState {
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            p: self.p,
+            sp: self.sp,
+            pc: self.pc,
+            total_cycles: self.total_cycles,
+            nmi_line: self.nmi_line,
+            is_nmi_pending: self.is_nmi_pending,
+            irq_line: self.irq_line,
+            is_halted: self.is_halted,
+            polled_interrupt: self.polled_interrupt,
+        }
+    }
+
+    pub fn load_state(&mut self, state: &CpuState) {
+        self.a = state.a;
+        self.x = state.x;
+        self.y = state.y;
+        self.set_status(state.p);
+        self.sp = state.sp;
+        self.pc = state.pc;
+        self.total_cycles = state.total_cycles;
+        self.nmi_line = state.nmi_line;
+        self.is_nmi_pending = state.is_nmi_pending;
+        self.irq_line = state.irq_line;
+        self.is_halted = state.is_halted;
+        self.polled_interrupt = state.polled_interrupt;
+    }
+
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    // Cold start: registers come up cleared and the reset sequence runs from there.
+    pub fn power_on(&mut self, pc: Option<u16>) {
         self.a = 0;
         self.x = 0;
         self.y = 0;
-        self.sp = 0xFD;
-        self.total_cycles = 7;
-        self.p = Self::INTERRUPT_FLAG | Self::UNUSED_FLAG;
+        self.sp = 0;
+        self.p = Self::POWER_ON_STATUS;
+        self.total_cycles = 0;
+        self.bus.power_on();
+        self.reset(pc);
+    }
+
+    // Warm reset: A, X, Y and the other flags are kept, while SP drops by three
+    // because the reset sequence runs the interrupt pushes with writes suppressed.
+    // Only after power_on does this leave P at exactly POWER_ON_STATUS.
+    pub fn reset(&mut self, pc: Option<u16>) {
+        self.sp = self.sp.wrapping_sub(3);
+        if self.config.seed_reset_cycles {
+            self.total_cycles += Self::INTERRUPT_CYCLES as usize;
+        }
+        self.p |= Self::INTERRUPT_FLAG | Self::UNUSED_FLAG;
+        self.is_nmi_pending = false;
+        self.polled_interrupt = None;
+        self.is_halted = false;
+        // reset aborts whatever instruction was being decoded
+        self.operand = None;
+        self.operand_address = None;
+        self.address_mode = AddressMode::Implied;
+        self.is_page_crossed = false;
         self.pc = match pc {
             Some(addr) => addr,
-            None => {
-                self.bus.read(Self::RESET_VECTOR_ADDR) as u16
-                    | (self.bus.read(Self::RESET_VECTOR_ADDR + 1) as u16) << 8
-            }
+            None => self.bus.read_u16_le(self.config.reset_vector),
+        }
+    }
+
+    // NMI is edge triggered: asserting the line latches a request until it is serviced.
+    pub fn set_nmi_line(&mut self, is_asserted: bool) {
+        if is_asserted && !self.nmi_line {
+            self.is_nmi_pending = true;
         }
+        self.nmi_line = is_asserted;
+    }
+
+    pub fn pending_interrupts(&self) -> Interrupts {
+        Interrupts {
+            nmi: self.is_nmi_pending || self.polled_interrupt == Some(Interrupt::Nmi),
+            irq: self.irq_line || self.bus.irq(),
+        }
+    }
+
+    // Drops latched requests and releases the IRQ line; a device that still
+    // wants service asserts it again.
+    pub fn clear_pending(&mut self) {
+        self.is_nmi_pending = false;
+        self.irq_line = false;
+        self.polled_interrupt = None;
+    }
+
+    // IRQ is level triggered and stays asserted until the device releases it.
+    pub fn set_irq_line(&mut self, is_asserted: bool) {
+        self.irq_line = is_asserted;
+    }
+
+    // Whether the next step services an interrupt polled by the last
+    // instruction instead of fetching the opcode at PC.
+    pub fn is_interrupt_due(&self) -> bool {
+        self.polled_interrupt.is_some() && !self.is_halted
+    }
+
+    pub fn illegal_opcode_policy(&self) -> IllegalOpcodePolicy {
+        self.config.illegal_opcode_policy
+    }
+
+    pub fn set_stack_low_water(&mut self, mark: Option<u8>) {
+        self.config.stack_low_water = mark;
+    }
+
+    // SP after a push crossed the stack low-water mark, if one has since the
+    // last call.
+    pub fn take_stack_overflow(&mut self) -> Option<u8> {
+        self.stack_overflow.take()
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.is_halted
     }
 
     pub fn step(&mut self) -> u8 {
         self.curr_cycles = 0;
+        if self.is_halted {
+            return 0;
+        }
+        if let Some(interrupt) = self.polled_interrupt.take() {
+            self.service_interrupt(interrupt);
+            self.curr_cycles += self.bus.tick(self.curr_cycles);
+            self.total_cycles += self.curr_cycles as usize;
+            return self.curr_cycles;
+        }
+        let p = self.p;
         let opcode = self.bus.read(self.pc);
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         let instruction = Self::INSTRUCTIONS
             .get(opcode as usize)
             .unwrap_or(&Self::INVALID_INSTRUCTION);
@@ -1481,17 +1981,104 @@ impl<'a> Cpu {
             AddressMode::IndirectX => self.resolve_indirect_x(),
             AddressMode::IndirectY => self.resolve_indirect_y(),
         };
+        if instruction.check_page_cross && self.is_page_crossed {
+            self.dummy_read_unfixed();
+        }
         (instruction.func)(self);
+        // branch only spends cycles of its own when taken
+        let is_branch_taken = matches!(self.address_mode, AddressMode::Relative) && self.curr_cycles > 0;
+        // Interrupts are polled before the last cycle. A taken branch that
+        // stays on its page polls before its extra cycle instead, so an IRQ
+        // raised during that cycle waits for one more instruction.
+        let poll_cycles = if is_branch_taken && !self.is_page_crossed { 2 } else { 1 };
         self.curr_cycles += instruction.cycles;
         if instruction.check_page_cross && self.is_page_crossed {
             self.curr_cycles += 1;
             self.is_page_crossed = false;
         }
+        let cycles_before_poll = self.curr_cycles.saturating_sub(poll_cycles);
+        let mut stall = self.bus.tick(cycles_before_poll);
+        // CLI, SEI and PLP poll interrupts before changing the I flag,
+        // so the new mask only takes effect after the next instruction.
+        let interrupt_mask = match opcode {
+            0x28 | 0x58 | 0x78 => p,
+            _ => self.p,
+        };
+        self.poll_interrupts(interrupt_mask);
+        stall += self.bus.tick(self.curr_cycles - cycles_before_poll);
+        self.curr_cycles += stall;
         self.total_cycles += self.curr_cycles as usize;
         self.curr_cycles
     }
 
+    // Like step, but reports an illegal opcode hit under IllegalOpcodePolicy::Error.
+    pub fn try_step(&mut self) -> Result<u8, IllegalOpcodeError> {
+        let cycles = self.step();
+        match self.illegal_opcode.take() {
+            Some(err) => Err(err),
+            None => Ok(cycles),
+        }
+    }
+
+    // Runs up to count instructions, stopping early if the CPU halts.
+    // Returns the number of cycles spent.
+    pub fn step_many(&mut self, count: usize) -> usize {
+        let mut cycles: usize = 0;
+        for _ in 0..count {
+            if self.is_halted {
+                break;
+            }
+            cycles += self.step() as usize;
+        }
+        cycles
+    }
+
+    fn poll_interrupts(&mut self, p: u8) {
+        if self.bus.take_nmi() {
+            self.is_nmi_pending = true;
+        }
+        self.polled_interrupt = if self.is_nmi_pending {
+            Some(Interrupt::Nmi)
+        } else if (self.irq_line || self.bus.irq()) && (p & Self::INTERRUPT_FLAG) == 0 {
+            Some(Interrupt::Irq)
+        } else {
+            None
+        };
+    }
+
+    fn service_interrupt(&mut self, interrupt: Interrupt) {
+        self.push_interrupt_frame(self.pc, self.pushed_status(false));
+        let vector = match interrupt {
+            Interrupt::Nmi => self.config.nmi_vector,
+            Interrupt::Irq => self.config.irq_vector,
+        };
+        let vector = self.hijack_vector(vector);
+        self.pc = self.bus.read_u16_le(vector);
+        self.curr_cycles += Self::INTERRUPT_CYCLES;
+    }
+
+    // An NMI raised while BRK or IRQ is pushing its frame takes over the sequence:
+    // the frame stays as pushed but the CPU vectors through NMI instead.
+    fn hijack_vector(&mut self, vector: u16) -> u16 {
+        if self.is_nmi_pending {
+            self.is_nmi_pending = false;
+            self.config.nmi_vector
+        } else {
+            vector
+        }
+    }
+
+    fn push_interrupt_frame(&mut self, return_addr: u16, p: u8) {
+        self.push_stack((return_addr >> 8) as u8);
+        self.push_stack(return_addr as u8);
+        self.push_stack(p);
+        self.p |= Self::INTERRUPT_FLAG;
+    }
+
+    // An interrupt sequence run by this step gets an entry of its own, with
+    // Interrupt::marker as the mnemonic and no opcode or operand.
     pub fn trace_step(&mut self) -> TraceEntry {
+        let interrupt = self.polled_interrupt.filter(|_| !self.is_halted);
         let a = self.a;
         let x = self.x;
         let y = self.y;
@@ -1499,41 +2086,207 @@ impl<'a> Cpu {
         let pc = self.pc;
         let sp = self.sp;
         let cycles = self.total_cycles;
-        let opcode = self.bus.read(self.pc);
+        let opcode = self.bus.peek(self.pc);
         let instruction = Self::INSTRUCTIONS
             .get(opcode as usize)
             .unwrap_or(&Self::INVALID_INSTRUCTION);
+        let operand_value = self.operand_value(self.pc);
         self.step();
+        if let Some(interrupt) = interrupt {
+            return TraceEntry {
+                opcode: None,
+                mnemonic: interrupt.marker().to_string(),
+                operand: None,
+                operand_size: 0,
+                operand_address: None,
+                operand_value: None,
+                a,
+                x,
+                y,
+                p,
+                pc,
+                sp,
+                cycles,
+            };
+        }
         TraceEntry {
-            opcode: opcode,
+            opcode: Some(opcode),
             mnemonic: instruction.mnemonic.to_string(),
             operand: self.operand,
+            operand_size: self.operand.map_or(0, |_| instruction.address_mode.len() - 1),
             operand_address: self.operand_address,
-            a: a,
-            x: x,
-            y: y,
-            p: p,
-            pc: pc,
-            sp: sp,
+            operand_value,
+            a,
+            x,
+            y,
+            p,
+            pc,
+            sp,
             cycles,
         }
     }
 
+    // Assembly for the instruction at pc, with memory operands annotated as
+    // in nestest logs.
+    pub fn disassemble(&self, pc: u16) -> String {
+        let instruction = &Self::INSTRUCTIONS[self.bus.peek(pc) as usize];
+        if instruction.mnemonic.is_empty() {
+            return format!(".byte ${:02X}", self.bus.peek(pc));
+        }
+        let operand_pc = pc.wrapping_add(1);
+        let byte = self.bus.peek(operand_pc);
+        let word = self.bus.peek_u16_le(operand_pc);
+        let operand = match instruction.address_mode {
+            AddressMode::Implied => None,
+            AddressMode::Accumulator => Some("A".to_string()),
+            AddressMode::Immediate => Some(format!("#${byte:02X}")),
+            AddressMode::Relative => Some(format!(
+                "${:04X}",
+                operand_pc.wrapping_add(1).wrapping_add(byte as i8 as u16)
+            )),
+            AddressMode::Absolute if matches!(instruction.mnemonic, "JMP" | "JSR") => {
+                Some(format!("${word:04X}"))
+            }
+            _ => self.annotate(pc),
+        };
+        match operand {
+            Some(operand) => format!("{} {operand}", instruction.mnemonic),
+            None => instruction.mnemonic.to_string(),
+        }
+    }
+
+    // Addresses and disassembly of up to `before` instructions leading to pc,
+    // the one at pc and `after` following it. The earlier instructions are
+    // found by decoding forward from the furthest address that lands exactly
+    // on pc, so data mixed in with code can throw them off.
+    pub fn disassembly_window(&self, pc: u16, before: usize, after: usize) -> Vec<(u16, String)> {
+        let mut addresses = Vec::new();
+        for back in (1..=before as u16 * 3).rev() {
+            let mut address = pc.wrapping_sub(back);
+            let mut remaining = back;
+            let mut decoded = Vec::new();
+            while remaining > 0 {
+                let len = self.instruction_len(address);
+                if len > remaining {
+                    break;
+                }
+                decoded.push(address);
+                address = address.wrapping_add(len);
+                remaining -= len;
+            }
+            if remaining == 0 {
+                addresses = decoded;
+                break;
+            }
+        }
+        addresses.drain(..addresses.len().saturating_sub(before));
+        let mut address = pc;
+        for _ in 0..=after {
+            addresses.push(address);
+            address = address.wrapping_add(self.instruction_len(address));
+        }
+        addresses
+            .into_iter()
+            .map(|address| (address, self.disassemble(address)))
+            .collect()
+    }
+
+    // Bytes the instruction at pc takes, counting an illegal opcode as one
+    // the way disassemble does.
+    fn instruction_len(&self, pc: u16) -> u16 {
+        let instruction = &Self::INSTRUCTIONS[self.bus.peek(pc) as usize];
+        if instruction.mnemonic.is_empty() {
+            1
+        } else {
+            instruction.address_mode.len() as u16
+        }
+    }
+
+    // The memory operand of the instruction at PC in nestest notation, like
+    // "$0200 = 5A" or "$0300,X @ 0305 = 12", with the value it would read.
+    // None for instructions without a memory operand.
+    pub fn operand_annotation(&self) -> Option<String> {
+        self.annotate(self.pc)
+    }
+
+    fn annotate(&self, pc: u16) -> Option<String> {
+        let instruction = &Self::INSTRUCTIONS[self.bus.peek(pc) as usize];
+        let operand_pc = pc.wrapping_add(1);
+        let byte = self.bus.peek(operand_pc);
+        let word = self.bus.peek_u16_le(operand_pc);
+        let address = self.effective_address(pc)?;
+        let value = self.bus.peek(address);
+        let annotation = match instruction.address_mode {
+            AddressMode::Zeropage => format!("${byte:02X} = {value:02X}"),
+            AddressMode::ZeropageX => format!("${byte:02X},X @ {address:02X} = {value:02X}"),
+            AddressMode::ZeropageY => format!("${byte:02X},Y @ {address:02X} = {value:02X}"),
+            AddressMode::Absolute => format!("${word:04X} = {value:02X}"),
+            AddressMode::AbsoluteX => format!("${word:04X},X @ {address:04X} = {value:02X}"),
+            AddressMode::AbsoluteY => format!("${word:04X},Y @ {address:04X} = {value:02X}"),
+            AddressMode::Indirect => format!("(${word:04X}) = {address:04X}"),
+            AddressMode::IndirectX => format!(
+                "(${byte:02X},X) @ {:02X} = {address:04X} = {value:02X}",
+                byte.wrapping_add(self.x)
+            ),
+            AddressMode::IndirectY => format!(
+                "(${byte:02X}),Y = {:04X} @ {address:04X} = {value:02X}",
+                self.peek_address_around_page(byte as u16)
+            ),
+            _ => return None,
+        };
+        Some(annotation)
+    }
+
+    // Address the instruction at pc would access, computed without bus side effects.
+    pub fn effective_address(&self, pc: u16) -> Option<u16> {
+        let instruction = &Self::INSTRUCTIONS[self.bus.peek(pc) as usize];
+        let operand_pc = pc.wrapping_add(1);
+        let byte = self.bus.peek(operand_pc);
+        let word = self.bus.peek_u16_le(operand_pc);
+        match instruction.address_mode {
+            AddressMode::Accumulator
+            | AddressMode::Implied
+            | AddressMode::Immediate
+            | AddressMode::Relative => None,
+            AddressMode::Zeropage => Some(byte as u16),
+            AddressMode::ZeropageX => Some(byte.wrapping_add(self.x) as u16),
+            AddressMode::ZeropageY => Some(byte.wrapping_add(self.y) as u16),
+            AddressMode::Absolute => Some(word),
+            AddressMode::AbsoluteX => Some(word.wrapping_add(self.x as u16)),
+            AddressMode::AbsoluteY => Some(word.wrapping_add(self.y as u16)),
+            AddressMode::Indirect => Some(self.peek_address_around_page(word)),
+            AddressMode::IndirectX => Some(self.peek_zeropage_pointer(byte.wrapping_add(self.x))),
+            AddressMode::IndirectY => {
+                Some(self.peek_zeropage_pointer(byte).wrapping_add(self.y as u16))
+            }
+        }
+    }
+
+    // The byte the instruction at pc reads from memory. None for stores and
+    // jumps, which only use the address.
+    fn operand_value(&self, pc: u16) -> Option<u8> {
+        let instruction = &Self::INSTRUCTIONS[self.bus.peek(pc) as usize];
+        if matches!(instruction.mnemonic, "STA" | "STX" | "STY" | "JMP" | "JSR") {
+            return None;
+        }
+        self.effective_address(pc).map(|address| self.bus.peek(address))
+    }
+
     fn resolve_immediate(&mut self) -> (Option<u16>, Option<i32>) {
         let operand = self.bus.read(self.pc);
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         (Some(operand as u16), None)
     }
 
     fn resolve_relative(&mut self) -> (Option<u16>, Option<i32>) {
         let offset = self.bus.read(self.pc);
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         (Some(offset as u16), None)
     }
 
     fn resolve_zeropage(&mut self, index: u8) -> (Option<u16>, Option<i32>) {
         let base_addr = self.bus.read(self.pc);
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         (
             Some(base_addr as u16),
             Some(base_addr.wrapping_add(index) as i32),
@@ -1544,78 +2297,149 @@ impl<'a> Cpu {
         address1 & 0xFF00 != address2 & 0xFF00
     }
 
-    fn read_address_around_page(&self, address: u16) -> u16 {
+    fn read_address_around_page(&mut self, address: u16) -> u16 {
         let mut pointer = self.bus.read(address) as u16;
-        if Self::is_page_crossed(address, address + 1) {
+        if Self::is_page_crossed(address, address.wrapping_add(1)) {
             pointer |= (self.bus.read(address & 0xFF00) as u16) << 8;
         } else {
-            pointer |= (self.bus.read(address + 1) as u16) << 8;
+            pointer |= (self.bus.read(address.wrapping_add(1)) as u16) << 8;
         }
         pointer
     }
 
+    fn peek_address_around_page(&self, address: u16) -> u16 {
+        let high_addr = if Self::is_page_crossed(address, address.wrapping_add(1)) {
+            address & 0xFF00
+        } else {
+            address.wrapping_add(1)
+        };
+        self.bus.peek(address) as u16 | (self.bus.peek(high_addr) as u16) << 8
+    }
+
+    // Pointers for (indirect,X) and (indirect),Y live in the zero page, so one
+    // at $FF takes its high byte from $00 rather than $0100.
+    fn read_zeropage_pointer(&mut self, address: u8) -> u16 {
+        let low = self.bus.read(address as u16) as u16;
+        low | (self.bus.read(address.wrapping_add(1) as u16) as u16) << 8
+    }
+
+    fn peek_zeropage_pointer(&self, address: u8) -> u16 {
+        let low = self.bus.peek(address as u16) as u16;
+        low | (self.bus.peek(address.wrapping_add(1) as u16) as u16) << 8
+    }
+
     fn resolve_absolute(&mut self, index: u8) -> (Option<u16>, Option<i32>) {
-        let base_addr = self.bus.read(self.pc) as u16 | (self.bus.read(self.pc + 1) as u16) << 8;
-        self.pc += 2;
+        let base_addr = self.bus.read_u16_le(self.pc);
+        self.pc = self.pc.wrapping_add(2);
         let effective_addr = base_addr.wrapping_add(index as u16);
         self.is_page_crossed = Self::is_page_crossed(base_addr, effective_addr);
         (Some(base_addr), Some(effective_addr as i32))
     }
 
     fn resolve_indirect(&mut self) -> (Option<u16>, Option<i32>) {
-        let pointer = self.bus.read(self.pc) as u16 | (self.bus.read(self.pc + 1) as u16) << 8;
-        self.pc += 2;
+        let pointer = self.bus.read_u16_le(self.pc);
+        self.pc = self.pc.wrapping_add(2);
         let effective_addr = self.read_address_around_page(pointer);
         (Some(pointer), Some(effective_addr as i32))
     }
 
     fn resolve_indirect_x(&mut self) -> (Option<u16>, Option<i32>) {
         let base_addr = self.bus.read(self.pc);
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         let zeropage_addr = base_addr.wrapping_add(self.x);
-        let effective_addr = self.read_address_around_page(zeropage_addr as u16);
+        let effective_addr = self.read_zeropage_pointer(zeropage_addr);
         (Some(base_addr as u16), Some(effective_addr as i32))
     }
 
     fn resolve_indirect_y(&mut self) -> (Option<u16>, Option<i32>) {
-        let pointer = self.bus.read(self.pc) as u16;
-        self.pc += 1;
-        let base_addr = self.read_address_around_page(pointer);
+        let pointer = self.bus.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        let base_addr = self.read_zeropage_pointer(pointer);
         let effective_addr = base_addr.wrapping_add(self.y as u16);
         self.is_page_crossed = Self::is_page_crossed(base_addr, effective_addr);
-        (Some(pointer), Some(effective_addr as i32))
+        (Some(pointer as u16), Some(effective_addr as i32))
     }
 
-    fn get_operand(&self) -> u16 {
+    // operand_address is an i32 only to fit the accumulator sentinel; anything
+    // else outside the 16-bit space is an addressing bug, not a wrap.
+    fn bus_address(addr: i32) -> u16 {
+        debug_assert!(
+            (0..=0xFFFF).contains(&addr),
+            "operand address out of range: {addr:#X}"
+        );
+        addr as u16
+    }
+
+    fn get_operand(&mut self) -> u16 {
         match self.operand_address {
             Some(addr) => match addr {
                 Self::ACCUMULATOR_ADDR => self.a as u16,
-                _ => self.bus.read(addr as u16) as u16,
+                _ => self.bus.read(Self::bus_address(addr)) as u16,
             },
             None => self.operand.unwrap(),
         }
     }
 
+    // Indexed stores, and indexed reads that cross a page, spend a cycle reading
+    // from the address before the page carry is fixed up, which devices behind
+    // the bus can observe.
+    fn dummy_read_unfixed(&mut self) {
+        if let AddressMode::AbsoluteX | AddressMode::AbsoluteY | AddressMode::IndirectY = self.address_mode {
+            let address = self.operand_address.unwrap() as u16;
+            self.bus.read(if self.is_page_crossed {
+                address.wrapping_sub(0x100)
+            } else {
+                address
+            });
+        }
+    }
+
+    // Read-modify-write instructions always take the indexed dummy read,
+    // crossing or not, and write the unmodified value back before the result.
+    fn read_for_modify(&mut self) -> u8 {
+        self.dummy_read_unfixed();
+        let operand = self.get_operand() as u8;
+        if !matches!(self.address_mode, AddressMode::Accumulator) {
+            self.store(self.operand_address, operand);
+        }
+        operand
+    }
+
     fn store(&mut self, address: Option<i32>, data: u8) {
         match self.address_mode {
             AddressMode::Accumulator => self.a = data,
             _ => match address {
-                Some(addr) => self.bus.write(addr as u16, data),
+                Some(addr) => {
+                    self.write_count += 1;
+                    self.bus.write(Self::bus_address(addr), data)
+                }
                 None => panic!("expected address got None"),
             },
         }
     }
 
     fn push_stack(&mut self, data: u8) {
+        self.write_count += 1;
         self.bus.write(Self::STACK_BASE_ADDR | self.sp as u16, data);
-        self.sp -= 1;
+        let is_wrapped = self.sp == 0x00;
+        self.sp = self.sp.wrapping_sub(1);
+        if let Some(mark) = self.config.stack_low_water {
+            if (is_wrapped || self.sp < mark) && self.stack_overflow.is_none() {
+                self.stack_overflow = Some(self.sp);
+            }
+        }
     }
 
     fn pop_stack(&mut self) -> u8 {
-        self.sp += 1;
+        self.sp = self.sp.wrapping_add(1);
         self.bus.read(Self::STACK_BASE_ADDR | self.sp as u16)
     }
 
+    // Pulls a return address, low byte first.
+    fn pop_address(&mut self) -> u16 {
+        u16::from_le_bytes([self.pop_stack(), self.pop_stack()])
+    }
+
     fn set_flag(&mut self, flag: u8, is_need_set: bool) {
         if is_need_set {
             self.p |= flag;
@@ -1630,21 +2454,38 @@ impl<'a> Cpu {
     }
 
     fn brk(&mut self) {
-        let return_addr = self.pc + 1;
-        self.push_stack((return_addr >> 8) as u8);
-        self.push_stack(return_addr as u8);
-        self.push_stack(self.p | Self::BREAK_FLAG);
-        self.p |= Self::INTERRUPT_FLAG;
-        self.pc = self.bus.read(Self::IRQ_VECTOR_ADDR) as u16
-            | (self.bus.read(Self::IRQ_VECTOR_ADDR + 1) as u16) << 8;
+        self.push_interrupt_frame(self.pc.wrapping_add(1), self.pushed_status(true));
+        let vector = self.hijack_vector(self.config.irq_vector);
+        self.pc = self.bus.read_u16_le(vector);
     }
 
     fn php(&mut self) {
-        self.push_stack(self.p | Self::BREAK_FLAG);
+        self.push_stack(self.pushed_status(true));
+    }
+
+    // B and the unused bit only exist in pushed copies of P; the register
+    // itself always reads B clear and bit 5 set, whatever was on the stack.
+    fn set_status(&mut self, value: u8) {
+        self.p = value & !Self::BREAK_FLAG | Self::UNUSED_FLAG;
+    }
+
+    fn set_status_from_stack(&mut self) {
+        let value = self.pop_stack();
+        self.set_status(value);
+    }
+
+    // P as pushed by BRK and PHP (B set) or an interrupt (B clear).
+    fn pushed_status(&self, is_break: bool) -> u8 {
+        let p = self.p | Self::UNUSED_FLAG;
+        if is_break {
+            p | Self::BREAK_FLAG
+        } else {
+            p & !Self::BREAK_FLAG
+        }
     }
 
     fn plp(&mut self) {
-        self.p = self.pop_stack() & !Self::BREAK_FLAG | Self::UNUSED_FLAG;
+        self.set_status_from_stack();
     }
 
     fn pha(&mut self) {
@@ -1692,7 +2533,7 @@ impl<'a> Cpu {
     }
 
     fn rol(&mut self) {
-        let operand = self.get_operand() as u8;
+        let operand = self.read_for_modify();
         let rotated = operand << 1 | self.p & Self::CARRY_FLAG;
         self.set_flag(Self::CARRY_FLAG, (operand >> 7) == 1);
         self.set_nz_flags(rotated);
@@ -1700,7 +2541,7 @@ impl<'a> Cpu {
     }
 
     fn ror(&mut self) {
-        let operand = self.get_operand() as u8;
+        let operand = self.read_for_modify();
         let rotated = operand >> 1 | (self.p & Self::CARRY_FLAG) << 7;
         self.set_flag(Self::CARRY_FLAG, (operand & 1) == 1);
         self.set_nz_flags(rotated);
@@ -1708,12 +2549,12 @@ impl<'a> Cpu {
     }
 
     fn rti(&mut self) {
-        self.p = self.pop_stack() | Self::UNUSED_FLAG;
-        self.pc = self.pop_stack() as u16 | (self.pop_stack() as u16) << 8;
+        self.set_status_from_stack();
+        self.pc = self.pop_address();
     }
 
     fn rts(&mut self) {
-        self.pc = (self.pop_stack() as u16 | (self.pop_stack() as u16) << 8) + 1;
+        self.pc = self.pop_address().wrapping_add(1);
     }
 
     fn jmp(&mut self) {
@@ -1721,7 +2562,7 @@ impl<'a> Cpu {
     }
 
     fn jsr(&mut self) {
-        let return_addr = self.pc - 1;
+        let return_addr = self.pc.wrapping_sub(1);
         self.push_stack((return_addr >> 8) as u8);
         self.push_stack(return_addr as u8);
         self.pc = self.operand_address.unwrap() as u16;
@@ -1733,7 +2574,7 @@ impl<'a> Cpu {
     }
 
     fn asl(&mut self) {
-        let operand = self.get_operand() as u8;
+        let operand = self.read_for_modify();
         let result = operand << 1;
         self.set_flag(Self::CARRY_FLAG, (operand >> 7) == 1);
         self.set_nz_flags(result);
@@ -1751,7 +2592,7 @@ impl<'a> Cpu {
     }
 
     fn lsr(&mut self) {
-        let operand = self.get_operand() as u8;
+        let operand = self.read_for_modify();
         let result = operand >> 1;
         self.set_flag(Self::CARRY_FLAG, (operand & 1) == 1);
         self.set_nz_flags(result);
@@ -1770,14 +2611,17 @@ impl<'a> Cpu {
     }
 
     fn adc(&mut self) {
-        self.adc_(self.get_operand());
+        let operand = self.get_operand();
+        self.adc_(operand);
     }
 
     fn sbc(&mut self) {
-        self.adc_(self.get_operand() ^ 0xFF);
+        let operand = self.get_operand() ^ 0xFF;
+        self.adc_(operand);
     }
 
     fn sta(&mut self) {
+        self.dummy_read_unfixed();
         self.store(self.operand_address, self.a);
     }
 
@@ -1810,13 +2654,13 @@ impl<'a> Cpu {
     }
 
     fn inc(&mut self) {
-        let result = (self.get_operand() as u8).wrapping_add(1);
+        let result = self.read_for_modify().wrapping_add(1);
         self.set_nz_flags(result);
         self.store(self.operand_address, result);
     }
 
     fn dec(&mut self) {
-        let result = (self.get_operand() as u8).wrapping_sub(1);
+        let result = self.read_for_modify().wrapping_sub(1);
         self.set_nz_flags(result);
         self.store(self.operand_address, result);
     }
@@ -1933,23 +2777,77 @@ impl<'a> Cpu {
 
     fn nop(&mut self) {}
 
+    // JAM locks the CPU up until the next reset, with PC left on the opcode.
+    fn jam(&mut self) {
+        self.pc = self.pc.wrapping_sub(1);
+        self.is_halted = true;
+    }
+
+    // Operand bytes of an opcode without an implementation. The addressing
+    // mode follows from the opcode's column (bits 2-4) and group (bits 0-1);
+    // the KIL slots take none.
+    const fn undefined_operand_len(opcode: u8) -> u16 {
+        match (opcode >> 2 & 0x07, opcode & 0x03) {
+            // KIL
+            (0, 2) if opcode < 0x80 => 0,
+            (4, 2) => 0,
+            // implied
+            (2, 0) | (2, 2) | (6, 0) | (6, 2) => 0,
+            // immediate, zero page and indirect
+            (0, _) | (1, _) | (2, _) | (4, _) | (5, _) => 1,
+            // absolute
+            _ => 2,
+        }
+    }
+
     fn invalid_opcode(&mut self) {
-        panic!("illegal opcode")
+        let pc = self.pc.wrapping_sub(1);
+        let err = IllegalOpcodeError {
+            opcode: self.bus.peek(pc),
+            pc,
+        };
+        match self.config.illegal_opcode_policy {
+            IllegalOpcodePolicy::Panic => panic!("{err}"),
+            IllegalOpcodePolicy::Nop => self.curr_cycles += 2,
+            IllegalOpcodePolicy::Skip => {
+                self.pc = self.pc.wrapping_add(Self::undefined_operand_len(err.opcode));
+                self.curr_cycles += 2;
+            }
+            IllegalOpcodePolicy::Halt => self.jam(),
+            IllegalOpcodePolicy::Error => {
+                self.jam();
+                self.illegal_opcode = Some(err);
+            }
+        }
+    }
+}
+
+impl Cpu<bus::FlatMemory> {
+    pub fn with_flat_memory() -> Self {
+        Self::new(bus::FlatMemory::new())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::LinkedList;
+    #[cfg(feature = "std")]
     use std::fs::File;
+    #[cfg(feature = "std")]
     use std::io::{BufRead, BufReader};
+    #[cfg(feature = "std")]
     use std::{panic, path};
 
     use super::*;
-    use crate::{bus, rom};
+    use crate::bus;
+    #[cfg(feature = "std")]
+    use crate::rom;
     
+    #[cfg(feature = "std")]
     const NESTEST_PC: u16 = 0xC000;
+    #[cfg(feature = "std")]
     const NESTEST_ROM_PATH: &str = "./rom/nestest.nes";
+    #[cfg(feature = "std")]
     const NESTEST_TRACE_PATH: &str = "./rom/nestest_official.trace";
 
     fn parse_register_data<T: num_traits::Num>(str_val: &str, radix: u32) -> Result<T, T::FromStrRadixErr> {
@@ -1971,6 +2869,7 @@ mod tests {
             stack.push_back(operand_byte);
             index += 1;
         }
+        let operand_size = stack.len() as u8;
         if !stack.is_empty() {
             let mut operand_val: u16 = 0;
             while !stack.is_empty() {
@@ -1981,9 +2880,11 @@ mod tests {
         
         let mnemonic = chunks[index].to_string();
         index += 1;
+        let annotation_index = index;
         while !chunks[index].starts_with("A:") {
             index += 1;
         }
+        let operand_address = parse_effective_address(&chunks[annotation_index..index]);
 
         let a: u8;
         let x: u8;
@@ -2003,20 +2904,41 @@ mod tests {
             panic!("parsing registers data error");
         }
         TraceEntry {
-            opcode: opcode,
-            mnemonic: mnemonic,
-            operand: operand,
-            operand_address: None,
-            a: a,
-            x: x,
-            y: y,
-            p: p,
-            pc: pc,
-            sp: sp,
-            cycles: cycles
+            opcode: Some(opcode),
+            mnemonic,
+            operand,
+            operand_size,
+            operand_address,
+            operand_value: None,
+            a,
+            x,
+            y,
+            p,
+            pc,
+            sp,
+            cycles
+        }
+    }
+
+    // Effective address from the operand annotation of a nestest line:
+    // "$00 = 00", "$0300,Y @ 0300 = 89", "($80,X) @ 80 = 0200 = 5A",
+    // "($89),Y = 0300 @ 0300 = 89" or "($0200) = DB7E".
+    fn parse_effective_address(chunks: &[&str]) -> Option<i32> {
+        let parse_hex = |str_val: &str| i32::from_str_radix(str_val.trim_start_matches('$'), 16).ok();
+        if let Some(at) = chunks.iter().position(|&c| c == "@") {
+            return match chunks.get(at + 4) {
+                Some(&"=") => parse_hex(chunks[at + 3]),
+                _ => parse_hex(chunks[at + 1]),
+            };
+        }
+        match chunks {
+            [operand, "=", value] if operand.starts_with('(') => parse_hex(value),
+            [operand, "=", _] => parse_hex(operand),
+            _ => None,
         }
     }
     
+    #[cfg(feature = "std")]
     fn parse_nestest_trace(trace_path: &str) -> Vec<TraceEntry> {
         let path = path::Path::new(trace_path).canonicalize().unwrap();
         let file = File::open(path).unwrap();
@@ -2031,11 +2953,12 @@ mod tests {
         trace
     }
     
+    #[cfg(feature = "std")]
     fn run_nestest(rom_path: &str, limit: usize) -> Vec<TraceEntry> {
         let result = panic::catch_unwind(|| {
             let cartridge = rom::read(rom_path).unwrap();
             let mut cpu = Cpu::new(bus::CpuBus::new(cartridge));
-            cpu.reset(Some(NESTEST_PC));
+            cpu.power_on(Some(NESTEST_PC));
             let mut trace: Vec<TraceEntry> = Vec::new();
             for _ in 0..limit {
                 trace.push(cpu.trace_step());
@@ -2046,11 +2969,826 @@ mod tests {
     }
     
     #[test]
+    fn test_flat_memory_program() {
+        let mut cpu = Cpu::with_flat_memory();
+        // LDA #$42; TAX
+        cpu.bus_mut().load(0x0600, &[0xA9, 0x42, 0xAA]);
+        cpu.bus_mut().set_reset_vector(0x0600);
+        cpu.power_on(None);
+        assert_eq!(cpu.pc, 0x0600);
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.a, 0x42);
+        assert_eq!(cpu.x, 0x42);
+        assert_eq!(cpu.pc, 0x0603);
+    }
+
+    fn flat_cpu(program: &[u8]) -> Cpu<bus::FlatMemory> {
+        let mut cpu = Cpu::with_flat_memory();
+        cpu.bus_mut().load(0x0600, program);
+        cpu.bus_mut().set_reset_vector(0x0600);
+        cpu.bus_mut().load(NMI_VECTOR_ADDR, &[0x00, 0x08]);
+        cpu.bus_mut().load(IRQ_VECTOR_ADDR, &[0x00, 0x07]);
+        cpu.power_on(None);
+        cpu
+    }
+
+    #[test]
+    fn test_operand_annotation() {
+        // LDX #$05; LDY #$10; STA $47; LDA $0300,X; LDA ($80),Y; INX
+        let program = [
+            0xA2, 0x05, 0xA0, 0x10, 0x85, 0x47, 0xBD, 0x00, 0x03, 0xB1, 0x80, 0xE8,
+        ];
+        let mut cpu = flat_cpu(&program);
+        cpu.bus_mut().load(0x0047, &[0x5A]);
+        cpu.bus_mut().load(0x0080, &[0xF8, 0x02]);
+        cpu.bus_mut().load(0x0305, &[0x12]);
+        cpu.bus_mut().load(0x0308, &[0x34]);
+        cpu.step_many(2);
+        assert_eq!(cpu.operand_annotation().as_deref(), Some("$47 = 5A"));
+        cpu.step();
+        assert_eq!(cpu.operand_annotation().as_deref(), Some("$0300,X @ 0305 = 12"));
+        cpu.step();
+        assert_eq!(cpu.operand_annotation().as_deref(), Some("($80),Y = 02F8 @ 0308 = 34"));
+        cpu.step();
+        assert_eq!(cpu.operand_annotation(), None);
+    }
+
+    #[test]
+    fn test_status_from_stack() {
+        for stacked in [0x00, 0xFF, 0x10, 0x20, 0xCF] {
+            // LDA #stacked; PHA; PLP
+            let mut cpu = flat_cpu(&[0xA9, stacked, 0x48, 0x28]);
+            cpu.step_many(3);
+            assert_eq!(cpu.p, stacked & !0x10 | 0x20, "PLP of {stacked:02X}");
+
+            // LDA #$06; PHA; LDA #$00; PHA; LDA #stacked; PHA; RTI
+            let program = [0xA9, 0x06, 0x48, 0xA9, 0x00, 0x48, 0xA9, stacked, 0x48, 0x40];
+            let mut cpu = flat_cpu(&program);
+            cpu.step_many(7);
+            assert_eq!(cpu.p, stacked & !0x10 | 0x20, "RTI of {stacked:02X}");
+            assert_eq!(cpu.pc(), 0x0600);
+        }
+    }
+
+    #[test]
+    fn test_trace_operand_value() {
+        // LDA $0200; STA $0201; INC $0200; LDA #$01
+        let mut cpu = flat_cpu(&[0xAD, 0x00, 0x02, 0x8D, 0x01, 0x02, 0xEE, 0x00, 0x02, 0xA9, 0x01]);
+        cpu.bus_mut().load(0x0200, &[0x5A, 0x77]);
+        let entry = cpu.trace_step();
+        assert_eq!((entry.operand_address, entry.operand_value), (Some(0x0200), Some(0x5A)));
+        assert_eq!(cpu.trace_step().operand_value, None);
+        assert_eq!(cpu.trace_step().operand_value, Some(0x5A));
+        assert_eq!(cpu.trace_step().operand_value, None);
+    }
+
+    #[test]
+    fn test_trace_operand_size() {
+        // LDA $10; LDA $0010; ASL A; LDA #$01
+        let mut cpu = flat_cpu(&[0xA5, 0x10, 0xAD, 0x10, 0x00, 0x0A, 0xA9, 0x01]);
+        let sizes: Vec<(Option<u16>, u8)> = (0..4)
+            .map(|_| cpu.trace_step())
+            .map(|entry| (entry.operand, entry.operand_size))
+            .collect();
+        assert_eq!(sizes, [(Some(0x10), 1), (Some(0x0010), 2), (None, 0), (Some(0x01), 1)]);
+    }
+
+    #[test]
+    fn test_last_instruction_cycles() {
+        // LDX #$01; LDA $02FF,X
+        let mut cpu = flat_cpu(&[0xA2, 0x01, 0xBD, 0xFF, 0x02]);
+        cpu.step();
+        assert_eq!(cpu.last_instruction_cycles(), 2);
+        cpu.step();
+        assert_eq!(cpu.last_instruction_cycles(), 5);
+    }
+
+    // Raises IRQ once irq_at cycles have been ticked, like a timer on the cart.
+    #[derive(Default)]
+    struct IrqTimerBus {
+        memory: bus::FlatMemory,
+        cycles: usize,
+        irq_at: usize,
+    }
+
+    impl Bus for IrqTimerBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.memory.read(address)
+        }
+
+        fn peek(&self, address: u16) -> u8 {
+            self.memory.peek(address)
+        }
+
+        fn write(&mut self, address: u16, data: u8) {
+            self.memory.write(address, data);
+        }
+
+        fn tick(&mut self, cycles: u8) -> u8 {
+            self.cycles += cycles as usize;
+            0
+        }
+
+        fn irq(&self) -> bool {
+            self.cycles >= self.irq_at
+        }
+    }
+
+    fn irq_timer_cpu(irq_at: usize) -> Cpu<IrqTimerBus> {
+        let mut cpu = Cpu::new(IrqTimerBus { irq_at, ..Default::default() });
+        // LDX #$01; CLI; BNE +0; INX; INX
+        cpu.bus_mut().memory.load(0x0600, &[0xA2, 0x01, 0x58, 0xD0, 0x00, 0xE8, 0xE8]);
+        cpu.bus_mut().memory.set_reset_vector(0x0600);
+        cpu.bus_mut().memory.load(IRQ_VECTOR_ADDR, &[0x00, 0x07]);
+        cpu.power_on(None);
+        cpu
+    }
+
+    #[test]
+    fn test_branch_delays_irq() {
+        // the taken branch runs cycles 4-6; IRQ rising during its second
+        // cycle is seen by a normal instruction's poll but not the branch's
+        let mut cpu = irq_timer_cpu(6);
+        cpu.step_many(3);
+        assert_eq!(cpu.pc(), 0x0605);
+        cpu.step();
+        assert_eq!(cpu.x, 0x02);
+        cpu.step();
+        assert_eq!(cpu.pc(), 0x0700);
+
+        // rising during its first cycle it is serviced right after the branch
+        let mut cpu = irq_timer_cpu(5);
+        cpu.step_many(4);
+        assert_eq!(cpu.pc(), 0x0700);
+        assert_eq!(cpu.x, 0x01);
+    }
+
+    #[test]
+    fn test_irq_delayed_after_cli() {
+        // CLI; NOP; NOP
+        let mut cpu = flat_cpu(&[0x58, 0xEA, 0xEA]);
+        cpu.set_irq_line(true);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0601);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0602);
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.pc, 0x0700);
+        assert_eq!(cpu.pop_stack() & Cpu::<bus::FlatMemory>::BREAK_FLAG, 0);
+        assert_eq!(cpu.pop_stack(), 0x02);
+        assert_eq!(cpu.pop_stack(), 0x06);
+    }
+
+    #[test]
+    fn test_irq_taken_after_sei() {
+        // CLI; SEI; NOP
+        let mut cpu = flat_cpu(&[0x58, 0x78, 0xEA]);
+        cpu.step();
+        cpu.set_irq_line(true);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0700);
+    }
+
+    #[test]
+    fn test_nmi_edge() {
+        // NOP; NOP; NOP
+        let mut cpu = flat_cpu(&[0xEA, 0xEA, 0xEA]);
+        cpu.set_nmi_line(true);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0800);
+        cpu.bus_mut().load(0x0800, &[0xEA, 0xEA]);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0802);
+    }
+
+    #[test]
+    fn test_trace_nmi_marker() {
+        // NOP; NOP
+        let mut cpu = flat_cpu(&[0xEA, 0xEA]);
+        cpu.bus_mut().load(0x0800, &[0xEA]);
+        cpu.set_nmi_line(true);
+        assert_eq!(cpu.trace_step().mnemonic, "NOP");
+        let marker = cpu.trace_step();
+        assert_eq!(marker.mnemonic, "[NMI]");
+        assert_eq!(marker.opcode, None);
+        assert_eq!(marker.pc, 0x0601);
+        assert_eq!(marker.operand, None);
+        let handler = cpu.trace_step();
+        assert_eq!(handler.pc, 0x0800);
+        assert_eq!(handler.cycles - marker.cycles, 7);
+    }
+
+    #[test]
+    fn test_nmi_hijacks_brk() {
+        // BRK; NOP
+        let mut cpu = flat_cpu(&[0x00, 0xEA]);
+        cpu.set_nmi_line(true);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0800);
+        assert_ne!(cpu.pop_stack() & Cpu::<bus::FlatMemory>::BREAK_FLAG, 0);
+        assert_eq!(cpu.pop_stack(), 0x02);
+        cpu.bus_mut().load(0x0800, &[0xEA]);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0801);
+    }
+
+    #[test]
+    fn test_nmi_hijacks_irq() {
+        // CLI; NOP
+        let mut cpu = flat_cpu(&[0x58, 0xEA]);
+        cpu.set_irq_line(true);
+        cpu.step();
+        cpu.step();
+        cpu.set_nmi_line(true);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0800);
+        assert_eq!(cpu.pop_stack() & Cpu::<bus::FlatMemory>::BREAK_FLAG, 0);
+    }
+
+    #[test]
+    fn test_live_status_has_no_break_flag() {
+        let assert_live_p = |cpu: &Cpu<bus::FlatMemory>, after: &str| {
+            assert_eq!(cpu.p & 0x30, 0x20, "P={:02X} after {after}", cpu.p);
+        };
+        // LDA #$FF; PHA; PLP; PHP; CLI; BRK
+        let mut cpu = flat_cpu(&[0xA9, 0xFF, 0x48, 0x28, 0x08, 0x58, 0x00]);
+        cpu.bus_mut().load(0x0700, &[0x40]);
+        cpu.step_many(3);
+        assert_live_p(&cpu, "PLP");
+        cpu.step();
+        assert_live_p(&cpu, "PHP");
+        assert_eq!(cpu.bus().peek(0x01FD) & 0x30, 0x30);
+        cpu.step_many(2);
+        assert_live_p(&cpu, "BRK");
+        cpu.step();
+        assert_live_p(&cpu, "RTI");
+        // the IRQ is polled during the NOP
+        cpu.bus_mut().load(0x0608, &[0xEA]);
+        cpu.set_irq_line(true);
+        cpu.step_many(2);
+        assert_eq!(cpu.pc, 0x0700);
+        assert_live_p(&cpu, "IRQ");
+        cpu.set_nmi_line(true);
+        cpu.step_many(2);
+        assert_eq!(cpu.pc, 0x0800);
+        assert_live_p(&cpu, "NMI");
+        let mut state = cpu.save_state();
+        state.p = 0x10;
+        cpu.load_state(&state);
+        assert_live_p(&cpu, "load_state");
+    }
+
+    #[test]
+    fn test_effective_address_absolute_x() {
+        // LDA $02F0,X
+        let mut cpu = flat_cpu(&[0xBD, 0xF0, 0x02]);
+        cpu.x = 0x20;
+        assert_eq!(cpu.effective_address(0x0600), Some(0x0310));
+        cpu.step();
+        assert_eq!(cpu.operand_address, Some(0x0310));
+    }
+
+    #[test]
+    fn test_effective_address_indirect_y() {
+        // LDA ($10),Y
+        let mut cpu = flat_cpu(&[0xB1, 0x10]);
+        cpu.bus_mut().load(0x0010, &[0xF0, 0x02]);
+        cpu.y = 0x15;
+        assert_eq!(cpu.effective_address(0x0600), Some(0x0305));
+        cpu.step();
+        assert_eq!(cpu.operand_address, Some(0x0305));
+    }
+
+    #[test]
+    fn test_effective_address_implied() {
+        // INX; ASL A
+        let cpu = flat_cpu(&[0xE8, 0x0A]);
+        assert_eq!(cpu.effective_address(0x0600), None);
+        assert_eq!(cpu.effective_address(0x0601), None);
+    }
+
+    #[test]
+    fn test_power_on_state() {
+        let mut cpu = flat_cpu(&[0xEA]);
+        cpu.a = 0x11;
+        cpu.x = 0x22;
+        cpu.y = 0x33;
+        cpu.sp = 0x80;
+        cpu.p = 0xC3;
+        cpu.power_on(None);
+        assert_eq!((cpu.a, cpu.x, cpu.y), (0, 0, 0));
+        assert_eq!(cpu.sp, 0xFD);
+        assert_eq!(cpu.p, 0x24);
+        assert_eq!(cpu.total_cycles, 7);
+        assert_eq!(cpu.pc, 0x0600);
+    }
+
+    #[test]
+    fn test_reset_preserves_registers() {
+        let mut cpu = flat_cpu(&[0xEA]);
+        cpu.step();
+        cpu.a = 0x11;
+        cpu.x = 0x22;
+        cpu.y = 0x33;
+        cpu.p = 0xC1;
+        cpu.reset(None);
+        assert_eq!((cpu.a, cpu.x, cpu.y), (0x11, 0x22, 0x33));
+        assert_eq!(cpu.sp, 0xFA);
+        assert_eq!(cpu.p, 0xC1 | 0x24);
+        assert_eq!(cpu.total_cycles, 16);
+        assert_eq!(cpu.pc, 0x0600);
+    }
+
+    #[test]
+    fn test_reset_discards_decode_state() {
+        // LDA $12FF,X; INX
+        let mut cpu = flat_cpu(&[0xBD, 0xFF, 0x12, 0xE8]);
+        cpu.x = 0x01;
+        cpu.pc = 0x0601;
+        cpu.resolve_absolute(cpu.x);
+        assert!(cpu.is_page_crossed);
+        cpu.reset(Some(0x0603));
+        assert_eq!((cpu.operand, cpu.operand_address), (None, None));
+        assert!(!cpu.is_page_crossed);
+        let entry = cpu.trace_step();
+        assert_eq!((entry.mnemonic.as_str(), entry.operand, entry.operand_address), ("INX", None, None));
+        assert_eq!(cpu.last_instruction_cycles(), 2);
+        assert_eq!((cpu.x, cpu.pc), (0x02, 0x0604));
+    }
+
+    #[test]
+    fn test_power_on_status() {
+        let mut cpu = flat_cpu(&[0x38, 0xF8]);
+        assert_eq!(cpu.p, 0x24);
+        assert_eq!(Cpu::<bus::FlatMemory>::POWER_ON_STATUS, 0x24);
+        // SEC; SED
+        cpu.step_many(2);
+        cpu.power_on(Some(0x0600));
+        assert_eq!(cpu.p, 0x24);
+        cpu.reset(None);
+        assert_eq!(cpu.p, 0x24);
+        // a warm reset from a dirty P keeps C and D and only sets I and U
+        cpu.step_many(2);
+        assert_eq!(cpu.p, 0x24 | 0x01 | 0x08);
+        cpu.p &= !0x04;
+        cpu.reset(None);
+        assert_eq!(cpu.p, 0x24 | 0x01 | 0x08);
+    }
+
+    #[test]
+    fn test_step_many() {
+        // INX; INX; JAM; INX
+        let mut cpu = flat_cpu(&[0xE8, 0xE8, 0x02, 0xE8]);
+        assert_eq!(cpu.step_many(1), 2);
+        assert_eq!(cpu.step_many(10), 4);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.x, 2);
+        assert_eq!(cpu.pc, 0x0602);
+        assert_eq!(cpu.step_many(10), 0);
+        cpu.reset(None);
+        assert!(!cpu.is_halted());
+    }
+
+    // cargo test --release -- --ignored --nocapture bench_step_many
+    #[test]
+    #[ignore]
+    fn bench_step_many() {
+        const STEPS: usize = 1_000_000;
+        // INX; JMP $0600
+        let program = [0xE8, 0x4C, 0x00, 0x06];
+        let mut cpu = flat_cpu(&program);
+        let start = std::time::Instant::now();
+        for _ in 0..STEPS {
+            cpu.step();
+        }
+        let step_time = start.elapsed();
+        let mut cpu = flat_cpu(&program);
+        let start = std::time::Instant::now();
+        cpu.step_many(STEPS);
+        let step_many_time = start.elapsed();
+        println!("step: {step_time:?}, step_many: {step_many_time:?}");
+    }
+
+    #[test]
+    fn test_relocated_vectors() {
+        let config = CpuConfig {
+            nmi_vector: 0x0300,
+            reset_vector: 0x0302,
+            irq_vector: 0x0304,
+            ..Default::default()
+        };
+        let mut cpu = Cpu::with_config(bus::FlatMemory::new(), config);
+        cpu.bus_mut().load(0x0300, &[0x00, 0x09, 0x00, 0x06, 0x00, 0x07]);
+        // BRK
+        cpu.bus_mut().load(0x0600, &[0x00]);
+        // NOP
+        cpu.bus_mut().load(0x0700, &[0xEA]);
+        cpu.power_on(None);
+        assert_eq!(cpu.pc, 0x0600);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0700);
+        cpu.set_nmi_line(true);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0900);
+    }
+
+    #[test]
+    fn test_jsr_at_zero() {
+        let mut cpu = flat_cpu(&[0x60]);
+        // JSR $0600
+        cpu.bus_mut().load(0x0000, &[0x20, 0x00, 0x06]);
+        cpu.pc = 0x0000;
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0600);
+        assert_eq!(cpu.sp, 0xFB);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0003);
+        assert_eq!(cpu.sp, 0xFD);
+    }
+
+    #[test]
+    fn test_jsr_rts_wrap() {
+        let mut cpu = flat_cpu(&[0x60]);
+        // JSR $0600 with the operand wrapping past $FFFF
+        cpu.bus_mut().load(0xFFFE, &[0x20, 0x00]);
+        cpu.bus_mut().load(0x0000, &[0x06]);
+        cpu.pc = 0xFFFE;
+        cpu.sp = 0x00;
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0600);
+        assert_eq!(cpu.sp, 0xFE);
+        assert_eq!(cpu.bus().peek(0x0100), 0x00);
+        assert_eq!(cpu.bus().peek(0x01FF), 0x00);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cpu.sp, 0x00);
+    }
+
+    #[test]
+    fn test_parse_nestest_annotations() {
+        let lines = [
+            ("C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12", Some(0x00)),
+            ("DBEF  B4 FF     LDY $FF,X @ 89 = BB             A:66 X:8A Y:00 P:26 SP:FB PPU: 91,253 CYC:10428", Some(0x89)),
+            ("D940  B1 97     LDA ($97),Y = FFFF @ 0033 = A3  A:FF X:65 Y:34 P:65 SP:FB PPU: 77,122 CYC:8793", Some(0x0033)),
+            ("CFE3  A1 80     LDA ($80,X) @ 82 = 0300 = 5B    A:5A X:02 Y:69 P:25 SP:FB PPU: 22,181 CYC:2561", Some(0x0300)),
+            ("DBB5  6C FF 02  JMP ($02FF) = 0300              A:60 X:07 Y:00 P:65 SP:F9 PPU: 84,201 CYC:9615", Some(0x0300)),
+            ("C5FD  20 2D C7  JSR $C72D                       A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 63 CYC:21", None),
+            ("CEFC  4A        LSR A                           A:01 X:55 Y:69 P:65 SP:FB PPU: 20, 53 CYC:2291", None),
+        ];
+        for (line, operand_address) in lines {
+            assert_eq!(parse_nestest_line(line.to_string()).operand_address, operand_address);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
     fn test_nestest() {
         let nestest_trace = parse_nestest_trace(NESTEST_TRACE_PATH);
         let cpu_trace = run_nestest(NESTEST_ROM_PATH, nestest_trace.len());
-        for (cpu_tr, nestest_tr) in std::iter::zip(cpu_trace, nestest_trace) {
-            assert_eq!(cpu_tr, nestest_tr);
+        if let Some(diff) = crate::trace::compare_traces(&cpu_trace, &nestest_trace) {
+            panic!("nestest diverged: {diff}");
+        }
+    }
+
+    // A stand-in for nestest that needs no external files: a short program
+    // for flat memory at $0600 and its log in nestest's format.
+    #[cfg(feature = "std")]
+    const EMBEDDED_PROGRAM: [u8; 19] = [
+        0xA2, 0x02, // LDX #$02
+        0xA9, 0x10, // LDA #$10
+        0x9D, 0x00, 0x02, // STA $0200,X
+        0xCA, // DEX
+        0xD0, 0xFA, // BNE $0604
+        0x20, 0x0F, 0x06, // JSR $060F
+        0xEA, // NOP
+        0x02, // JAM
+        0x38, // SEC
+        0x69, 0x01, // ADC #$01
+        0x60, // RTS
+    ];
+    #[cfg(feature = "std")]
+    const EMBEDDED_TRACE: &str = "\
+0600  A2 02     LDX #$02                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+0602  A9 10     LDA #$10                        A:00 X:02 Y:00 P:24 SP:FD PPU:  0, 27 CYC:9
+0604  9D 00 02  STA $0200,X @ 0202 = 00         A:10 X:02 Y:00 P:24 SP:FD PPU:  0, 33 CYC:11
+0607  CA        DEX                             A:10 X:02 Y:00 P:24 SP:FD PPU:  0, 48 CYC:16
+0608  D0 FA     BNE $0604                       A:10 X:01 Y:00 P:24 SP:FD PPU:  0, 54 CYC:18
+0604  9D 00 02  STA $0200,X @ 0201 = 00         A:10 X:01 Y:00 P:24 SP:FD PPU:  0, 63 CYC:21
+0607  CA        DEX                             A:10 X:01 Y:00 P:24 SP:FD PPU:  0, 78 CYC:26
+0608  D0 FA     BNE $0604                       A:10 X:00 Y:00 P:26 SP:FD PPU:  0, 84 CYC:28
+060A  20 0F 06  JSR $060F                       A:10 X:00 Y:00 P:26 SP:FD PPU:  0, 90 CYC:30
+060F  38        SEC                             A:10 X:00 Y:00 P:26 SP:FB PPU:  0,108 CYC:36
+0610  69 01     ADC #$01                        A:10 X:00 Y:00 P:27 SP:FB PPU:  0,114 CYC:38
+0612  60        RTS                             A:12 X:00 Y:00 P:24 SP:FB PPU:  0,120 CYC:40
+060D  EA        NOP                             A:12 X:00 Y:00 P:24 SP:FD PPU:  0,138 CYC:46
+";
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_embedded_conformance_program() {
+        let expected: Vec<TraceEntry> = EMBEDDED_TRACE
+            .lines()
+            .map(|line| parse_nestest_line(line.to_string()))
+            .collect();
+        let mut cpu = flat_cpu(&EMBEDDED_PROGRAM);
+        let trace: Vec<TraceEntry> = (0..expected.len()).map(|_| cpu.trace_step()).collect();
+        if let Some(diff) = crate::trace::compare_traces(&trace, &expected) {
+            panic!("embedded program diverged: {diff}");
+        }
+        assert_eq!(cpu.bus().peek_u16_le(0x0201), 0x1010);
+        cpu.step();
+        assert!(cpu.is_halted());
+    }
+
+    #[test]
+    fn test_illegal_opcode_nop_policy() {
+        // illegal; INX
+        let mut cpu = flat_cpu(&[0x03, 0xE8]);
+        cpu.config.illegal_opcode_policy = IllegalOpcodePolicy::Nop;
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.pc, 0x0601);
+        cpu.step();
+        assert_eq!(cpu.x, 1);
+        assert!(!cpu.is_halted());
+    }
+
+    #[test]
+    fn test_illegal_opcode_skip_policy() {
+        // NOP $0000 (unofficial, 3 bytes); NOP #$E8 (unofficial, 2 bytes); INX
+        let mut cpu = flat_cpu(&[0x0C, 0xE8, 0xE8, 0x80, 0xE8, 0xE8]);
+        cpu.config.illegal_opcode_policy = IllegalOpcodePolicy::Skip;
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.pc, 0x0603);
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.pc, 0x0605);
+        cpu.step();
+        assert_eq!(cpu.x, 1);
+        // KIL slots and unofficial implied NOPs have no operand
+        let lengths = [0x02, 0x1A, 0x04, 0x14, 0x89, 0x03, 0x13, 0x0C, 0x1C, 0x1B, 0x9E]
+            .map(Cpu::<bus::FlatMemory>::undefined_operand_len);
+        assert_eq!(lengths, [0, 0, 1, 1, 1, 1, 1, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_illegal_opcode_halt_policy() {
+        let mut cpu = flat_cpu(&[0x03, 0xE8]);
+        cpu.config.illegal_opcode_policy = IllegalOpcodePolicy::Halt;
+        assert_eq!(cpu.try_step(), Ok(0));
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.pc, 0x0600);
+    }
+
+    #[test]
+    fn test_illegal_opcode_error_policy() {
+        let mut cpu = flat_cpu(&[0xE8, 0x03]);
+        cpu.config.illegal_opcode_policy = IllegalOpcodePolicy::Error;
+        assert_eq!(cpu.try_step(), Ok(2));
+        assert_eq!(cpu.try_step(), Err(IllegalOpcodeError { opcode: 0x03, pc: 0x0601 }));
+        assert!(cpu.is_halted());
+    }
+
+    // Flat memory that logs every read, to observe dummy reads.
+    #[derive(Default)]
+    struct RecordingBus {
+        memory: bus::FlatMemory,
+        reads: Vec<u16>,
+        writes: Vec<(u16, u8)>,
+    }
+
+    impl Bus for RecordingBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.reads.push(address);
+            self.memory.read(address)
+        }
+
+        fn peek(&self, address: u16) -> u8 {
+            self.memory.peek(address)
         }
+
+        fn write(&mut self, address: u16, data: u8) {
+            self.writes.push((address, data));
+            self.memory.write(address, data);
+        }
+    }
+
+    #[test]
+    fn test_indexed_rmw_dummy_read() {
+        let mut cpu = Cpu::new(RecordingBus::default());
+        // LDX #$01; INC $12FF,X; INC $1200,X
+        cpu.bus_mut().memory.load(0x0600, &[0xA2, 0x01, 0xFE, 0xFF, 0x12, 0xFE, 0x00, 0x12]);
+        cpu.bus_mut().memory.load(0x1300, &[0x41]);
+        cpu.bus_mut().memory.set_reset_vector(0x0600);
+        cpu.power_on(None);
+        cpu.step();
+        cpu.bus_mut().reads.clear();
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.bus().reads, [0x0602, 0x0603, 0x0604, 0x1200, 0x1300]);
+        assert_eq!(cpu.bus().writes, [(0x1300, 0x41), (0x1300, 0x42)]);
+        // without a crossing the dummy read hits the target address
+        cpu.bus_mut().reads.clear();
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.bus().reads, [0x0605, 0x0606, 0x0607, 0x1201, 0x1201]);
+    }
+
+    #[test]
+    fn test_indexed_store_dummy_read() {
+        let mut cpu = Cpu::new(RecordingBus::default());
+        // LDY #$01; LDA #$42; STA $12FF,Y
+        cpu.bus_mut().memory.load(0x0600, &[0xA0, 0x01, 0xA9, 0x42, 0x99, 0xFF, 0x12]);
+        cpu.bus_mut().memory.set_reset_vector(0x0600);
+        cpu.power_on(None);
+        cpu.step_many(2);
+        cpu.bus_mut().reads.clear();
+        assert_eq!(cpu.step(), 5);
+        assert_eq!(cpu.bus().reads, [0x0604, 0x0605, 0x0606, 0x1200]);
+        assert_eq!(cpu.bus().peek(0x1300), 0x42);
+    }
+
+    #[test]
+    fn test_instruction_table() {
+        let table = Cpu::<bus::FlatMemory>::instruction_table();
+        let defined = table.iter().filter(|info| !info.mnemonic.is_empty()).count();
+        // 151 official opcodes plus the implemented unofficial ones
+        assert_eq!(defined, 163);
+        assert_eq!(
+            table[0xBD],
+            InstructionInfo {
+                opcode: 0xBD,
+                mnemonic: "LDA",
+                cycles: 4,
+                address_mode: "absolute,X",
+                bytes: 3,
+                page_cross_penalty: true,
+            }
+        );
+        assert_eq!((table[0x4C].mnemonic, table[0x4C].cycles, table[0x4C].bytes), ("JMP", 3, 3));
+        assert_eq!((table[0xE8].address_mode, table[0xE8].bytes), ("implied", 1));
+        assert_eq!((table[0x6C].address_mode, table[0x6C].bytes), ("indirect", 3));
+        assert_eq!((table[0xB1].address_mode, table[0xB1].bytes), ("(indirect),Y", 2));
+    }
+
+    #[test]
+    fn test_unimplemented_opcodes() {
+        let unimplemented = Cpu::<bus::FlatMemory>::unimplemented_opcodes();
+        assert_eq!(unimplemented.len(), 0x100 - 163);
+        // SLO (indirect,X) is not implemented yet
+        assert!(unimplemented.contains(&0x03));
+        assert!(!unimplemented.contains(&0x02));
+        assert!(!unimplemented.contains(&0xA9));
+    }
+
+    #[test]
+    fn test_indexed_read_page_cross_dummy_read() {
+        let mut cpu = Cpu::new(RecordingBus::default());
+        // LDX #$01; LDA $12FF,X; LDA $1200,X
+        cpu.bus_mut().memory.load(0x0600, &[0xA2, 0x01, 0xBD, 0xFF, 0x12, 0xBD, 0x00, 0x12]);
+        cpu.bus_mut().memory.load(0x1300, &[0x42]);
+        cpu.bus_mut().memory.set_reset_vector(0x0600);
+        cpu.power_on(None);
+        cpu.step();
+        cpu.bus_mut().reads.clear();
+        assert_eq!(cpu.step(), 5);
+        assert_eq!(cpu.bus().reads, [0x0602, 0x0603, 0x0604, 0x1200, 0x1300]);
+        assert_eq!(cpu.a, 0x42);
+        cpu.bus_mut().reads.clear();
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.bus().reads, [0x0605, 0x0606, 0x0607, 0x1201]);
+    }
+
+    #[test]
+    fn test_pending_interrupts() {
+        // NOP; NOP
+        let mut cpu = flat_cpu(&[0xEA, 0xEA]);
+        cpu.bus_mut().load(0x0800, &[0xEA]);
+        assert_eq!(cpu.pending_interrupts(), Interrupts::default());
+        cpu.set_nmi_line(true);
+        assert_eq!(cpu.pending_interrupts(), Interrupts { nmi: true, irq: false });
+        cpu.step();
+        assert!(cpu.pending_interrupts().nmi);
+        // the NMI is serviced instead of the second NOP
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0800);
+        assert!(!cpu.pending_interrupts().nmi);
+
+        cpu.set_irq_line(true);
+        cpu.set_nmi_line(false);
+        cpu.set_nmi_line(true);
+        assert_eq!(cpu.pending_interrupts(), Interrupts { nmi: true, irq: true });
+        cpu.clear_pending();
+        assert_eq!(cpu.pending_interrupts(), Interrupts::default());
+    }
+
+    #[test]
+    fn test_reset_cycle_seeding() {
+        let mut cpu = Cpu::with_flat_memory();
+        cpu.power_on(None);
+        assert_eq!(cpu.total_cycles(), 7);
+        cpu.reset(None);
+        assert_eq!(cpu.total_cycles(), 14);
+
+        let config = CpuConfig {
+            seed_reset_cycles: false,
+            ..Default::default()
+        };
+        let mut cpu = Cpu::with_config(bus::FlatMemory::new(), config);
+        cpu.power_on(None);
+        assert_eq!(cpu.total_cycles(), 0);
+        cpu.reset(None);
+        assert_eq!(cpu.total_cycles(), 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "operand address out of range")]
+    fn test_operand_address_out_of_range() {
+        let mut cpu = flat_cpu(&[0xAD, 0x00, 0x02]);
+        cpu.address_mode = AddressMode::Absolute;
+        cpu.operand_address = Some(0x10000);
+        cpu.get_operand();
+    }
+
+    #[test]
+    fn test_disassemble() {
+        // LDA #$05; ASL A; STA $10; LDA ($10),Y; BNE -4; JMP ($0300); INX; illegal
+        let program = [
+            0xA9, 0x05, 0x0A, 0x85, 0x10, 0xB1, 0x10, 0xD0, 0xFC, 0x6C, 0x00, 0x03, 0xE8, 0x03,
+        ];
+        let mut cpu = flat_cpu(&program);
+        cpu.bus_mut().load(0x0010, &[0x00, 0x02]);
+        cpu.bus_mut().load(0x0200, &[0x7F]);
+        cpu.bus_mut().load(0x0300, &[0x00, 0xC0]);
+        assert_eq!(cpu.disassemble(0x0600), "LDA #$05");
+        assert_eq!(cpu.disassemble(0x0602), "ASL A");
+        assert_eq!(cpu.disassemble(0x0603), "STA $10 = 00");
+        assert_eq!(cpu.disassemble(0x0605), "LDA ($10),Y = 0200 @ 0200 = 7F");
+        assert_eq!(cpu.disassemble(0x0607), "BNE $0605");
+        assert_eq!(cpu.disassemble(0x0609), "JMP ($0300) = C000");
+        assert_eq!(cpu.disassemble(0x060C), "INX");
+        assert_eq!(cpu.disassemble(0x060D), ".byte $03");
+    }
+
+    #[test]
+    fn test_indirect_jump_at_top_of_memory() {
+        // JMP ($FFFF): the high byte comes from $FF00
+        let mut cpu = flat_cpu(&[0x6C, 0xFF, 0xFF]);
+        cpu.bus_mut().load(0xFF00, &[0x12]);
+        assert_eq!(cpu.effective_address(0x0600), Some(0x1207));
+        cpu.step();
+        assert_eq!(cpu.pc, 0x1207);
+    }
+
+    #[test]
+    fn test_jam_at_top_of_memory() {
+        // JMP $FFFF to a JAM, whose fetch wraps PC to $0000
+        let mut cpu = flat_cpu(&[0x4C, 0xFF, 0xFF]);
+        cpu.bus_mut().load(0xFFFF, &[0x02]);
+        cpu.step();
+        cpu.step();
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.pc, 0xFFFF);
+    }
+
+    #[test]
+    fn test_zeropage_pointer_wrap() {
+        // LDA ($FF,X) with X = 0; LDA ($FF),Y with Y = 2
+        let mut cpu = flat_cpu(&[0xA1, 0xFF, 0xB1, 0xFF]);
+        cpu.bus_mut().load(0x0000, &[0x03]);
+        cpu.bus_mut().load(0x00FF, &[0x00, 0x06]);
+        cpu.bus_mut().load(0x0300, &[0x11, 0x22, 0x33]);
+        assert_eq!(cpu.effective_address(0x0600), Some(0x0300));
+        cpu.step();
+        assert_eq!(cpu.operand_address, Some(0x0300));
+        assert_eq!(cpu.a, 0x11);
+        cpu.y = 0x02;
+        assert_eq!(cpu.effective_address(0x0602), Some(0x0302));
+        cpu.step();
+        assert_eq!(cpu.operand_address, Some(0x0302));
+        assert_eq!(cpu.a, 0x33);
+    }
+
+    #[test]
+    fn test_disassembly_window() {
+        // LDA #$05; ASL A; STA $10; LDA ($10),Y; BNE -4; JMP ($0300)
+        let program = [0xA9, 0x05, 0x0A, 0x85, 0x10, 0xB1, 0x10, 0xD0, 0xFC, 0x6C, 0x00, 0x03];
+        let mut cpu = flat_cpu(&program);
+        cpu.bus_mut().load(0x0010, &[0x00, 0x02]);
+        cpu.bus_mut().load(0x0300, &[0x00, 0xC0]);
+        let window = cpu.disassembly_window(0x0605, 2, 2);
+        assert_eq!(
+            window,
+            vec![
+                (0x0602, "ASL A".to_string()),
+                (0x0603, "STA $10 = 00".to_string()),
+                (0x0605, "LDA ($10),Y = 0200 @ 0200 = 00".to_string()),
+                (0x0607, "BNE $0605".to_string()),
+                (0x0609, "JMP ($0300) = C000".to_string()),
+            ]
+        );
+        assert_eq!(cpu.disassembly_window(0x0600, 0, 0), vec![(0x0600, "LDA #$05".to_string())]);
     }
 }
@@ -1,51 +1,1626 @@
-use crate::bus;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+#[cfg(feature = "png")]
+use std::path::Path;
+
+use crate::apu;
+use crate::bus::{self, Bus};
+use crate::controller;
 use crate::cpu;
+#[cfg(feature = "png")]
+use crate::{palette, png};
+use crate::ppu;
 use crate::rom;
+use crate::state::{self, StateReader};
+use crate::timing;
+use crate::trace;
+
+pub const NTSC_CYCLES_PER_FRAME: usize = timing::NTSC.cycles_per_frame();
+pub const PAL_CYCLES_PER_FRAME: usize = timing::PAL.cycles_per_frame();
+
+// instructions shown either side of PC by Emulator::diagnostics
+const DIAGNOSTIC_WINDOW: usize = 4;
 
-pub struct Emulator {
-    cpu: cpu::Cpu,
+// how far apart the PCs of a stalled loop can be
+const STALL_PC_RANGE: u16 = 16;
+
+// Called with the trace entry of every executed instruction.
+type InstructionHook = Box<dyn FnMut(&cpu::TraceEntry)>;
+
+pub struct Emulator<B: Bus = bus::CpuBus> {
+    cpu: cpu::Cpu<B>,
+    // states before the most recent steps, oldest first
+    rewind: VecDeque<EmulatorState>,
+    rewind_capacity: usize,
+    // clock ratios of the cartridge's region
+    timing: timing::RegionTiming,
+    instruction_hook: Option<InstructionHook>,
+    // breakpoint addresses and whether each is enabled
+    breakpoints: HashMap<u16, bool>,
+    // freeze the PPU and APU during step, for debugging the CPU in isolation
+    is_step_clocks_paused: bool,
+    input_recording: Option<InputLog>,
+    input_playback: Option<std::vec::IntoIter<[u8; 2]>>,
+    // instructions a loop has to spin for before bounded runs report it
+    stall_instructions: Option<usize>,
 }
 
-impl Emulator {
-    pub fn new(cartridge: rom::Cartridge, cpu_pc: Option<u16>) -> Self {
-        let mut emulator = Self {
-            cpu: cpu::Cpu::new(bus::CpuBus::new(cartridge)),
+// Button masks of both controllers for each frame run with run_frame, from
+// the first frame recorded. Replaying one on an emulator built the same way,
+// RAM pattern included, reproduces the recorded run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputLog {
+    pub frames: Vec<[u8; 2]>,
+}
+
+// Everything needed to resume emulation. The framebuffer, audio samples
+// and emulator settings are not part of it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmulatorState {
+    pub cpu: cpu::CpuState,
+    pub ram: Vec<u8>,
+    // Cartridge::crc32 of the game the state was saved from
+    pub cartridge_crc32: u32,
+    // PPU, APU, controllers and cartridge, in CpuBus::save_state's layout
+    pub devices: Vec<u8>,
+}
+
+impl EmulatorState {
+    // RAM bytes that differ from other, as (address, ours, theirs).
+    pub fn diff(&self, other: &EmulatorState) -> Vec<(u16, u8, u8)> {
+        self.ram
+            .iter()
+            .zip(other.ram.iter())
+            .enumerate()
+            .filter(|(_, (ours, theirs))| ours != theirs)
+            .map(|(address, (&ours, &theirs))| (address as u16, ours, theirs))
+            .collect()
+    }
+
+    // The on-disk format: magic, version, then little-endian fields in a
+    // fixed order: cartridge CRC32, CPU state, RAM length and RAM, device
+    // state length and device state.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(STATE_MAGIC);
+        bytes.push(STATE_VERSION);
+        bytes.extend_from_slice(&self.cartridge_crc32.to_le_bytes());
+        bytes.extend_from_slice(&self.cpu.to_bytes());
+        bytes.extend_from_slice(&(self.ram.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&self.ram);
+        state::write_sized(&mut bytes, &self.devices);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StateFormatError> {
+        if bytes.len() < STATE_HEADER_SIZE {
+            return Err(StateFormatError::Truncated);
+        }
+        let (header, body) = bytes.split_at(STATE_HEADER_SIZE);
+        if &header[..STATE_MAGIC.len()] != STATE_MAGIC {
+            return Err(StateFormatError::BadMagic);
+        }
+        if header[STATE_MAGIC.len()] != STATE_VERSION {
+            return Err(StateFormatError::UnsupportedVersion(header[STATE_MAGIC.len()]));
+        }
+        let (crc32, body) = body.split_first_chunk::<4>().ok_or(StateFormatError::Truncated)?;
+        let (cpu, body) = body
+            .split_first_chunk::<{ cpu::CpuState::ENCODED_SIZE }>()
+            .ok_or(StateFormatError::Truncated)?;
+        let (ram_len, body) = body.split_first_chunk::<2>().ok_or(StateFormatError::Truncated)?;
+        // anything but the console's 2KB would not fit the bus on load
+        if u16::from_le_bytes(*ram_len) as usize != bus::RAM_SIZE {
+            return Err(StateFormatError::Corrupt);
+        }
+        let mut rest = StateReader::new(body);
+        let ram = rest.bytes(bus::RAM_SIZE).ok_or(StateFormatError::Truncated)?;
+        let devices = rest.sized().ok_or(StateFormatError::Truncated)?;
+        if !rest.is_empty() {
+            return Err(StateFormatError::Corrupt);
+        }
+        Ok(Self {
+            cpu: cpu::CpuState::from_bytes(cpu).ok_or(StateFormatError::Corrupt)?,
+            ram: ram.to_vec(),
+            cartridge_crc32: u32::from_le_bytes(*crc32),
+            devices: devices.to_vec(),
+        })
+    }
+
+    // CPU registers that differ from other, as (name, ours, theirs).
+    pub fn register_diff(&self, other: &EmulatorState) -> Vec<(&'static str, u16, u16)> {
+        let (ours, theirs) = (&self.cpu, &other.cpu);
+        [
+            ("A", ours.a as u16, theirs.a as u16),
+            ("X", ours.x as u16, theirs.x as u16),
+            ("Y", ours.y as u16, theirs.y as u16),
+            ("P", ours.p as u16, theirs.p as u16),
+            ("SP", ours.sp as u16, theirs.sp as u16),
+            ("PC", ours.pc, theirs.pc),
+        ]
+        .into_iter()
+        .filter(|(_, ours, theirs)| ours != theirs)
+        .collect()
+    }
+}
+
+// Header of the binary save state format, followed by the version byte.
+const STATE_MAGIC: &[u8; 4] = b"MYNS";
+// bumped whenever the layout after the header changes
+pub const STATE_VERSION: u8 = 2;
+const STATE_HEADER_SIZE: usize = STATE_MAGIC.len() + 1;
+
+#[derive(Debug, PartialEq)]
+pub enum StateFormatError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    Corrupt,
+}
+
+impl fmt::Display for StateFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateFormatError::BadMagic => write!(f, "not a save state"),
+            StateFormatError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {version} is not supported, expected {STATE_VERSION}"
+            ),
+            StateFormatError::Truncated => write!(f, "save state is truncated"),
+            StateFormatError::Corrupt => write!(f, "save state is corrupt"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StateMismatchError {
+    pub state_crc32: u32,
+    pub cartridge_crc32: u32,
+}
+
+impl fmt::Display for StateMismatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "state is for cartridge {:08X}, loaded cartridge is {:08X}",
+            self.state_crc32, self.cartridge_crc32
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LoadStateError {
+    WrongCartridge(StateMismatchError),
+    // the device state doesn't fit the loaded cartridge and settings
+    Corrupt,
+}
+
+impl fmt::Display for LoadStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadStateError::WrongCartridge(err) => err.fmt(f),
+            LoadStateError::Corrupt => write!(f, "save state does not fit the loaded cartridge"),
+        }
+    }
+}
+
+// Why a bounded run stopped.
+#[derive(Debug, PartialEq)]
+pub enum RunOutcome {
+    Breakpoint(u16),
+    Halted,
+    BudgetExhausted,
+    IllegalOpcode { opcode: u8, pc: u16 },
+    // the instruction at pc pushed SP past the stack low-water mark
+    StackOverflow { pc: u16, sp: u8 },
+    // the watched address held the awaited value after the instruction
+    // before pc
+    Watchpoint { address: u16, pc: u16 },
+    // PC stayed within a few bytes without writing memory; the PC it was at
+    StalledLoop(u16),
+}
+
+// Options for constructing an Emulator; anything not set keeps the defaults
+// Emulator::new uses.
+pub struct EmulatorBuilder {
+    cartridge: rom::Cartridge,
+    start_pc: Option<u16>,
+    ram_pattern: bus::RamPattern,
+    bus_mode: bus::BusMode,
+    cpu_config: cpu::CpuConfig,
+    region: Option<rom::TvSystem>,
+    rewind_capacity: usize,
+}
+
+impl EmulatorBuilder {
+    pub fn new(cartridge: rom::Cartridge) -> Self {
+        Self {
+            cartridge,
+            start_pc: None,
+            ram_pattern: bus::RamPattern::default(),
+            bus_mode: bus::BusMode::default(),
+            cpu_config: cpu::CpuConfig::default(),
+            region: None,
+            rewind_capacity: 0,
+        }
+    }
+
+    // Starts here instead of at the reset vector.
+    pub fn start_pc(mut self, pc: u16) -> Self {
+        self.start_pc = Some(pc);
+        self
+    }
+
+    pub fn ram_pattern(mut self, pattern: bus::RamPattern) -> Self {
+        self.ram_pattern = pattern;
+        self
+    }
+
+    pub fn bus_mode(mut self, mode: bus::BusMode) -> Self {
+        self.bus_mode = mode;
+        self
+    }
+
+    pub fn illegal_opcode_policy(mut self, policy: cpu::IllegalOpcodePolicy) -> Self {
+        self.cpu_config.illegal_opcode_policy = policy;
+        self
+    }
+
+    pub fn stack_low_water(mut self, mark: u8) -> Self {
+        self.cpu_config.stack_low_water = Some(mark);
+        self
+    }
+
+    pub fn cpu_config(mut self, config: cpu::CpuConfig) -> Self {
+        self.cpu_config = config;
+        self
+    }
+
+    // Overrides the region from the header, for dumps that flag it wrong.
+    pub fn region(mut self, region: rom::TvSystem) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    pub fn rewind_capacity(mut self, capacity: usize) -> Self {
+        self.rewind_capacity = capacity;
+        self
+    }
+
+    pub fn build(self) -> Emulator {
+        let region = self.region.unwrap_or(self.cartridge.tv_system());
+        let region_timing = match region {
+            rom::TvSystem::NTSC => timing::NTSC,
+            rom::TvSystem::PAL => timing::PAL,
         };
-        emulator.cpu.reset(cpu_pc);
+        let mut bus = bus::CpuBus::with_mode(self.cartridge, self.bus_mode);
+        bus.fill_ram(self.ram_pattern);
+        bus.ppu_mut().set_timing(region_timing);
+        let mut emulator = Emulator::from_cpu(cpu::Cpu::with_config(bus, self.cpu_config));
+        emulator.timing = region_timing;
+        emulator.rewind_capacity = self.rewind_capacity;
+        emulator.cpu.power_on(self.start_pc);
         emulator
     }
+}
+
+impl Emulator {
+    pub fn new(cartridge: rom::Cartridge, cpu_pc: Option<u16>) -> Self {
+        let builder = Self::builder(cartridge);
+        match cpu_pc {
+            Some(pc) => builder.start_pc(pc),
+            None => builder,
+        }
+        .build()
+    }
+
+    pub fn builder(cartridge: rom::Cartridge) -> EmulatorBuilder {
+        EmulatorBuilder::new(cartridge)
+    }
+
+    pub fn save_state(&self) -> EmulatorState {
+        EmulatorState {
+            cpu: self.cpu.save_state(),
+            ram: self.cpu.bus().ram().to_vec(),
+            cartridge_crc32: self.cpu.bus().cartridge().crc32(),
+            devices: self.cpu.bus().save_state(),
+        }
+    }
 
-    pub fn run(&mut self) {
+    // Refuses states saved from a different game or with device state that
+    // doesn't fit, leaving the emulator as is.
+    pub fn load_state(&mut self, state: &EmulatorState) -> Result<(), LoadStateError> {
+        let cartridge_crc32 = self.cpu.bus().cartridge().crc32();
+        if state.cartridge_crc32 != cartridge_crc32 {
+            return Err(LoadStateError::WrongCartridge(StateMismatchError {
+                state_crc32: state.cartridge_crc32,
+                cartridge_crc32,
+            }));
+        }
+        let devices = self.cpu.bus().save_state();
+        if self.cpu.bus_mut().load_state(&state.devices).is_none() {
+            // undo whatever was loaded before the mismatch
+            self.cpu.bus_mut().load_state(&devices).unwrap();
+            return Err(LoadStateError::Corrupt);
+        }
+        self.cpu.load_state(&state.cpu);
+        self.cpu.bus_mut().load_ram(&state.ram);
+        Ok(())
+    }
+
+    // Keeps the states before the last `capacity` steps for step_back; 0 disables rewinding.
+    pub fn set_rewind_capacity(&mut self, capacity: usize) {
+        self.rewind_capacity = capacity;
+        while self.rewind.len() > capacity {
+            self.rewind.pop_front();
+        }
+    }
+
+    pub fn step(&mut self) -> u8 {
+        if self.rewind_capacity > 0 {
+            if self.rewind.len() == self.rewind_capacity {
+                self.rewind.pop_front();
+            }
+            self.rewind.push_back(self.save_state());
+        }
+        self.cpu.bus_mut().set_clocks_paused(self.is_step_clocks_paused);
+        let cycles = self.cpu.step();
+        self.cpu.bus_mut().set_clocks_paused(false);
+        cycles
+    }
+
+    // Only affects step; the run methods always clock every component.
+    pub fn set_step_clocks_paused(&mut self, is_paused: bool) {
+        self.is_step_clocks_paused = is_paused;
+    }
+
+    // Restores the state before the most recent step, if one was recorded.
+    pub fn step_back(&mut self) -> bool {
+        match self.rewind.pop_back() {
+            Some(state) => {
+                // recorded from this emulator, so always the same cartridge
+                self.load_state(&state).unwrap();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn ppu(&self) -> &ppu::Ppu {
+        self.cpu.bus().ppu()
+    }
+
+    // Runs until the PPU starts its next scanline, finishing the instruction
+    // that crosses into it.
+    pub fn step_scanline(&mut self) -> RunOutcome {
+        let cycles = self.ppu().cycles_to_next_scanline() as usize;
+        self.run_for_cycles(cycles)
+    }
+
+    // True once per completed picture; see Ppu::take_frame_ready.
+    pub fn take_frame_ready(&mut self) -> bool {
+        self.cpu.bus_mut().ppu_mut().take_frame_ready()
+    }
+
+    // See CpuBus::set_dmc_controller_conflict.
+    pub fn set_dmc_controller_conflict(&mut self, is_enabled: bool) {
+        self.cpu.bus_mut().set_dmc_controller_conflict(is_enabled);
+    }
+
+    // See CpuBus::set_rom_write_logging.
+    pub fn set_rom_write_logging(&mut self, is_enabled: bool) {
+        self.cpu.bus_mut().set_rom_write_logging(is_enabled);
+    }
+
+    pub fn take_rom_writes(&mut self) -> Vec<(u16, u8)> {
+        self.cpu.bus_mut().take_rom_writes()
+    }
+
+    pub fn apu_mut(&mut self) -> &mut apu::Apu {
+        self.cpu.bus_mut().apu_mut()
+    }
+
+    // Runs whole PPU frames. Each ends with the instruction that carries the
+    // PPU into the next frame, and the next budget is taken from where the
+    // PPU is, so frame boundaries don't drift.
+    pub fn run_frames(&mut self, frames: usize) -> RunOutcome {
+        for _ in 0..frames {
+            let cycles = self.ppu().cycles_to_next_frame() as usize;
+            match self.run_for_cycles(cycles) {
+                RunOutcome::BudgetExhausted => (),
+                outcome => return outcome,
+            }
+        }
+        RunOutcome::BudgetExhausted
+    }
+
+    // Runs frames with video and audio output switched off, for skipping
+    // ahead quickly; only the last frame is drawn and produces samples.
+    // Timing is unaffected, so this ends on the same cycle as run_frames.
+    pub fn fast_forward(&mut self, frames: usize) -> RunOutcome {
+        if frames == 0 {
+            return RunOutcome::BudgetExhausted;
+        }
+        self.set_output_enabled(false);
+        let outcome = self.run_frames(frames - 1);
+        self.set_output_enabled(true);
+        match outcome {
+            RunOutcome::BudgetExhausted => self.run_frames(1),
+            outcome => outcome,
+        }
+    }
+
+    fn set_output_enabled(&mut self, is_enabled: bool) {
+        let bus = self.cpu.bus_mut();
+        bus.ppu_mut().set_render_enabled(is_enabled);
+        bus.apu_mut().set_output_enabled(is_enabled);
+    }
+
+    // Sets every button of the controller on port from a bitmask,
+    // bit 0 = A, B, Select, Start, Up, Down, Left, bit 7 = Right.
+    pub fn set_controller_state(&mut self, port: controller::Port, mask: u8) {
+        self.cpu.bus_mut().controller_mut(port).set_buttons(mask);
+    }
+
+    // Coin slots, service button and DIP switches of a VS. System cabinet;
+    // None for other cartridges.
+    pub fn vs_inputs_mut(&mut self) -> Option<&mut controller::VsInputs> {
+        self.cpu.bus_mut().vs_inputs_mut()
+    }
+
+    pub fn start_recording(&mut self) {
+        self.input_recording = Some(InputLog::default());
+    }
+
+    pub fn stop_recording(&mut self) -> InputLog {
+        self.input_recording.take().unwrap_or_default()
+    }
+
+    // Drives the controllers from log in the following run_frame calls,
+    // overriding set_controller_state until the log runs out.
+    pub fn play(&mut self, log: InputLog) {
+        self.input_playback = Some(log.frames.into_iter());
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.input_playback.is_some()
+    }
+
+    // Runs one frame, taking its input from the log being played and adding
+    // it to the one being recorded.
+    pub fn run_frame(&mut self) -> RunOutcome {
+        if let Some(frames) = &mut self.input_playback {
+            match frames.next() {
+                Some(masks) => {
+                    for (port, mask) in controller::Port::ALL.into_iter().zip(masks) {
+                        self.cpu.bus_mut().controller_mut(port).set_buttons(mask);
+                    }
+                }
+                None => self.input_playback = None,
+            }
+        }
+        if let Some(log) = &mut self.input_recording {
+            let bus = self.cpu.bus();
+            log.frames.push(controller::Port::ALL.map(|port| bus.controller(port).buttons()));
+        }
+        self.run_frames(1)
+    }
+
+    #[cfg(feature = "png")]
+    pub fn save_screenshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let rgba = self.ppu().to_rgba(&palette::Palette::default());
+        let data = png::encode_rgba(ppu::SCREEN_WIDTH as u32, ppu::SCREEN_HEIGHT as u32, &rgba);
+        std::fs::write(path, data)
+    }
+}
+
+impl Emulator<bus::FlatMemory> {
+    // Runs a headerless binary on plain 64KB memory, for 6502 test suites
+    // and experiments that are not NES programs.
+    pub fn load_flat(bytes: &[u8], load_addr: u16, reset_pc: u16) -> Self {
+        let mut cpu = cpu::Cpu::with_flat_memory();
+        cpu.bus_mut().load(load_addr, bytes);
+        cpu.bus_mut().set_reset_vector(reset_pc);
+        cpu.power_on(None);
+        Self::from_cpu(cpu)
+    }
+}
+
+impl<B: Bus> Emulator<B> {
+    fn from_cpu(cpu: cpu::Cpu<B>) -> Self {
+        Self {
+            cpu,
+            rewind: VecDeque::new(),
+            rewind_capacity: 0,
+            timing: timing::NTSC,
+            instruction_hook: None,
+            breakpoints: HashMap::new(),
+            is_step_clocks_paused: false,
+            input_recording: None,
+            input_playback: None,
+            stall_instructions: None,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, pc: u16) {
+        self.breakpoints.insert(pc, true);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u16) {
+        self.breakpoints.remove(&pc);
+    }
+
+    // Does nothing for an address without a breakpoint.
+    pub fn enable_breakpoint(&mut self, pc: u16, is_enabled: bool) {
+        if let Some(enabled) = self.breakpoints.get_mut(&pc) {
+            *enabled = is_enabled;
+        }
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    fn is_breakpoint(&self, pc: u16) -> bool {
+        self.breakpoints.get(&pc).copied().unwrap_or(false)
+    }
+
+    // Bounded runs stop with RunOutcome::StalledLoop after this many
+    // instructions within STALL_PC_RANGE bytes and without a memory write,
+    // the usual shape of a hang. Waiting for NMI in such a loop is normal,
+    // so this is off by default.
+    pub fn set_stall_detection(&mut self, instructions: Option<usize>) {
+        self.stall_instructions = instructions;
+    }
+
+    // Bounded runs stop with RunOutcome::StackOverflow once a push takes SP
+    // below mark. None turns the check off.
+    pub fn set_stack_low_water(&mut self, mark: Option<u8>) {
+        self.cpu.set_stack_low_water(mark);
+    }
+
+    // Called after every instruction run and the bounded runs execute, with
+    // the instruction's trace entry. Without a hook no trace entries are built.
+    pub fn set_instruction_hook(&mut self, hook: impl FnMut(&cpu::TraceEntry) + 'static) {
+        self.instruction_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_instruction_hook(&mut self) {
+        self.instruction_hook = None;
+    }
+
+    fn execute(&mut self) {
+        match self.instruction_hook.as_mut() {
+            Some(hook) => hook(&self.cpu.trace_step()),
+            None => {
+                self.cpu.step();
+            }
+        }
+    }
+
+    // Frame length in whole CPU cycles, from the cartridge's region.
+    pub fn cycles_per_frame(&self) -> usize {
+        self.timing.cycles_per_frame()
+    }
+
+    // Writes through the bus, so bytes land in RAM or PRG RAM and are dropped
+    // by ROM, exactly as CPU stores would be.
+    pub fn write_ram(&mut self, address: u16, bytes: &[u8]) {
+        for (offset, byte) in bytes.iter().enumerate() {
+            self.cpu.bus_mut().write(address.wrapping_add(offset as u16), *byte);
+        }
+    }
+
+    // Reads without side effects, for memory viewers and tests.
+    pub fn dump_memory(&self, range: RangeInclusive<u16>) -> Vec<u8> {
+        range.map(|address| self.cpu.bus().peek(address)).collect()
+    }
+
+    // Executes one instruction, returning its trace entry and disassembly as
+    // they were before it ran, which is what a debugger's step shows.
+    pub fn step_disasm(&mut self) -> (cpu::TraceEntry, String) {
+        let disassembly = self.cpu.disassemble(self.cpu.pc());
+        (self.cpu.trace_step(), disassembly)
+    }
+
+    // Registers and the disassembly around PC, marked with '>', for printing
+    // when emulation fails.
+    pub fn diagnostics(&self) -> String {
+        let state = self.cpu.save_state();
+        let mut out = format!(
+            "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}\n",
+            state.a, state.x, state.y, state.p, state.sp, state.total_cycles
+        );
+        for (address, disassembly) in self.cpu.disassembly_window(state.pc, DIAGNOSTIC_WINDOW, DIAGNOSTIC_WINDOW) {
+            let marker = if address == state.pc { '>' } else { ' ' };
+            out += &format!("{marker} {address:04X}  {disassembly}\n");
+        }
+        out
+    }
+
+    // Runs until an enabled breakpoint is reached or the CPU halts. Like the
+    // bounded runs, the instruction at the starting PC always executes.
+    pub fn run(&mut self) -> RunOutcome {
         loop {
-            self.cpu.step();
+            if self.cpu.is_halted() {
+                return RunOutcome::Halted;
+            }
+            self.execute();
+            let pc = self.cpu.pc();
+            if self.is_breakpoint(pc) {
+                return RunOutcome::Breakpoint(pc);
+            }
         }
     }
 
-    pub fn run_trace(&mut self) {
+    pub fn run_trace(&mut self, options: &trace::TraceOptions) -> io::Result<()> {
+        self.run_trace_to(&mut io::stdout().lock(), options)
+    }
+
+    pub fn run_trace_to<W: Write>(&mut self, out: &mut W, options: &trace::TraceOptions) -> io::Result<()> {
+        let mut prev: Option<cpu::TraceEntry> = None;
+        let mut count: usize = 0;
+        let mut is_armed = options.arm_pc.is_none();
+        let mut traced: usize = 0;
+        while options.limit.is_none_or(|limit| count < limit) {
+            is_armed |= options.arm_pc == Some(self.cpu.pc());
+            if !is_armed {
+                self.cpu.step();
+                count += 1;
+                continue;
+            }
+            if options.armed_limit.is_some_and(|limit| traced >= limit) {
+                break;
+            }
+            traced += 1;
+            // the listing has to be taken before the instruction runs
+            let listing = (options.format == trace::TraceFormat::Nestest).then(|| self.listing());
+            let entry = self.cpu.trace_step();
+            if options.filter.matches(&entry) {
+                let line = match (options.format, &listing) {
+                    (trace::TraceFormat::Json, _) => trace::format_trace_json(&entry),
+                    (trace::TraceFormat::Nestest, Some((bytes, disassembly))) => {
+                        trace::format_trace_nestest(&entry, bytes, disassembly)
+                    }
+                    _ => match options.cycle_column {
+                        trace::CycleColumn::Cpu => trace::format_trace(&entry, prev.as_ref(), options.color),
+                        trace::CycleColumn::PpuDots => {
+                            trace::format_trace_dots(&entry, prev.as_ref(), options.color, &self.timing)
+                        }
+                    },
+                };
+                writeln!(out, "{line}")?;
+            }
+            prev = Some(entry);
+            count += 1;
+        }
+        Ok(())
+    }
+
+    // Bytes and disassembly of the instruction at PC.
+    fn listing(&self) -> (Vec<u8>, String) {
+        let pc = self.cpu.pc();
+        let opcode = self.cpu.bus().peek(pc);
+        let len = cpu::Cpu::<B>::instruction_table()[opcode as usize].bytes as u16;
+        let bytes = (0..len.max(1)).map(|offset| self.cpu.bus().peek(pc.wrapping_add(offset))).collect();
+        (bytes, self.cpu.disassemble(pc))
+    }
+
+    // Runs until PC reaches the given address or max_cycles are spent.
+    pub fn run_until(&mut self, pc: u16, max_cycles: usize) -> RunOutcome {
+        self.run_bounded(Some(pc), None, max_cycles)
+    }
+
+    // Runs until the byte at address equals value, the way test ROMs report
+    // their result (blargg's write it to $6000), or max_cycles are spent.
+    pub fn run_until_mem(&mut self, address: u16, value: u8, max_cycles: usize) -> RunOutcome {
+        self.run_bounded(None, Some((address, value)), max_cycles)
+    }
+
+    pub fn run_for_cycles(&mut self, cycles: usize) -> RunOutcome {
+        self.run_bounded(None, None, cycles)
+    }
+
+    // The instruction at the starting PC always executes, so a run can be
+    // resumed from the address it previously stopped at.
+    fn run_bounded(
+        &mut self,
+        target_pc: Option<u16>,
+        watch: Option<(u16, u8)>,
+        max_cycles: usize,
+    ) -> RunOutcome {
+        let start_cycles = self.cpu.total_cycles();
+        let mut is_first_step = true;
+        // lowest and highest PC and the write count since the loop might have started
+        let mut stall_pcs = (self.cpu.pc(), self.cpu.pc());
+        let mut stall_writes = self.cpu.write_count();
+        let mut stall_length: usize = 0;
         loop {
-            let trace = self.cpu.trace_step();
-            let operand = match trace.operand {
-                Some(op) => format!("{:02X}", op),
-                None => match trace.operand_address {
-                    Some(addr) => format!("{addr:04X}"),
-                    None => "".to_string(),
-                },
+            let pc = self.cpu.pc();
+            if self.cpu.is_halted() {
+                return RunOutcome::Halted;
+            }
+            if !is_first_step && (target_pc == Some(pc) || self.is_breakpoint(pc)) {
+                return RunOutcome::Breakpoint(pc);
+            }
+            if self.cpu.total_cycles() - start_cycles >= max_cycles {
+                return RunOutcome::BudgetExhausted;
+            }
+            // under Nop and Halt the CPU deals with the opcode itself, and
+            // nothing is fetched while an interrupt is taken first
+            let opcode = self.cpu.bus().peek(pc);
+            let is_reported = matches!(
+                self.cpu.illegal_opcode_policy(),
+                cpu::IllegalOpcodePolicy::Panic | cpu::IllegalOpcodePolicy::Error
+            ) && !self.cpu.is_interrupt_due();
+            if is_reported && cpu::Cpu::<B>::is_illegal_opcode(opcode) {
+                return RunOutcome::IllegalOpcode { opcode, pc };
+            }
+            self.execute();
+            if let Some(sp) = self.cpu.take_stack_overflow() {
+                return RunOutcome::StackOverflow { pc, sp };
+            }
+            if let Some(instructions) = self.stall_instructions {
+                let pc = self.cpu.pc();
+                stall_pcs = (stall_pcs.0.min(pc), stall_pcs.1.max(pc));
+                stall_length += 1;
+                if stall_pcs.1 - stall_pcs.0 > STALL_PC_RANGE || self.cpu.write_count() != stall_writes {
+                    stall_pcs = (pc, pc);
+                    stall_writes = self.cpu.write_count();
+                    stall_length = 0;
+                } else if stall_length >= instructions {
+                    return RunOutcome::StalledLoop(pc);
+                }
+            }
+            if let Some((address, value)) = watch {
+                if self.cpu.bus().peek(address) == value {
+                    return RunOutcome::Watchpoint { address, pc: self.cpu.pc() };
+                }
+            }
+            is_first_step = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller;
+    use crate::mapper;
+    use crate::signal;
+
+    fn flat_emulator(program: &[u8]) -> Emulator<bus::FlatMemory> {
+        Emulator::load_flat(program, 0x0600, 0x0600)
+    }
+
+    fn nestest_emulator() -> Emulator {
+        Emulator::new(rom::read("./rom/nestest.nes").unwrap(), Some(0xC000))
+    }
+
+    // NROM cartridge running INX; JMP $C000 forever
+    fn looping_emulator() -> Emulator {
+        Emulator::new(rom::from_bytes(&looping_rom()).unwrap(), None)
+    }
+
+    fn looping_rom() -> Vec<u8> {
+        let mut buffer = b"NES\x1A\x01\x01".to_vec();
+        buffer.resize(16 + 16 * 1024 + 8 * 1024, 0);
+        buffer[16..20].copy_from_slice(&[0xE8, 0x4C, 0x00, 0xC0]);
+        buffer[16 + 0x3FFC..16 + 0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        buffer
+    }
+
+    // The looping cartridge with nothing mapped at $6000-$7FFF.
+    fn no_prg_ram_emulator() -> Emulator {
+        let buffer = looping_rom();
+        let prg_rom = buffer[16..16 + 16 * 1024].to_vec();
+        let mut cartridge = rom::from_bytes(&buffer).unwrap();
+        cartridge.set_mapper(Box::new(mapper::Mapper0::new(
+            prg_rom,
+            mapper::ChrMemory::new(vec![]),
+            0,
+            mapper::Mirroring::Horizontal,
+        )));
+        Emulator::new(cartridge, None)
+    }
+
+    #[test]
+    fn test_trace_pc_range_filter() {
+        // INX; INX; INX; JMP $0600
+        let mut emulator = flat_emulator(&[0xE8, 0xE8, 0xE8, 0x4C, 0x00, 0x06]);
+        let options = trace::TraceOptions {
+            filter: trace::TraceFilter {
+                pc_range: Some(0x0601..=0x0602),
+                ..Default::default()
+            },
+            limit: Some(8),
+            ..Default::default()
+        };
+        let mut out: Vec<u8> = Vec::new();
+        emulator.run_trace_to(&mut out, &options).unwrap();
+        let lines: Vec<String> = String::from_utf8(out).unwrap().lines().map(String::from).collect();
+        assert_eq!(lines.len(), 4);
+        for line in lines {
+            assert!(line.starts_with("0601") || line.starts_with("0602"), "{line}");
+        }
+    }
+
+    #[test]
+    fn test_trace_arm_pc() {
+        // LDX #$00; INX; CPX #$03; BNE $0602; LDY #$07; JMP $0609
+        let program = [0xA2, 0x00, 0xE8, 0xE0, 0x03, 0xD0, 0xFB, 0xA0, 0x07, 0x4C, 0x09, 0x06];
+        let mut emulator = flat_emulator(&program);
+        let options = trace::TraceOptions {
+            arm_pc: Some(0x0607),
+            armed_limit: Some(3),
+            ..Default::default()
+        };
+        let mut out: Vec<u8> = Vec::new();
+        emulator.run_trace_to(&mut out, &options).unwrap();
+        let lines: Vec<String> = String::from_utf8(out).unwrap().lines().map(String::from).collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("0607"), "{}", lines[0]);
+        assert!(lines[0].contains("X=03"), "{}", lines[0]);
+        assert!(lines[1].starts_with("0609"), "{}", lines[1]);
+        assert!(lines[2].starts_with("0609"), "{}", lines[2]);
+    }
+
+    #[test]
+    fn test_run_trace_nestest_format() {
+        let golden = std::fs::read_to_string("./rom/nestest_official.trace").unwrap();
+        let options = trace::TraceOptions {
+            format: trace::TraceFormat::Nestest,
+            limit: Some(golden.lines().count()),
+            ..Default::default()
+        };
+        let mut out: Vec<u8> = Vec::new();
+        nestest_emulator().run_trace_to(&mut out, &options).unwrap();
+        let ours = String::from_utf8(out).unwrap();
+        for (index, (ours, golden)) in ours.lines().zip(golden.lines()).enumerate() {
+            assert_eq!(ours, golden.trim_end(), "line {}", index + 1);
+        }
+        assert_eq!(ours.lines().count(), golden.lines().count());
+    }
+
+    #[test]
+    fn test_run_trace_cycle_column() {
+        let trace_first = |cycle_column: trace::CycleColumn| {
+            let options = trace::TraceOptions {
+                limit: Some(1),
+                cycle_column,
+                ..Default::default()
             };
-            println!(
-                "{pc:04X} {opcode:02X} {mnemonic:>4} {operand:<8} \
-                      A={a:02X} X={x:02X} Y={y:02X} P={p:02X} SP={sp:02X} \
-                      CYC={cycles}",
-                pc = trace.pc,
-                opcode = trace.opcode,
-                mnemonic = trace.mnemonic,
-                operand = operand,
-                a = trace.a,
-                x = trace.x,
-                y = trace.y,
-                p = trace.p,
-                sp = trace.sp,
-                cycles = trace.cycles
-            );
+            let mut out: Vec<u8> = Vec::new();
+            nestest_emulator().run_trace_to(&mut out, &options).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let cpu = trace_first(trace::CycleColumn::Cpu);
+        let dots = trace_first(trace::CycleColumn::PpuDots);
+        assert!(cpu.starts_with("C000 4C  JMP") && cpu.trim_end().ends_with(" CYC=7"), "{cpu}");
+        assert!(dots.trim_end().ends_with(" DOT=21"), "{dots}");
+        assert_eq!(cpu.rsplit_once(' ').unwrap().0, dots.rsplit_once(' ').unwrap().0);
+    }
+
+    #[test]
+    fn test_step_scanline() {
+        let mut emulator = looping_emulator();
+        let start_cycles = emulator.cpu.total_cycles();
+        assert_eq!(emulator.step_scanline(), RunOutcome::BudgetExhausted);
+        assert_eq!(emulator.ppu().scanline(), 1);
+        for _ in 1..timing::NTSC.scanlines_per_frame {
+            emulator.step_scanline();
+        }
+        assert_eq!(emulator.ppu().frame(), 1);
+        assert_eq!(emulator.ppu().scanline(), 0);
+        let cycles = emulator.cpu.total_cycles() - start_cycles;
+        // the last instruction may run past the frame boundary
+        assert!((NTSC_CYCLES_PER_FRAME..NTSC_CYCLES_PER_FRAME + 7).contains(&cycles), "{cycles}");
+    }
+
+    #[test]
+    fn test_step_scanline_pal() {
+        let mut buffer = looping_rom();
+        // flags 9: PAL
+        buffer[9] = 0x01;
+        let mut pal = Emulator::new(rom::from_bytes(&buffer).unwrap(), None);
+        let mut ntsc = looping_emulator();
+        let run_frame = |emulator: &mut Emulator, scanlines: u32| {
+            let start_cycles = emulator.cpu.total_cycles();
+            for _ in 0..scanlines {
+                emulator.step_scanline();
+            }
+            assert_eq!(emulator.ppu().frame(), 1);
+            emulator.cpu.total_cycles() - start_cycles
+        };
+        // 3.2 dots per cycle make a PAL scanline shorter in CPU cycles, but
+        // the extra 50 scanlines make the frame longer
+        let pal_cycles = run_frame(&mut pal, timing::PAL.scanlines_per_frame);
+        let ntsc_cycles = run_frame(&mut ntsc, timing::NTSC.scanlines_per_frame);
+        assert!((PAL_CYCLES_PER_FRAME..PAL_CYCLES_PER_FRAME + 7).contains(&pal_cycles), "{pal_cycles}");
+        assert!(pal_cycles > ntsc_cycles);
+    }
+
+    #[test]
+    fn test_step_clocks_paused() {
+        let mut emulator = looping_emulator();
+        emulator.set_step_clocks_paused(true);
+        for _ in 0..1000 {
+            emulator.step();
+        }
+        assert_eq!(emulator.ppu().scanline(), 0);
+        assert_eq!(emulator.ppu().frame(), 0);
+        emulator.set_step_clocks_paused(false);
+        for _ in 0..1000 {
+            emulator.step();
+        }
+        assert!(emulator.ppu().scanline() > 0);
+        // runs are never paused
+        emulator.set_step_clocks_paused(true);
+        emulator.run_frames(1);
+        assert_eq!(emulator.ppu().frame(), 1);
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut emulator = nestest_emulator();
+        emulator.set_rewind_capacity(2);
+        assert!(!emulator.step_back());
+        let mut states = vec![emulator.save_state()];
+        for _ in 0..3 {
+            emulator.step();
+            states.push(emulator.save_state());
+        }
+        assert_ne!(states[1], states[3]);
+        assert!(emulator.step_back());
+        assert_eq!(emulator.save_state(), states[2]);
+        assert!(emulator.step_back());
+        assert_eq!(emulator.save_state(), states[1]);
+        assert!(!emulator.step_back());
+    }
+
+    #[test]
+    fn test_load_state_restores_ram() {
+        let mut emulator = nestest_emulator();
+        let state = emulator.save_state();
+        emulator.cpu.bus_mut().write(0x0010, 0x5A);
+        emulator.step();
+        emulator.load_state(&state).unwrap();
+        assert_eq!(emulator.cpu.bus().peek(0x0010), 0x00);
+        assert_eq!(emulator.save_state(), state);
+    }
+
+    #[test]
+    fn test_state_diff() {
+        let mut emulator = nestest_emulator();
+        let before = emulator.save_state();
+        emulator.cpu.bus_mut().write(0x0123, 0x5A);
+        let after = emulator.save_state();
+        assert_eq!(before.diff(&after), vec![(0x0123, 0x00, 0x5A)]);
+        assert!(before.register_diff(&after).is_empty());
+        emulator.step();
+        let stepped = emulator.save_state();
+        assert!(after.register_diff(&stepped).iter().any(|&(name, _, _)| name == "PC"));
+    }
+
+    #[test]
+    fn test_state_bytes_round_trip() {
+        let mut emulator = nestest_emulator();
+        emulator.cpu.bus_mut().write(0x0123, 0x5A);
+        emulator.run_for_cycles(1000);
+        let state = emulator.save_state();
+        let bytes = state.to_bytes();
+        assert_eq!(&bytes[..5], b"MYNS\x02");
+        assert_eq!(EmulatorState::from_bytes(&bytes), Ok(state.clone()));
+
+        let mut fresh = nestest_emulator();
+        fresh.load_state(&EmulatorState::from_bytes(&bytes).unwrap()).unwrap();
+        assert_eq!(fresh.save_state(), state);
+    }
+
+    // UxROM cartridge with four PRG banks, each starting with its number, that
+    // switches to bank 2, enables NMI and then counts in X
+    fn uxrom_emulator() -> Emulator {
+        let mut buffer = b"NES\x1A\x04\x00\x20".to_vec();
+        buffer.resize(16 + 4 * 16 * 1024, 0);
+        for bank in 0..4 {
+            buffer[16 + bank * 16 * 1024] = bank as u8;
+        }
+        // LDA #$02; STA $8000; LDA #$80; STA $2000; INX; JMP $C00A
+        let program = [0xA9, 0x02, 0x8D, 0x00, 0x80, 0xA9, 0x80, 0x8D, 0x00, 0x20, 0xE8, 0x4C, 0x0A, 0xC0];
+        let last_bank = 16 + 3 * 16 * 1024;
+        buffer[last_bank..last_bank + program.len()].copy_from_slice(&program);
+        buffer[last_bank + 0x3FFC..last_bank + 0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        Emulator::new(rom::from_bytes(&buffer).unwrap(), None)
+    }
+
+    #[test]
+    fn test_state_bytes_restore_banks() {
+        let mut emulator = uxrom_emulator();
+        emulator.run_for_cycles(1000);
+        let state = emulator.save_state();
+        assert_eq!(emulator.cpu.bus().peek(0x8000), 2);
+
+        let mut fresh = uxrom_emulator();
+        assert_eq!(fresh.cpu.bus().peek(0x8000), 0);
+        fresh.load_state(&EmulatorState::from_bytes(&state.to_bytes()).unwrap()).unwrap();
+        assert_eq!(fresh.cpu.bus().peek(0x8000), 2);
+        assert_eq!(fresh.cpu.bus().cartridge().bank_mapping().prg, [4, 5, 6, 7]);
+        assert_eq!(fresh.save_state(), state);
+        fresh.run_for_cycles(100_000);
+        emulator.run_for_cycles(100_000);
+        assert_eq!(fresh.save_state(), emulator.save_state());
+    }
+
+    #[test]
+    fn test_load_state_corrupt_devices() {
+        let mut emulator = uxrom_emulator();
+        let mut state = emulator.save_state();
+        emulator.run_for_cycles(1000);
+        let before = emulator.save_state();
+        state.devices.pop();
+        assert_eq!(emulator.load_state(&state), Err(LoadStateError::Corrupt));
+        assert_eq!(emulator.save_state(), before);
+    }
+
+    #[test]
+    fn test_state_bytes_rejected() {
+        let mut bytes = nestest_emulator().save_state().to_bytes();
+        assert_eq!(
+            EmulatorState::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(StateFormatError::Truncated)
+        );
+        bytes[4] = STATE_VERSION + 1;
+        let err = EmulatorState::from_bytes(&bytes).unwrap_err();
+        assert_eq!(err, StateFormatError::UnsupportedVersion(3));
+        assert_eq!(err.to_string(), "save state version 3 is not supported, expected 2");
+        bytes[0] = b'X';
+        assert_eq!(EmulatorState::from_bytes(&bytes), Err(StateFormatError::BadMagic));
+    }
+
+    #[test]
+    fn test_state_bytes_ram_length() {
+        let bytes = nestest_emulator().save_state().to_bytes();
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(EmulatorState::from_bytes(&trailing), Err(StateFormatError::Corrupt));
+        // a well-formed state holding 1KB of RAM
+        let ram_len_at = 5 + 4 + cpu::CpuState::ENCODED_SIZE;
+        let mut short = bytes[..ram_len_at].to_vec();
+        short.extend_from_slice(&0x400u16.to_le_bytes());
+        short.extend_from_slice(&bytes[ram_len_at + 2..ram_len_at + 2 + 0x400]);
+        short.extend_from_slice(&bytes[ram_len_at + 2 + bus::RAM_SIZE..]);
+        assert_eq!(EmulatorState::from_bytes(&short), Err(StateFormatError::Corrupt));
+    }
+
+    #[test]
+    fn test_load_state_other_cartridge() {
+        let mut emulator = nestest_emulator();
+        emulator.cpu.bus_mut().write(0x0010, 0x5A);
+        let state = looping_emulator().save_state();
+        assert_ne!(state.cartridge_crc32, emulator.save_state().cartridge_crc32);
+        assert_eq!(
+            emulator.load_state(&state),
+            Err(LoadStateError::WrongCartridge(StateMismatchError {
+                state_crc32: state.cartridge_crc32,
+                cartridge_crc32: emulator.save_state().cartridge_crc32,
+            }))
+        );
+        assert_eq!(emulator.cpu.bus().peek(0x0010), 0x5A);
+    }
+
+    #[test]
+    fn test_frame_hash_deterministic() {
+        let run = || {
+            let cartridge = rom::read("./rom/nestest.nes").unwrap();
+            let mut emulator = Emulator::new(cartridge, Some(0xC000));
+            emulator.run_for_cycles(10_000);
+            emulator.ppu().frame_hash()
+        };
+        assert_eq!(run(), run());
+    }
+
+    // Builds two emulators with build and runs them side by side a frame at
+    // a time, failing at the first frame after which their states or
+    // pictures differ. build has to seed everything the same way each call.
+    fn assert_deterministic(build: impl Fn() -> Emulator, frames: usize) {
+        let (mut first, mut second) = (build(), build());
+        for frame in 1..=frames {
+            assert_eq!(first.run_frames(1), second.run_frames(1), "outcome of frame {frame}");
+            assert_eq!(first.save_state(), second.save_state(), "state after frame {frame}");
+            assert_eq!(first.ppu().frame_hash(), second.ppu().frame_hash(), "picture after frame {frame}");
+        }
+    }
+
+    #[test]
+    fn test_nestest_deterministic() {
+        assert_deterministic(
+            || {
+                Emulator::builder(rom::read("./rom/nestest.nes").unwrap())
+                    .ram_pattern(bus::RamPattern::Alternating)
+                    .illegal_opcode_policy(cpu::IllegalOpcodePolicy::Halt)
+                    .start_pc(0xC000)
+                    .build()
+            },
+            3,
+        );
+    }
+
+    #[test]
+    fn test_dmc_dma_stall() {
+        let mut emulator = nestest_emulator();
+        let bus = emulator.cpu.bus_mut();
+        // one byte sample at $C000, not looping
+        bus.write(0x4012, 0x00);
+        bus.write(0x4013, 0x00);
+        bus.write(0x4015, 0x10);
+        assert_eq!(bus.peek(0x4015) & 0x10, 0x10);
+        let start_cycles = emulator.cpu.total_cycles();
+        // JMP $C5F5
+        assert_eq!(emulator.step(), 3 + 4);
+        assert_eq!(emulator.cpu.total_cycles() - start_cycles, 7);
+        assert_eq!(emulator.cpu.bus().peek(0x4015) & 0x10, 0x00);
+        assert_eq!(emulator.step(), 2);
+    }
+
+    #[test]
+    fn test_fast_forward_keeps_timing() {
+        let mut emulator = looping_emulator();
+        let mut fast_emulator = looping_emulator();
+        // backdrop colour $16
+        for bus in [emulator.cpu.bus_mut(), fast_emulator.cpu.bus_mut()] {
+            bus.write(0x2006, 0x3F);
+            bus.write(0x2006, 0x00);
+            bus.write(0x2007, 0x16);
+        }
+        assert_eq!(emulator.run_frames(3), RunOutcome::BudgetExhausted);
+        assert_eq!(fast_emulator.fast_forward(3), RunOutcome::BudgetExhausted);
+        assert!(emulator.cpu.total_cycles() >= 3 * NTSC_CYCLES_PER_FRAME);
+        assert_eq!(fast_emulator.cpu.total_cycles(), emulator.cpu.total_cycles());
+        assert_eq!(fast_emulator.save_state(), emulator.save_state());
+        // the last frame is drawn
+        assert_eq!(fast_emulator.ppu().frame_hash(), emulator.ppu().frame_hash());
+        assert!(fast_emulator.ppu().framebuffer().iter().all(|&pixel| pixel == 0x16));
+        assert!(fast_emulator.ppu().is_render_enabled());
+        assert!(fast_emulator.apu_mut().is_output_enabled());
+    }
+
+    #[test]
+    fn test_run_frames_follows_ppu() {
+        let mut emulator = looping_emulator();
+        for frame in 1..=10 {
+            emulator.run_frames(1);
+            assert_eq!(emulator.ppu().frame(), frame);
+            assert_eq!(emulator.ppu().scanline(), 0);
+        }
+        // ten NTSC frames are 893420 dots, a little over 297806 CPU cycles
+        let cycles = emulator.cpu.total_cycles() - 7;
+        assert!((297_807..297_807 + 7).contains(&cycles));
+    }
+
+    // Adds the A button of controller 1 to $10 on every poll.
+    fn input_emulator() -> Emulator {
+        let mut buffer = looping_rom();
+        let program = [
+            0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40, 0xAD, 0x16, 0x40, 0x29, 0x01,
+            0x18, 0x65, 0x10, 0x85, 0x10, 0x4C, 0x00, 0xC0,
+        ];
+        buffer[16..16 + program.len()].copy_from_slice(&program);
+        Emulator::builder(rom::from_bytes(&buffer).unwrap())
+            .ram_pattern(bus::RamPattern::Alternating)
+            .build()
+    }
+
+    #[test]
+    fn test_input_replay() {
+        let mut emulator = input_emulator();
+        emulator.start_recording();
+        for frame in 0..6 {
+            let mask = if frame % 3 == 1 { 1 << controller::Button::A as u8 } else { 0 };
+            emulator.set_controller_state(controller::Port::One, mask);
+            emulator.run_frame();
+        }
+        let log = emulator.stop_recording();
+        assert_eq!(log.frames.len(), 6);
+        assert_eq!(log.frames[1], [0x01, 0x00]);
+
+        let mut replay = input_emulator();
+        replay.play(log);
+        for _ in 0..6 {
+            replay.run_frame();
+        }
+        assert!(replay.is_playing());
+        replay.run_frame();
+        assert!(!replay.is_playing());
+        emulator.run_frame();
+        assert_ne!(emulator.cpu.bus().peek(0x0010), 0x00);
+        assert_eq!(replay.save_state(), emulator.save_state());
+        assert_eq!(replay.ppu().frame_hash(), emulator.ppu().frame_hash());
+
+        // without the input the run diverges
+        let mut idle = input_emulator();
+        for _ in 0..7 {
+            idle.run_frame();
+        }
+        assert_ne!(idle.save_state(), emulator.save_state());
+    }
+
+    #[test]
+    fn test_cycles_per_frame_from_region() {
+        let mut buffer = looping_rom();
+        assert_eq!(looping_emulator().cycles_per_frame(), NTSC_CYCLES_PER_FRAME);
+        // flags 9: PAL
+        buffer[9] = 0x01;
+        let mut emulator = Emulator::new(rom::from_bytes(&buffer).unwrap(), None);
+        assert_eq!(emulator.cycles_per_frame(), PAL_CYCLES_PER_FRAME);
+        let start_cycles = emulator.cpu.total_cycles();
+        emulator.run_frames(1);
+        assert!(emulator.cpu.total_cycles() - start_cycles >= PAL_CYCLES_PER_FRAME);
+        assert_eq!(flat_emulator(&[0xEA]).cycles_per_frame(), NTSC_CYCLES_PER_FRAME);
+    }
+
+    #[test]
+    fn test_write_ram() {
+        let mut emulator = nestest_emulator();
+        let pattern: Vec<u8> = (0..=0xFF).collect();
+        emulator.write_ram(0x0000, &pattern);
+        assert_eq!(emulator.dump_memory(0x0000..=0x00FF), pattern);
+        // RAM mirror
+        assert_eq!(emulator.dump_memory(0x0800..=0x0803), [0, 1, 2, 3]);
+        emulator.write_ram(0x6000, &[0xAB]);
+        assert_eq!(emulator.dump_memory(0x6000..=0x6000), [0xAB]);
+        let rom = emulator.dump_memory(0xC000..=0xC001);
+        emulator.write_ram(0xC000, &[0x00, 0x00]);
+        assert_eq!(emulator.dump_memory(0xC000..=0xC001), rom);
+    }
+
+    #[test]
+    fn test_dump_open_bus() {
+        let mut emulator = no_prg_ram_emulator();
+        emulator.write_ram(0x6000, &[0xAB]);
+        assert_eq!(emulator.dump_memory(0x5000..=0x5001), [0, 0]);
+        assert_eq!(emulator.dump_memory(0x6000..=0x6001), [0, 0]);
+    }
+
+    #[test]
+    fn test_builder() {
+        let cartridge = rom::from_bytes(&looping_rom()).unwrap();
+        // $03 is undefined
+        let mut emulator = Emulator::builder(cartridge)
+            .ram_pattern(bus::RamPattern::Fill(0x03))
+            .illegal_opcode_policy(cpu::IllegalOpcodePolicy::Nop)
+            .region(rom::TvSystem::PAL)
+            .start_pc(0x0000)
+            .build();
+        assert_eq!(emulator.dump_memory(0x0000..=0x07FF), vec![0x03; 0x800]);
+        assert_eq!(emulator.step(), 2);
+        assert_eq!(emulator.cpu.pc(), 0x0001);
+        assert_eq!(emulator.cycles_per_frame(), PAL_CYCLES_PER_FRAME);
+    }
+
+    #[test]
+    fn test_alternating_ram_pattern() {
+        let cartridge = rom::from_bytes(&looping_rom()).unwrap();
+        let emulator = Emulator::builder(cartridge)
+            .ram_pattern(bus::RamPattern::Alternating)
+            .build();
+        assert_eq!(emulator.dump_memory(0x0000..=0x0009), [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0]);
+    }
+
+    #[test]
+    fn test_controller_state_round_trip() {
+        let mut emulator = nestest_emulator();
+        let mask = 0b1010_0110;
+        emulator.set_controller_state(controller::Port::Two, mask);
+        let bus = emulator.cpu.bus_mut();
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+        let read = (0..8).fold(0, |buttons, bit| buttons | (bus.read(0x4017) & 1) << bit);
+        assert_eq!(read, mask);
+        assert_eq!(bus.read(0x4017), 1);
+        assert_eq!(bus.read(0x4016), 0);
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn test_save_screenshot() {
+        let emulator = nestest_emulator();
+        let path = std::env::temp_dir().join(format!("mayones-screenshot-{}.png", std::process::id()));
+        emulator.save_screenshot(&path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&data[12..16], b"IHDR");
+        assert_eq!(u32::from_be_bytes(data[16..20].try_into().unwrap()), ppu::SCREEN_WIDTH as u32);
+        assert_eq!(u32::from_be_bytes(data[20..24].try_into().unwrap()), ppu::SCREEN_HEIGHT as u32);
+    }
+
+    #[test]
+    fn test_load_flat() {
+        // data; LDX #$03; loop: DEX; STX $10; BNE loop; JAM
+        let program = [0xFF, 0xA2, 0x03, 0xCA, 0x86, 0x10, 0xD0, 0xFB, 0x02];
+        let mut emulator = Emulator::load_flat(&program, 0x8000, 0x8001);
+        assert_eq!(emulator.cpu.pc(), 0x8001);
+        assert_eq!(emulator.run_for_cycles(1000), RunOutcome::Halted);
+        assert_eq!(emulator.cpu.pc(), 0x8008);
+        assert_eq!(emulator.cpu.bus().peek(0x0010), 0x00);
+        assert_eq!(emulator.cpu.bus().peek(0x8000), 0xFF);
+    }
+
+    #[test]
+    fn test_instruction_hook() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // INX; INX; JMP $0600
+        let mut emulator = flat_emulator(&[0xE8, 0xE8, 0x4C, 0x00, 0x06]);
+        let pcs = Rc::new(RefCell::new(Vec::new()));
+        let hook_pcs = Rc::clone(&pcs);
+        emulator.set_instruction_hook(move |entry| hook_pcs.borrow_mut().push(entry.pc));
+        emulator.run_for_cycles(2 + 2 + 3 + 2);
+        assert_eq!(*pcs.borrow(), [0x0600, 0x0601, 0x0602, 0x0600]);
+        emulator.clear_instruction_hook();
+        emulator.run_for_cycles(10);
+        assert_eq!(pcs.borrow().len(), 4);
+    }
+
+    #[test]
+    fn test_step_disasm() {
+        // LDX #$05; LDA $0200,X
+        let mut emulator = flat_emulator(&[0xA2, 0x05, 0xBD, 0x00, 0x02]);
+        emulator.write_ram(0x0205, &[0x42]);
+        emulator.step_disasm();
+        let (entry, disassembly) = emulator.step_disasm();
+        assert_eq!(entry.pc, 0x0602);
+        assert_eq!(entry.mnemonic, "LDA");
+        assert_eq!(entry.operand_address, Some(0x0205));
+        assert_eq!(entry.x, 0x05);
+        assert_eq!(disassembly, "LDA $0200,X @ 0205 = 42");
+        assert_eq!(emulator.cpu.pc(), 0x0605);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        // LDX #$05; LDA $0200,X; INX; NOP
+        let mut emulator = flat_emulator(&[0xA2, 0x05, 0xBD, 0x00, 0x02, 0xE8, 0xEA]);
+        emulator.step_disasm();
+        let diagnostics = emulator.diagnostics();
+        let lines: Vec<&str> = diagnostics.lines().collect();
+        assert!(lines[0].starts_with("A:00 X:05 Y:00"));
+        assert!(lines.contains(&"  0600  LDX #$05"));
+        assert!(lines.contains(&"> 0602  LDA $0200,X @ 0205 = 00"));
+        assert!(lines.contains(&"  0605  INX"));
+        assert!(lines.contains(&"  0606  NOP"));
+    }
+
+    // Mapper 0 that raises IRQ once it has seen a given number of CPU cycles.
+    #[derive(Debug)]
+    struct IrqAfterCycles {
+        inner: mapper::Mapper0,
+        cycles_left: usize,
+    }
+
+    impl mapper::Mapper for IrqAfterCycles {
+        fn read(&self, address: u16) -> u8 {
+            self.inner.read(address)
+        }
+
+        fn write(&mut self, address: u16, data: u8) {
+            self.inner.write(address, data)
+        }
+
+        fn mirroring(&self) -> mapper::Mirroring {
+            self.inner.mirroring()
+        }
+
+        fn prg_rom(&self) -> &[u8] {
+            self.inner.prg_rom()
         }
+
+        fn chr(&self) -> &mapper::ChrMemory {
+            self.inner.chr()
+        }
+
+        fn clock(&mut self, irq: &mut signal::IrqLine) {
+            self.cycles_left = self.cycles_left.saturating_sub(1);
+            irq.set(signal::IrqSource::Mapper, self.cycles_left == 0);
+        }
+
+        fn save_state(&self, bytes: &mut Vec<u8>) {
+            self.inner.save_state(bytes);
+        }
+
+        fn load_state(&mut self, state: &mut StateReader) -> Option<()> {
+            self.inner.load_state(state)
+        }
+    }
+
+    #[test]
+    fn test_mapper_irq_reaches_cpu() {
+        let mut buffer = looping_rom();
+        // CLI; INX; JMP $C001, with the IRQ handler at $C100
+        buffer[16..21].copy_from_slice(&[0x58, 0xE8, 0x4C, 0x01, 0xC0]);
+        buffer[16 + 0x3FFE..16 + 0x4000].copy_from_slice(&[0x00, 0xC1]);
+        let prg_rom = buffer[16..16 + 16 * 1024].to_vec();
+        let mut cartridge = rom::from_bytes(&buffer).unwrap();
+        cartridge.set_mapper(Box::new(IrqAfterCycles {
+            inner: mapper::Mapper0::new(prg_rom, mapper::ChrMemory::new(vec![]), 0, mapper::Mirroring::Horizontal),
+            cycles_left: 100,
+        }));
+        let mut emulator = Emulator::new(cartridge, None);
+        assert_eq!(emulator.run_until(0xC100, 90), RunOutcome::BudgetExhausted);
+        assert!(matches!(emulator.run_until(0xC100, 100), RunOutcome::Breakpoint(0xC100)));
+        assert!(emulator.cpu.bus().irq());
+        assert!(emulator.cpu.pending_interrupts().irq);
+    }
+
+    #[test]
+    fn test_run_until() {
+        // INX; INX; INX; JMP $0600
+        let mut emulator = flat_emulator(&[0xE8, 0xE8, 0xE8, 0x4C, 0x00, 0x06]);
+        assert_eq!(emulator.run_until(0x0602, 1000), RunOutcome::Breakpoint(0x0602));
+        assert_eq!(emulator.run_until(0x0602, 1000), RunOutcome::Breakpoint(0x0602));
+        assert_eq!(emulator.cpu.total_cycles(), 7 + 2 * 2 + 2 + 3 + 2 * 2);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        // INX; INX; INX; JMP $0600
+        let mut emulator = flat_emulator(&[0xE8, 0xE8, 0xE8, 0x4C, 0x00, 0x06]);
+        emulator.add_breakpoint(0x0603);
+        emulator.add_breakpoint(0x0601);
+        assert_eq!(emulator.run_for_cycles(1000), RunOutcome::Breakpoint(0x0601));
+        assert_eq!(emulator.run(), RunOutcome::Breakpoint(0x0603));
+        emulator.enable_breakpoint(0x0601, false);
+        assert_eq!(emulator.run_for_cycles(1000), RunOutcome::Breakpoint(0x0603));
+        emulator.enable_breakpoint(0x0601, true);
+        emulator.remove_breakpoint(0x0603);
+        assert_eq!(emulator.run(), RunOutcome::Breakpoint(0x0601));
+        emulator.clear_breakpoints();
+        assert_eq!(emulator.run_for_cycles(100), RunOutcome::BudgetExhausted);
+    }
+
+    #[test]
+    fn test_run_stops_when_halted() {
+        // INX; JAM
+        let mut emulator = flat_emulator(&[0xE8, 0x02]);
+        emulator.add_breakpoint(0x0700);
+        assert_eq!(emulator.run(), RunOutcome::Halted);
+        assert_eq!(emulator.cpu.save_state().x, 1);
+        assert_eq!(emulator.run(), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn test_run_until_mem() {
+        // LDA #$80; STA $6000; INX; INX; LDA #$42; STA $6000; JMP $060C
+        let program = [
+            0xA9, 0x80, 0x8D, 0x00, 0x60, 0xE8, 0xE8, 0xA9, 0x42, 0x8D, 0x00, 0x60, 0x4C, 0x0C, 0x06,
+        ];
+        let mut emulator = flat_emulator(&program);
+        assert_eq!(
+            emulator.run_until_mem(0x6000, 0x80, 1000),
+            RunOutcome::Watchpoint { address: 0x6000, pc: 0x0605 }
+        );
+        assert_eq!(
+            emulator.run_until_mem(0x6000, 0x42, 1000),
+            RunOutcome::Watchpoint { address: 0x6000, pc: 0x060C }
+        );
+        assert_eq!(emulator.cpu.save_state().x, 0x02);
+        assert_eq!(emulator.run_until_mem(0x6000, 0x00, 100), RunOutcome::BudgetExhausted);
+    }
+
+    #[test]
+    fn test_run_until_mem_open_bus() {
+        let mut emulator = no_prg_ram_emulator();
+        assert_eq!(emulator.run_until_mem(0x6000, 0x42, 1000), RunOutcome::BudgetExhausted);
+        assert!(matches!(
+            emulator.run_until_mem(0x5000, 0x00, 1000),
+            RunOutcome::Watchpoint { address: 0x5000, .. }
+        ));
+    }
+
+    #[test]
+    fn test_stalled_loop() {
+        // LDX #$00; STX $10; INX; BNE $0602; JMP $0607
+        let program = [0xA2, 0x00, 0x86, 0x10, 0xE8, 0xD0, 0xFB, 0x4C, 0x07, 0x06];
+        let mut emulator = flat_emulator(&program);
+        emulator.set_stall_detection(Some(100));
+        // the counting loop writes memory on every pass
+        assert_eq!(emulator.run_for_cycles(100_000), RunOutcome::StalledLoop(0x0607));
+        assert!(emulator.cpu.total_cycles() > 256 * 8);
+
+        let mut emulator = flat_emulator(&program);
+        assert_eq!(emulator.run_for_cycles(100_000), RunOutcome::BudgetExhausted);
+    }
+
+    #[test]
+    fn test_run_until_halted() {
+        // INX; JAM
+        let mut emulator = flat_emulator(&[0xE8, 0x02]);
+        assert_eq!(emulator.run_until(0x0700, 1000), RunOutcome::Halted);
+        assert_eq!(emulator.cpu.pc(), 0x0601);
+    }
+
+    #[test]
+    fn test_run_for_cycles_budget() {
+        // INX; JMP $0600
+        let mut emulator = flat_emulator(&[0xE8, 0x4C, 0x00, 0x06]);
+        assert_eq!(emulator.run_for_cycles(100), RunOutcome::BudgetExhausted);
+        assert_eq!(emulator.cpu.total_cycles(), 7 + 100);
+    }
+
+    #[test]
+    fn test_run_illegal_opcode() {
+        // INX; illegal
+        let mut emulator = flat_emulator(&[0xE8, 0x03]);
+        assert_eq!(
+            emulator.run_for_cycles(100),
+            RunOutcome::IllegalOpcode { opcode: 0x03, pc: 0x0601 }
+        );
+    }
+
+    #[test]
+    fn test_run_illegal_opcode_behind_nmi() {
+        // NOP; illegal, with the NMI handler at $0700
+        let mut emulator = flat_emulator(&[0xEA, 0x03]);
+        emulator.write_ram(0xFFFA, &[0x00, 0x07]);
+        emulator.cpu.set_nmi_line(true);
+        emulator.cpu.step();
+        assert_eq!(emulator.cpu.pc(), 0x0601);
+        assert_eq!(emulator.run_until(0x0700, 100), RunOutcome::Breakpoint(0x0700));
+    }
+
+    // Looping cartridge running illegal; INX; JMP $0001 from RAM.
+    fn illegal_opcode_emulator(policy: cpu::IllegalOpcodePolicy) -> Emulator {
+        let mut emulator = Emulator::builder(rom::from_bytes(&looping_rom()).unwrap())
+            .illegal_opcode_policy(policy)
+            .start_pc(0x0000)
+            .build();
+        emulator.write_ram(0x0000, &[0x03, 0xE8, 0x4C, 0x01, 0x00]);
+        emulator
+    }
+
+    #[test]
+    fn test_run_illegal_opcode_nop_policy() {
+        let mut emulator = illegal_opcode_emulator(cpu::IllegalOpcodePolicy::Nop);
+        assert_eq!(emulator.run_for_cycles(100), RunOutcome::BudgetExhausted);
+        assert!(emulator.cpu.save_state().x > 0);
+    }
+
+    #[test]
+    fn test_run_illegal_opcode_skip_policy() {
+        let mut emulator = illegal_opcode_emulator(cpu::IllegalOpcodePolicy::Skip);
+        let start_cycles = emulator.cpu.total_cycles();
+        // the undefined $03 takes the INX after it as its operand
+        assert_eq!(emulator.run_until(0x0002, 100), RunOutcome::Breakpoint(0x0002));
+        assert_eq!(emulator.cpu.save_state().x, 0);
+        assert_eq!(emulator.cpu.total_cycles() - start_cycles, 2);
+    }
+
+    #[test]
+    fn test_run_illegal_opcode_halt_policy() {
+        let mut emulator = illegal_opcode_emulator(cpu::IllegalOpcodePolicy::Halt);
+        assert_eq!(emulator.run_for_cycles(100), RunOutcome::Halted);
+        assert_eq!(emulator.cpu.pc(), 0x0000);
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // JSR $0600, recursing forever
+        let program = [0x20, 0x00, 0x06];
+        let mut emulator = flat_emulator(&program);
+        assert_eq!(emulator.run_for_cycles(10_000), RunOutcome::BudgetExhausted);
+
+        let mut emulator = flat_emulator(&program);
+        emulator.set_stack_low_water(Some(0x80));
+        assert_eq!(
+            emulator.run_for_cycles(10_000),
+            RunOutcome::StackOverflow { pc: 0x0600, sp: 0x7F }
+        );
+        // the stack still wraps as on hardware
+        emulator.set_stack_low_water(Some(0x00));
+        assert_eq!(
+            emulator.run_for_cycles(10_000),
+            RunOutcome::StackOverflow { pc: 0x0600, sp: 0xFF }
+        );
     }
 }
@@ -0,0 +1,22 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod apu;
+pub mod bus;
+mod checksum;
+pub mod controller;
+pub mod cpu;
+#[cfg(feature = "std")]
+pub mod emulator;
+pub mod mapper;
+pub mod palette;
+#[cfg(feature = "png")]
+pub mod png;
+pub mod ppu;
+pub mod rom;
+pub mod signal;
+pub mod state;
+pub mod timing;
+#[cfg(feature = "std")]
+pub mod trace;
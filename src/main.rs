@@ -1,14 +1,106 @@
-#![allow(warnings)]
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::panic::{self, AssertUnwindSafe};
 
-use std::io::{self, Write};
+use mayones::{emulator, rom, trace};
 
-mod bus;
-mod cpu;
-mod emulator;
-mod mapper;
-mod rom;
+fn parse_hex(value: &str) -> u16 {
+    u16::from_str_radix(value.trim_start_matches('$'), 16).expect("invalid hex address")
+}
+
+// nestest's automation entry, which runs without a PPU
+const NESTEST_START_PC: u16 = 0xC000;
+// lines in rom/nestest_official.trace
+const NESTEST_OFFICIAL_LINES: usize = 5003;
+
+struct Args {
+    trace: trace::TraceOptions,
+    trace_out: Option<String>,
+    start_pc: Option<u16>,
+}
+
+fn parse_args() -> Args {
+    let mut options = trace::TraceOptions {
+        color: io::stdout().is_terminal(),
+        ..Default::default()
+    };
+    let mut trace_out = None;
+    let mut start_pc = None;
+    let mut is_format_set = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--color" => options.color = true,
+            "--trace-format" => {
+                options.format = match args.next().as_deref() {
+                    Some("text") => trace::TraceFormat::Text,
+                    Some("json") => trace::TraceFormat::Json,
+                    Some("nestest") => trace::TraceFormat::Nestest,
+                    _ => panic!("--trace-format expects text, json or nestest"),
+                };
+                is_format_set = true;
+            }
+            "--cycle-column" => {
+                options.cycle_column = match args.next().as_deref() {
+                    Some("cpu") => trace::CycleColumn::Cpu,
+                    Some("dots") => trace::CycleColumn::PpuDots,
+                    _ => panic!("--cycle-column expects cpu or dots"),
+                }
+            }
+            // --trace-out nestest.log: write a nestest format log to a file,
+            // starting at $C000 for as many lines as the golden log unless
+            // --start-pc, --limit or --trace-format say otherwise
+            "--trace-out" => trace_out = Some(args.next().expect("--trace-out expects a file")),
+            "--start-pc" => {
+                start_pc = Some(parse_hex(&args.next().expect("--start-pc expects an address")));
+            }
+            "--limit" => {
+                let limit = args.next().expect("--limit expects a count");
+                options.limit = Some(limit.parse().expect("invalid --limit count"));
+            }
+            // --pc-range C000:C0FF
+            "--pc-range" => {
+                let range = args.next().expect("--pc-range expects START:END");
+                let (start, end) = range.split_once(':').expect("--pc-range expects START:END");
+                options.filter.pc_range = Some(parse_hex(start)..=parse_hex(end));
+            }
+            // --opcode A9 or --opcode LDA
+            "--opcode" => {
+                let opcode = args.next().expect("--opcode expects a value");
+                match u8::from_str_radix(&opcode, 16) {
+                    Ok(value) if opcode.len() == 2 => options.filter.opcodes.push(value),
+                    _ => options.filter.mnemonics.push(opcode),
+                }
+            }
+            // --arm-pc C5F5: trace only from the first time PC reaches C5F5
+            "--arm-pc" => {
+                options.arm_pc = Some(parse_hex(&args.next().expect("--arm-pc expects an address")));
+            }
+            "--armed-limit" => {
+                let limit = args.next().expect("--armed-limit expects a count");
+                options.armed_limit = Some(limit.parse().expect("invalid --armed-limit count"));
+            }
+            _ => panic!("unknown argument: {arg}"),
+        }
+    }
+    if trace_out.is_some() {
+        options.color = false;
+        if !is_format_set {
+            options.format = trace::TraceFormat::Nestest;
+        }
+        start_pc = start_pc.or(Some(NESTEST_START_PC));
+        options.limit = options.limit.or(Some(NESTEST_OFFICIAL_LINES));
+    }
+    Args {
+        trace: options,
+        trace_out,
+        start_pc,
+    }
+}
 
 fn main() {
+    let args = parse_args();
     print!("ROM path: ");
     io::stdout().flush().unwrap();
     let mut rom_path = String::new();
@@ -17,6 +109,20 @@ fn main() {
         Ok(cart) => cart,
         Err(msg) => panic!("{}", msg),
     };
-    let mut emulator = emulator::Emulator::new(cartridge, None);
-    emulator.run_trace();
+    if cartridge.extra_bytes() > 0 {
+        eprintln!("warning: rom has {} extra bytes, truncating", cartridge.extra_bytes());
+    }
+    let mut emulator = emulator::Emulator::new(cartridge, args.start_pc);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| match &args.trace_out {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(path).expect("creating trace file error"));
+            emulator.run_trace_to(&mut out, &args.trace).expect("writing trace error");
+            out.flush().expect("writing trace error");
+        }
+        None => emulator.run_trace(&args.trace).expect("writing trace error"),
+    }));
+    if let Err(payload) = result {
+        eprint!("{}", emulator.diagnostics());
+        panic::resume_unwind(payload);
+    }
 }
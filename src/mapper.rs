@@ -1,20 +1,786 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+
+use crate::signal::IrqLine;
+use crate::state::{self, StateReader};
+
+const PRG_BANK_SIZE: usize = 32 * 1024;
+const PRG_BANK_16K_SIZE: usize = 16 * 1024;
+// the unit of BankMapping::prg
+const PRG_BANK_8K_SIZE: usize = 8 * 1024;
+const CHR_BANK_4K_SIZE: usize = 4 * 1024;
+const CHR_RAM_SIZE: usize = 8 * 1024;
+
+// Mapper numbers from_id knows how to build, in ascending order.
+const SUPPORTED_IDS: [u8; 4] = [0, 1, 2, 7];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    // every nametable address maps to the first or second 1KB of VRAM
+    SingleScreenLower,
+    SingleScreenUpper,
+}
+
+// Pattern table memory. Carts without CHR ROM have 8KB of CHR RAM instead,
+// decided once here so every mapper handles CHR writes the same way.
+#[derive(Debug)]
+pub enum ChrMemory {
+    Rom(Vec<u8>),
+    Ram(Vec<u8>),
+}
+
+impl ChrMemory {
+    pub fn new(chr_rom: Vec<u8>) -> Self {
+        if chr_rom.is_empty() {
+            ChrMemory::Ram(vec![0; CHR_RAM_SIZE])
+        } else {
+            ChrMemory::Rom(chr_rom)
+        }
+    }
+
+    // Bytes of CHR ROM or RAM; never zero.
+    pub fn size(&self) -> usize {
+        match self {
+            ChrMemory::Rom(bytes) | ChrMemory::Ram(bytes) => bytes.len(),
+        }
+    }
+
+    // The CHR ROM data; empty for CHR RAM.
+    pub fn rom(&self) -> &[u8] {
+        match self {
+            ChrMemory::Rom(bytes) => bytes,
+            ChrMemory::Ram(_) => &[],
+        }
+    }
+
+    pub fn read(&self, address: usize) -> u8 {
+        let bytes = match self {
+            ChrMemory::Rom(bytes) | ChrMemory::Ram(bytes) => bytes,
+        };
+        bytes[address % bytes.len()]
+    }
+
+    pub fn write(&mut self, address: usize, data: u8) {
+        if let ChrMemory::Ram(bytes) = self {
+            let len = bytes.len();
+            bytes[address % len] = data;
+        }
+    }
+
+    // CHR RAM contents for save states; nothing for ROM.
+    pub fn save_state(&self, bytes: &mut Vec<u8>) {
+        if let ChrMemory::Ram(ram) = self {
+            state::write_sized(bytes, ram);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Option<()> {
+        match self {
+            ChrMemory::Rom(_) => Some(()),
+            ChrMemory::Ram(ram) => state.sized_into(ram),
+        }
+    }
+}
+
+// Which ROM banks are currently visible, for debuggers: PRG in 8KB units
+// at $8000, $A000, $C000 and $E000, CHR in 1KB units across $0000-$1FFF.
+// Bank numbers count from the start of the PRG or CHR data.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BankMapping {
+    pub prg: [usize; 4],
+    pub chr: [usize; 8],
+}
+
+impl Default for BankMapping {
+    // 32KB of PRG and 8KB of CHR, unswitched
+    fn default() -> Self {
+        Self {
+            prg: [0, 1, 2, 3],
+            chr: [0, 1, 2, 3, 4, 5, 6, 7],
+        }
+    }
+}
+
+pub fn supported_ids() -> &'static [u8] {
+    &SUPPORTED_IDS
+}
+
+pub fn is_supported(id: u8) -> bool {
+    SUPPORTED_IDS.contains(&id)
+}
+
+pub fn from_id(
+    id: u8,
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    prg_ram_size: usize,
+    mirroring: Mirroring,
+) -> Option<Box<dyn Mapper>> {
+    // every mapper mirrors its PRG ROM by taking offsets modulo its size
+    if prg_rom.is_empty() {
+        return None;
+    }
+    match id {
+        0 => Some(Box::new(Mapper0::new(prg_rom, chr, prg_ram_size, mirroring))),
+        1 => Some(Box::new(Mapper1::new(prg_rom, chr, prg_ram_size))),
+        2 => Some(Box::new(Mapper2::new(prg_rom, chr, mirroring))),
+        7 => Some(Box::new(Mapper7::new(prg_rom, chr))),
+        _ => None,
+    }
+}
+
+pub trait Mapper: Debug {
+    fn read(&self, address: u16) -> u8;
+
+    fn write(&mut self, address: u16, data: u8);
+
+    // Current nametable layout; fixed mappers report the header's.
+    fn mirroring(&self) -> Mirroring;
+
+    // False for mappers that switch mirroring through their registers.
+    fn is_mirroring_from_header(&self) -> bool {
+        true
+    }
+
+    // Whether a CPU write to address reaches RAM or a register. Writes that
+    // don't are ignored by the hardware, and usually a bug in the program.
+    fn is_writable(&self, _address: u16) -> bool {
+        true
+    }
+
+    // All of the PRG ROM, regardless of banking.
+    fn prg_rom(&self) -> &[u8];
+
+    fn chr(&self) -> &ChrMemory;
+
+    // Establishes the power-on bank layout, before the CPU fetches the reset vector.
+    fn power_on(&mut self) {}
+
+    // Runs once per CPU cycle. Mappers with IRQ counters assert or release
+    // their input of the IRQ line here.
+    fn clock(&mut self, _irq: &mut IrqLine) {}
+
+    fn bank_mapping(&self) -> BankMapping {
+        BankMapping::default()
+    }
+
+    // Bank registers, PRG RAM and CHR RAM for save states.
+    fn save_state(&self, bytes: &mut Vec<u8>);
+
+    // None if the state doesn't fit this mapper, which may be left partly loaded.
+    fn load_state(&mut self, state: &mut StateReader) -> Option<()>;
+}
+
+// NROM
 #[derive(Debug)]
 pub struct Mapper0 {
     prg_rom: Vec<u8>,
-    chr_rom: Vec<u8>,
+    chr: ChrMemory,
+    prg_ram: Vec<u8>,
+    mirroring: Mirroring,
 }
 
 impl Mapper0 {
-    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
-        Self { prg_rom, chr_rom }
+    pub fn new(prg_rom: Vec<u8>, chr: ChrMemory, prg_ram_size: usize, mirroring: Mirroring) -> Self {
+        Self {
+            prg_rom,
+            chr,
+            prg_ram: vec![0; prg_ram_size],
+            mirroring,
+        }
+    }
+}
+
+impl Mapper for Mapper0 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            // 16KB of PRG ROM is mirrored at $C000
+            0x8000..=0xFFFF => self.prg_rom[(address as usize - 0x8000) % self.prg_rom.len()],
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                self.prg_ram[(address as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x0000..=0x1FFF => self.chr.read(address as usize),
+            // nothing drives the bus below $6000, or at $6000-$7FFF without PRG RAM
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(address as usize - 0x6000) % len] = data;
+            }
+            0x0000..=0x1FFF => self.chr.write(address as usize, data),
+            _ => (),
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn is_writable(&self, address: u16) -> bool {
+        match address {
+            0x6000..=0x7FFF => !self.prg_ram.is_empty(),
+            0x0000..=0x1FFF => true,
+            _ => false,
+        }
+    }
+
+    fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    fn chr(&self) -> &ChrMemory {
+        &self.chr
+    }
+
+    fn bank_mapping(&self) -> BankMapping {
+        let bank_count = (self.prg_rom.len() / PRG_BANK_8K_SIZE).max(1);
+        BankMapping {
+            prg: core::array::from_fn(|window| window % bank_count),
+            ..Default::default()
+        }
+    }
+
+    fn save_state(&self, bytes: &mut Vec<u8>) {
+        state::write_sized(bytes, &self.prg_ram);
+        self.chr.save_state(bytes);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Option<()> {
+        state.sized_into(&mut self.prg_ram)?;
+        self.chr.load_state(state)
+    }
+}
+
+// MMC1 (SxROM): registers are loaded one bit per write through a 5-bit
+// shift register. PRG switches in 16KB or 32KB banks, CHR in 4KB or 8KB
+// banks, and the control register also sets the mirroring.
+#[derive(Debug)]
+pub struct Mapper1 {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    prg_ram: Vec<u8>,
+    shift: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    // bit 4 disables PRG RAM
+    prg_bank: u8,
+}
+
+impl Mapper1 {
+    const RESET_BIT: u8 = 0x80;
+    // PRG mode 3: $8000 switchable, last bank fixed at $C000
+    const POWER_ON_CONTROL: u8 = 0x0C;
+    const CHR_4K_MODE: u8 = 0x10;
+    const PRG_RAM_DISABLE: u8 = 0x10;
+
+    pub fn new(prg_rom: Vec<u8>, chr: ChrMemory, prg_ram_size: usize) -> Self {
+        Self {
+            prg_rom,
+            chr,
+            prg_ram: vec![0; prg_ram_size],
+            shift: 0,
+            shift_count: 0,
+            control: Self::POWER_ON_CONTROL,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_16K_SIZE).max(1)
+    }
+
+    // 16KB banks at $8000 and $C000.
+    fn prg_banks(&self) -> [usize; 2] {
+        let bank = (self.prg_bank & 0x0F) as usize;
+        let banks = match (self.control >> 2) & 0x03 {
+            0 | 1 => [bank & !1, bank | 1],
+            2 => [0, bank],
+            _ => [bank, self.prg_bank_count() - 1],
+        };
+        banks.map(|bank| bank % self.prg_bank_count())
+    }
+
+    // 4KB banks at $0000 and $1000.
+    fn chr_banks(&self) -> [usize; 2] {
+        let bank_count = (self.chr.size() / CHR_BANK_4K_SIZE).max(1);
+        let banks = if self.control & Self::CHR_4K_MODE == 0 {
+            let bank = (self.chr_bank_0 & !1) as usize;
+            [bank, bank + 1]
+        } else {
+            [self.chr_bank_0 as usize, self.chr_bank_1 as usize]
+        };
+        banks.map(|bank| bank % bank_count)
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let bank = self.chr_banks()[address as usize / CHR_BANK_4K_SIZE];
+        bank * CHR_BANK_4K_SIZE + (address as usize & (CHR_BANK_4K_SIZE - 1))
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        !self.prg_ram.is_empty() && self.prg_bank & Self::PRG_RAM_DISABLE == 0
+    }
+
+    // The fifth write picks the register from bits 13 and 14 of its address.
+    fn write_shift(&mut self, address: u16, data: u8) {
+        if data & Self::RESET_BIT != 0 {
+            self.shift = 0;
+            self.shift_count = 0;
+            self.control |= Self::POWER_ON_CONTROL;
+            return;
+        }
+        self.shift |= (data & 1) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count < 5 {
+            return;
+        }
+        let value = core::mem::take(&mut self.shift);
+        self.shift_count = 0;
+        match (address >> 13) & 0x03 {
+            0 => self.control = value,
+            1 => self.chr_bank_0 = value,
+            2 => self.chr_bank_1 = value,
+            _ => self.prg_bank = value,
+        }
+    }
+}
+
+impl Mapper for Mapper1 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0xFFFF => {
+                let bank = self.prg_banks()[(address as usize - 0x8000) / PRG_BANK_16K_SIZE];
+                self.prg_rom[bank * PRG_BANK_16K_SIZE + (address as usize & (PRG_BANK_16K_SIZE - 1))]
+            }
+            0x6000..=0x7FFF if self.is_prg_ram_enabled() => {
+                self.prg_ram[(address as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x0000..=0x1FFF => self.chr.read(self.chr_offset(address)),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x8000..=0xFFFF => self.write_shift(address, data),
+            0x6000..=0x7FFF if self.is_prg_ram_enabled() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(address as usize - 0x6000) % len] = data;
+            }
+            0x0000..=0x1FFF => self.chr.write(self.chr_offset(address), data),
+            _ => (),
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn is_mirroring_from_header(&self) -> bool {
+        false
+    }
+
+    fn is_writable(&self, address: u16) -> bool {
+        match address {
+            0x6000..=0x7FFF => self.is_prg_ram_enabled(),
+            0x0000..=0x1FFF | 0x8000..=0xFFFF => true,
+            _ => false,
+        }
+    }
+
+    fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    fn chr(&self) -> &ChrMemory {
+        &self.chr
+    }
+
+    fn power_on(&mut self) {
+        self.shift = 0;
+        self.shift_count = 0;
+        self.control = Self::POWER_ON_CONTROL;
+        self.chr_bank_0 = 0;
+        self.chr_bank_1 = 0;
+        self.prg_bank = 0;
+    }
+
+    fn bank_mapping(&self) -> BankMapping {
+        let [low, high] = self.prg_banks();
+        let [chr_low, chr_high] = self.chr_banks();
+        BankMapping {
+            prg: [low * 2, low * 2 + 1, high * 2, high * 2 + 1],
+            chr: core::array::from_fn(|window| {
+                let bank = if window < 4 { chr_low } else { chr_high };
+                bank * 4 + window % 4
+            }),
+        }
+    }
+
+    fn save_state(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&[
+            self.shift,
+            self.shift_count,
+            self.control,
+            self.chr_bank_0,
+            self.chr_bank_1,
+            self.prg_bank,
+        ]);
+        state::write_sized(bytes, &self.prg_ram);
+        self.chr.save_state(bytes);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Option<()> {
+        self.shift = state.u8()?;
+        self.shift_count = state.u8()?;
+        if self.shift_count >= 5 {
+            return None;
+        }
+        self.control = state.u8()?;
+        self.chr_bank_0 = state.u8()?;
+        self.chr_bank_1 = state.u8()?;
+        self.prg_bank = state.u8()?;
+        state.sized_into(&mut self.prg_ram)?;
+        self.chr.load_state(state)
+    }
+}
+
+// UxROM: a switchable 16KB PRG bank at $8000 and the last bank fixed at
+// $C000, where the vectors live.
+#[derive(Debug)]
+pub struct Mapper2 {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    prg_bank: usize,
+    mirroring: Mirroring,
+}
+
+impl Mapper2 {
+    pub fn new(prg_rom: Vec<u8>, chr: ChrMemory, mirroring: Mirroring) -> Self {
+        Self {
+            prg_rom,
+            chr,
+            prg_bank: 0,
+            mirroring,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_16K_SIZE).max(1)
+    }
+}
+
+impl Mapper for Mapper2 {
+    fn read(&self, address: u16) -> u8 {
+        let offset = address as usize & (PRG_BANK_16K_SIZE - 1);
+        match address {
+            0x8000..=0xBFFF => self.prg_rom[self.prg_bank * PRG_BANK_16K_SIZE + offset],
+            0xC000..=0xFFFF => self.prg_rom[(self.prg_bank_count() - 1) * PRG_BANK_16K_SIZE + offset],
+            0x0000..=0x1FFF => self.chr.read(address as usize),
+            _ => 0,
+        }
     }
 
-    pub fn read(&self, address: u16) -> u8 {
+    fn write(&mut self, address: u16, data: u8) {
         match address {
-            // this actual for 1 bank roms (mirrored) but not for 2 banks
-            0x8000..=0xFFFF => self.prg_rom[(address & 0x3FFF) as usize],
-            0x0000..=0x1FFF => self.chr_rom[address as usize],
-            _ => panic!("invalid address {:#X}", address),
+            0x8000..=0xFFFF => self.prg_bank = data as usize % self.prg_bank_count(),
+            0x0000..=0x1FFF => self.chr.write(address as usize, data),
+            _ => (),
         }
     }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    // no PRG RAM; every ROM write is a bank select
+    fn is_writable(&self, address: u16) -> bool {
+        matches!(address, 0x0000..=0x1FFF | 0x8000..=0xFFFF)
+    }
+
+    fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    fn chr(&self) -> &ChrMemory {
+        &self.chr
+    }
+
+    fn power_on(&mut self) {
+        self.prg_bank = 0;
+    }
+
+    fn bank_mapping(&self) -> BankMapping {
+        let last = self.prg_bank_count() - 1;
+        BankMapping {
+            prg: [self.prg_bank * 2, self.prg_bank * 2 + 1, last * 2, last * 2 + 1],
+            ..Default::default()
+        }
+    }
+
+    fn save_state(&self, bytes: &mut Vec<u8>) {
+        bytes.push(self.prg_bank as u8);
+        self.chr.save_state(bytes);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Option<()> {
+        self.prg_bank = state.u8()? as usize;
+        if self.prg_bank >= self.prg_bank_count() {
+            return None;
+        }
+        self.chr.load_state(state)
+    }
+}
+
+// AxROM: 32KB PRG banks and a register that also picks one of the two
+// nametables for single-screen mirroring.
+#[derive(Debug)]
+pub struct Mapper7 {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    prg_bank: usize,
+    mirroring: Mirroring,
+}
+
+impl Mapper7 {
+    const BANK_SELECT_BITS: u8 = 0x07;
+    const NAMETABLE_SELECT: u8 = 1 << 4;
+
+    pub fn new(prg_rom: Vec<u8>, chr: ChrMemory) -> Self {
+        Self {
+            prg_rom,
+            chr,
+            prg_bank: 0,
+            mirroring: Mirroring::SingleScreenLower,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_SIZE).max(1)
+    }
+}
+
+impl Mapper for Mapper7 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0xFFFF => {
+                let offset = self.prg_bank * PRG_BANK_SIZE + (address as usize & (PRG_BANK_SIZE - 1));
+                self.prg_rom[offset % self.prg_rom.len()]
+            }
+            0x0000..=0x1FFF => self.chr.read(address as usize),
+            // no PRG RAM
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x8000..=0xFFFF => {
+                self.prg_bank = (data & Self::BANK_SELECT_BITS) as usize % self.prg_bank_count();
+                self.mirroring = if data & Self::NAMETABLE_SELECT == 0 {
+                    Mirroring::SingleScreenLower
+                } else {
+                    Mirroring::SingleScreenUpper
+                };
+            }
+            0x0000..=0x1FFF => self.chr.write(address as usize, data),
+            _ => (),
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn is_mirroring_from_header(&self) -> bool {
+        false
+    }
+
+    // no PRG RAM; every ROM write is a bank select
+    fn is_writable(&self, address: u16) -> bool {
+        matches!(address, 0x0000..=0x1FFF | 0x8000..=0xFFFF)
+    }
+
+    fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    fn chr(&self) -> &ChrMemory {
+        &self.chr
+    }
+
+    fn bank_mapping(&self) -> BankMapping {
+        let first = self.prg_bank * 4;
+        BankMapping {
+            prg: [first, first + 1, first + 2, first + 3],
+            ..Default::default()
+        }
+    }
+
+    fn power_on(&mut self) {
+        self.prg_bank = 0;
+        self.mirroring = Mirroring::SingleScreenLower;
+    }
+
+    fn save_state(&self, bytes: &mut Vec<u8>) {
+        let is_upper = self.mirroring == Mirroring::SingleScreenUpper;
+        bytes.extend_from_slice(&[self.prg_bank as u8, is_upper as u8]);
+        self.chr.save_state(bytes);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Option<()> {
+        self.prg_bank = state.u8()? as usize;
+        if self.prg_bank >= self.prg_bank_count() {
+            return None;
+        }
+        self.mirroring = if state.bool()? {
+            Mirroring::SingleScreenUpper
+        } else {
+            Mirroring::SingleScreenLower
+        };
+        self.chr.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_axrom_single_screen() {
+        let mut prg_rom = vec![0; 2 * PRG_BANK_SIZE];
+        prg_rom[PRG_BANK_SIZE] = 0x11;
+        let mut mapper = Mapper7::new(prg_rom, ChrMemory::new(Vec::new()));
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenLower);
+        mapper.write(0x8000, 0x11);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenUpper);
+        assert_eq!(mapper.read(0x8000), 0x11);
+        mapper.write(0xFFFF, 0x00);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenLower);
+        assert_eq!(mapper.read(0x8000), 0x00);
+    }
+
+    #[test]
+    fn test_nrom_open_bus() {
+        let chr = ChrMemory::new(Vec::new());
+        let mapper = Mapper0::new(vec![0xEA; PRG_BANK_16K_SIZE], chr, 0, Mirroring::Vertical);
+        assert_eq!(mapper.read(0x4020), 0);
+        assert_eq!(mapper.read(0x5FFF), 0);
+        assert_eq!(mapper.read(0x6000), 0);
+        assert_eq!(mapper.read(0x7FFF), 0);
+    }
+
+    #[test]
+    fn test_chr_ram_writes() {
+        for id in [0, 2] {
+            let chr = ChrMemory::new(Vec::new());
+            let mut mapper = from_id(id, vec![0; 2 * PRG_BANK_16K_SIZE], chr, 0, Mirroring::Vertical).unwrap();
+            mapper.write(0x0000, 0x12);
+            mapper.write(0x1FFF, 0x34);
+            assert_eq!(mapper.read(0x0000), 0x12, "mapper {id}");
+            assert_eq!(mapper.read(0x1FFF), 0x34, "mapper {id}");
+        }
+    }
+
+    #[test]
+    fn test_bank_mapping() {
+        let chr = ChrMemory::new(Vec::new());
+        let mut mapper = from_id(2, vec![0; 8 * PRG_BANK_16K_SIZE], chr, 0, Mirroring::Vertical).unwrap();
+        assert_eq!(mapper.bank_mapping().prg, [0, 1, 14, 15]);
+        mapper.write(0x8000, 0x03);
+        assert_eq!(mapper.bank_mapping().prg, [6, 7, 14, 15]);
+        assert_eq!(mapper.bank_mapping().chr, [0, 1, 2, 3, 4, 5, 6, 7]);
+
+        let chr = ChrMemory::new(Vec::new());
+        let mut mapper = from_id(7, vec![0; 4 * PRG_BANK_SIZE], chr, 0, Mirroring::Vertical).unwrap();
+        mapper.write(0x8000, 0x02);
+        assert_eq!(mapper.bank_mapping().prg, [8, 9, 10, 11]);
+    }
+
+    // Loads an MMC1 register through the shift register, low bit first.
+    fn mmc1_write(mapper: &mut dyn Mapper, address: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.write(address, value >> bit & 1);
+        }
+    }
+
+    #[test]
+    fn test_mmc1_bank_mapping() {
+        let mut prg_rom = vec![0; 8 * PRG_BANK_16K_SIZE];
+        for bank in 0..8 {
+            prg_rom[bank * PRG_BANK_16K_SIZE] = bank as u8;
+        }
+        let chr = ChrMemory::new(vec![0; 32 * CHR_BANK_4K_SIZE]);
+        let mut mapper = from_id(1, prg_rom, chr, 8 * 1024, Mirroring::Horizontal).unwrap();
+        assert_eq!(mapper.bank_mapping(), BankMapping { prg: [0, 1, 14, 15], ..Default::default() });
+
+        mmc1_write(mapper.as_mut(), 0xE000, 0x03);
+        assert_eq!(mapper.bank_mapping().prg, [6, 7, 14, 15]);
+        assert_eq!((mapper.read(0x8000), mapper.read(0xC000)), (3, 7));
+
+        // vertical mirroring, first bank fixed at $8000, 4KB CHR banks
+        mmc1_write(mapper.as_mut(), 0x8000, 0x1A);
+        mmc1_write(mapper.as_mut(), 0xA000, 0x05);
+        mmc1_write(mapper.as_mut(), 0xC000, 0x09);
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+        assert_eq!(mapper.bank_mapping().prg, [0, 1, 6, 7]);
+        assert_eq!(mapper.bank_mapping().chr, [20, 21, 22, 23, 36, 37, 38, 39]);
+
+        // a write with bit 7 set drops the bits so far and restores PRG mode 3
+        mapper.write(0xE000, 0x01);
+        mapper.write(0xE000, 0x80);
+        mmc1_write(mapper.as_mut(), 0xE000, 0x02);
+        assert_eq!(mapper.bank_mapping().prg, [4, 5, 14, 15]);
+    }
+
+    #[test]
+    fn test_nrom_bank_mapping() {
+        for (banks, prg) in [(1, [0, 1, 0, 1]), (2, [0, 1, 2, 3])] {
+            let mut prg_rom = vec![0; banks * PRG_BANK_16K_SIZE];
+            *prg_rom.last_mut().unwrap() = 0xC0;
+            let chr = ChrMemory::new(Vec::new());
+            let mapper = from_id(0, prg_rom, chr, 0, Mirroring::Vertical).unwrap();
+            assert_eq!(mapper.bank_mapping().prg, prg, "{banks} banks");
+            assert_eq!(mapper.read(0xFFFF), 0xC0, "{banks} banks");
+        }
+    }
+
+    #[test]
+    fn test_supported_ids() {
+        assert!(supported_ids().contains(&0));
+        assert!(!is_supported(250));
+        for id in supported_ids() {
+            let chr = ChrMemory::new(Vec::new());
+            let mapper = from_id(*id, vec![0; 2 * PRG_BANK_16K_SIZE], chr, 0, Mirroring::Vertical);
+            assert!(mapper.is_some(), "mapper {id}");
+            let chr = ChrMemory::new(Vec::new());
+            assert!(from_id(*id, Vec::new(), chr, 0, Mirroring::Vertical).is_none(), "mapper {id}");
+        }
+        let chr = ChrMemory::new(Vec::new());
+        assert!(from_id(250, vec![0; PRG_BANK_16K_SIZE], chr, 0, Mirroring::Vertical).is_none());
+    }
+
+    #[test]
+    fn test_chr_rom_ignores_writes() {
+        let mut chr = ChrMemory::new(vec![0xAA; CHR_RAM_SIZE]);
+        chr.write(0x0010, 0x00);
+        assert_eq!(chr.read(0x0010), 0xAA);
+    }
 }
@@ -0,0 +1,132 @@
+const PALETTE_SIZE: usize = 64;
+
+const MASK_GRAYSCALE: u8 = 1 << 0;
+const MASK_EMPHASIS_BITS: u8 = 0xE0;
+
+// channels that are not emphasized are dimmed to roughly 80%
+const EMPHASIS_ATTENUATION: u16 = 204;
+
+const NTSC_2C02: [[u8; 3]; PALETTE_SIZE] = [
+    [84, 84, 84], [0, 30, 116], [8, 16, 144], [48, 0, 136],
+    [68, 0, 100], [92, 0, 48], [84, 4, 0], [60, 24, 0],
+    [32, 42, 0], [8, 58, 0], [0, 64, 0], [0, 60, 0],
+    [0, 50, 60], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [152, 150, 152], [8, 76, 196], [48, 50, 236], [92, 30, 228],
+    [136, 20, 176], [160, 20, 100], [152, 34, 32], [120, 60, 0],
+    [84, 90, 0], [40, 114, 0], [8, 124, 0], [0, 118, 40],
+    [0, 102, 120], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [236, 238, 236], [76, 154, 236], [120, 124, 236], [176, 98, 236],
+    [228, 84, 236], [236, 88, 180], [236, 106, 100], [212, 136, 32],
+    [160, 170, 0], [116, 196, 0], [76, 208, 32], [56, 204, 108],
+    [56, 180, 204], [60, 60, 60], [0, 0, 0], [0, 0, 0],
+    [236, 238, 236], [168, 204, 236], [188, 188, 236], [212, 178, 236],
+    [236, 174, 236], [236, 174, 212], [236, 180, 176], [228, 196, 144],
+    [204, 210, 120], [180, 222, 120], [168, 226, 144], [152, 226, 180],
+    [160, 214, 228], [160, 162, 160], [0, 0, 0], [0, 0, 0],
+];
+
+// RGB PPU (VS System / PlayChoice-10), 3 bits per channel
+const RGB_2C03: [u16; PALETTE_SIZE] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420,
+    0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630,
+    0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750,
+    0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772,
+    0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PaletteKind {
+    Ntsc2C02,
+    Rgb2C03,
+    Grayscale,
+}
+
+#[derive(Debug)]
+pub struct Palette {
+    colors: [[u8; 3]; PALETTE_SIZE],
+}
+
+impl Palette {
+    pub fn new(kind: PaletteKind) -> Self {
+        let colors = match kind {
+            PaletteKind::Ntsc2C02 => NTSC_2C02,
+            PaletteKind::Rgb2C03 => RGB_2C03.map(|color| {
+                [color >> 6, color >> 3, color].map(|level| ((level & 7) * 255 / 7) as u8)
+            }),
+            PaletteKind::Grayscale => NTSC_2C02.map(|[r, g, b]| {
+                let luma = ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8;
+                [luma; 3]
+            }),
+        };
+        Self { colors }
+    }
+
+    pub fn to_rgb(&self, index: u8) -> [u8; 3] {
+        self.colors[index as usize % PALETTE_SIZE]
+    }
+
+    // Applies the PPUMASK grayscale and color emphasis bits on top of the lookup.
+    pub fn to_rgb_masked(&self, index: u8, mask: u8) -> [u8; 3] {
+        let index = if mask & MASK_GRAYSCALE != 0 {
+            index & 0x30
+        } else {
+            index
+        };
+        let mut rgb = self.to_rgb(index);
+        let emphasis = (mask & MASK_EMPHASIS_BITS) >> 5;
+        if emphasis != 0 {
+            for (channel, value) in rgb.iter_mut().enumerate() {
+                if emphasis & (1 << channel) == 0 {
+                    *value = (*value as u16 * EMPHASIS_ATTENUATION / 255) as u8;
+                }
+            }
+        }
+        rgb
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::new(PaletteKind::Ntsc2C02)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_black() {
+        for kind in [PaletteKind::Ntsc2C02, PaletteKind::Rgb2C03, PaletteKind::Grayscale] {
+            assert_eq!(Palette::new(kind).to_rgb(0x0F), [0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn test_rgb_2c03_levels() {
+        let palette = Palette::new(PaletteKind::Rgb2C03);
+        assert_eq!(palette.to_rgb(0x30), [255, 255, 255]);
+        assert_eq!(palette.to_rgb(0x16), [255, 0, 0]);
+    }
+
+    #[test]
+    fn test_grayscale_mask() {
+        let palette = Palette::default();
+        assert_eq!(palette.to_rgb_masked(0x16, MASK_GRAYSCALE), palette.to_rgb(0x10));
+    }
+
+    #[test]
+    fn test_emphasis() {
+        let palette = Palette::default();
+        let [r, g, b] = palette.to_rgb(0x30);
+        // emphasize red
+        assert_eq!(palette.to_rgb_masked(0x30, 1 << 5), [r, 190, 188]);
+        assert!(palette.to_rgb_masked(0x30, 1 << 5)[1] < g);
+        // emphasize green and blue
+        assert_eq!(palette.to_rgb_masked(0x30, 0xC0), [188, g, b]);
+        assert_eq!(palette.to_rgb_masked(0x30, 0x00), [r, g, b]);
+    }
+}
@@ -0,0 +1,812 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::palette::Palette;
+use crate::state::StateReader;
+use crate::timing::{self, RegionTiming};
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+// first scanline after the visible picture
+const POST_RENDER_SCANLINE: u32 = 240;
+// vertical blank starts at dot 1 of the scanline after post-render
+const VBLANK_SCANLINE: u32 = POST_RENDER_SCANLINE + 1;
+
+// Open bus bits fade after roughly 600ms without being driven.
+pub const DEFAULT_OPEN_BUS_DECAY_FRAMES: usize = 36;
+
+const FNV_OFFSET_BASIS: u64 = 0xCBF29CE484222325;
+const FNV_PRIME: u64 = 0x00000100000001B3;
+
+// PPUCTRL ($2000) split into its fields.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PpuCtrl {
+    pub nametable_base: u16,
+    // added to the VRAM address after each PPUDATA access
+    pub vram_increment: u16,
+    // for 8x8 sprites; 8x16 sprites pick their table from the tile index
+    pub sprite_pattern_table: u16,
+    pub background_pattern_table: u16,
+    pub sprite_height: u8,
+    pub is_nmi_enabled: bool,
+}
+
+impl From<u8> for PpuCtrl {
+    fn from(bits: u8) -> Self {
+        Self {
+            nametable_base: 0x2000 + 0x400 * (bits & 0x03) as u16,
+            vram_increment: if bits & 0x04 == 0 { 1 } else { 32 },
+            sprite_pattern_table: if bits & 0x08 == 0 { 0x0000 } else { 0x1000 },
+            background_pattern_table: if bits & 0x10 == 0 { 0x0000 } else { 0x1000 },
+            sprite_height: if bits & 0x20 == 0 { 8 } else { 16 },
+            is_nmi_enabled: bits & 0x80 != 0,
+        }
+    }
+}
+
+// PPUMASK ($2001) split into its fields.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PpuMask {
+    pub is_greyscale: bool,
+    // whether the leftmost 8 pixels are drawn
+    pub show_background_left: bool,
+    pub show_sprites_left: bool,
+    pub show_background: bool,
+    pub show_sprites: bool,
+    pub emphasize_red: bool,
+    pub emphasize_green: bool,
+    pub emphasize_blue: bool,
+}
+
+impl From<u8> for PpuMask {
+    fn from(bits: u8) -> Self {
+        Self {
+            is_greyscale: bits & 0x01 != 0,
+            show_background_left: bits & 0x02 != 0,
+            show_sprites_left: bits & 0x04 != 0,
+            show_background: bits & 0x08 != 0,
+            show_sprites: bits & 0x10 != 0,
+            emphasize_red: bits & 0x20 != 0,
+            emphasize_green: bits & 0x40 != 0,
+            emphasize_blue: bits & 0x80 != 0,
+        }
+    }
+}
+
+// PPUSTATUS ($2002) flags; the low five bits are open bus.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PpuStatus {
+    pub is_sprite_overflow: bool,
+    pub is_sprite_zero_hit: bool,
+    pub is_vblank: bool,
+}
+
+impl PpuStatus {
+    fn bits(&self) -> u8 {
+        (self.is_sprite_overflow as u8) << 5
+            | (self.is_sprite_zero_hit as u8) << 6
+            | (self.is_vblank as u8) << 7
+    }
+}
+
+impl From<u8> for PpuStatus {
+    fn from(bits: u8) -> Self {
+        Self {
+            is_sprite_overflow: bits & 0x20 != 0,
+            is_sprite_zero_hit: bits & 0x40 != 0,
+            is_vblank: bits & 0x80 != 0,
+        }
+    }
+}
+
+pub const OAM_SIZE: usize = 256;
+// background and sprite palettes at $3F00-$3F1F
+const PALETTE_SIZE: usize = 32;
+const PALETTE_ADDR: u16 = 0x3F00;
+const SPRITE_COUNT: usize = OAM_SIZE / 4;
+// sprites the PPU can draw on one scanline
+const SPRITES_PER_SCANLINE: usize = 8;
+
+// How sprite evaluation decides the sprite overflow flag.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum SpriteOverflowMode {
+    // set when more than eight sprites are on the scanline
+    #[default]
+    Correct,
+    // the hardware's diagonal OAM scan: once eight sprites are found, a miss
+    // advances the byte within the entry along with the sprite, so tile,
+    // attribute and X bytes get compared as Y, giving false hits and misses
+    Hardware,
+}
+
+#[derive(Debug)]
+pub struct Ppu {
+    // one palette index per pixel
+    framebuffer: Vec<u8>,
+    // when off, timing still advances but the framebuffer is left untouched
+    is_render_enabled: bool,
+    timing: RegionTiming,
+    // position in the frame, in dots scaled by the dots per cycle denominator
+    // so PAL's fractional ratio stays exact
+    scaled_dot: u32,
+    frame: usize,
+    // last value driven onto the PPU data bus, returned by reads of
+    // write-only registers; each bit decays on its own
+    io_latch: u8,
+    io_latch_frames: [usize; 8],
+    open_bus_decay_frames: Option<usize>,
+    ctrl: u8,
+    mask: u8,
+    // set on entering the post-render scanline, cleared by take_frame_ready
+    is_frame_ready: bool,
+    // PPUSTATUS flags, cleared at the start of the pre-render scanline.
+    // Nothing is drawn yet, so sprite 0 never hits.
+    is_vblank: bool,
+    is_sprite_overflow: bool,
+    is_sprite_zero_hit: bool,
+    // sprite attributes, four bytes per sprite: Y, tile, attributes, X
+    oam: [u8; OAM_SIZE],
+    palette: [u8; PALETTE_SIZE],
+    oam_addr: u8,
+    sprite_overflow_mode: SpriteOverflowMode,
+    // the internal scroll registers shared by PPUSCROLL and PPUADDR:
+    // current VRAM address (v), temporary address (t), fine X scroll (x)
+    // and the write toggle (w)
+    vram_addr: u16,
+    temp_addr: u16,
+    fine_x: u8,
+    is_second_write: bool,
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Self {
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            is_render_enabled: true,
+            timing: timing::NTSC,
+            scaled_dot: 0,
+            frame: 0,
+            io_latch: 0,
+            io_latch_frames: [0; 8],
+            open_bus_decay_frames: Some(DEFAULT_OPEN_BUS_DECAY_FRAMES),
+            ctrl: 0,
+            mask: 0,
+            is_frame_ready: false,
+            is_vblank: false,
+            is_sprite_overflow: false,
+            is_sprite_zero_hit: false,
+            oam: [0; OAM_SIZE],
+            palette: [0; PALETTE_SIZE],
+            oam_addr: 0,
+            sprite_overflow_mode: SpriteOverflowMode::default(),
+            vram_addr: 0,
+            temp_addr: 0,
+            fine_x: 0,
+            is_second_write: false,
+        }
+    }
+
+    pub fn set_timing(&mut self, timing: RegionTiming) {
+        self.timing = timing;
+        self.scaled_dot = 0;
+    }
+
+    // Advances by one CPU cycle.
+    pub fn clock(&mut self) {
+        let (dots, scale) = self.timing.ppu_dots_per_cpu_cycle;
+        let scaled_scanline = self.timing.dots_per_scanline * scale;
+        let before = self.scaled_dot;
+        let scanline = self.scanline();
+        self.scaled_dot += dots;
+        let is_crossed = |position: u32| before < position && self.scaled_dot >= position;
+        if is_crossed(POST_RENDER_SCANLINE * scaled_scanline) {
+            self.is_frame_ready = true;
+        }
+        if is_crossed(VBLANK_SCANLINE * scaled_scanline + scale) {
+            self.is_vblank = true;
+        }
+        let pre_render = self.timing.scanlines_per_frame - 1;
+        if is_crossed(pre_render * scaled_scanline + scale) {
+            self.is_vblank = false;
+            self.is_sprite_overflow = false;
+            self.is_sprite_zero_hit = false;
+        }
+        let scaled_frame = self.timing.dots_per_frame() * scale;
+        if self.scaled_dot >= scaled_frame {
+            self.scaled_dot -= scaled_frame;
+            self.frame += 1;
+        }
+        let mask = self.mask();
+        let is_rendering = mask.show_background || mask.show_sprites;
+        let next_scanline = self.scanline();
+        if next_scanline != scanline && next_scanline < POST_RENDER_SCANLINE && is_rendering {
+            self.is_sprite_overflow |= self.sprite_overflow(next_scanline as u16);
+        }
+        if next_scanline != scanline && scanline < POST_RENDER_SCANLINE && self.is_render_enabled {
+            self.output_scanline(scanline as usize);
+        }
+    }
+
+    // Backgrounds and sprites aren't drawn yet, so every line shows the
+    // backdrop colour.
+    fn output_scanline(&mut self, scanline: usize) {
+        let backdrop = self.palette[0];
+        self.framebuffer[scanline * SCREEN_WIDTH..(scanline + 1) * SCREEN_WIDTH].fill(backdrop);
+    }
+
+    pub fn set_render_enabled(&mut self, is_enabled: bool) {
+        self.is_render_enabled = is_enabled;
+    }
+
+    pub fn is_render_enabled(&self) -> bool {
+        self.is_render_enabled
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    // CPU cycles until the next scanline starts, rounded up.
+    pub fn cycles_to_next_scanline(&self) -> u32 {
+        let (dots, scale) = self.timing.ppu_dots_per_cpu_cycle;
+        let scaled_scanline = self.timing.dots_per_scanline * scale;
+        let remaining = scaled_scanline - self.scaled_dot % scaled_scanline;
+        remaining.div_ceil(dots)
+    }
+
+    // CPU cycles until the next frame starts, rounded up.
+    pub fn cycles_to_next_frame(&self) -> u32 {
+        let (dots, scale) = self.timing.ppu_dots_per_cpu_cycle;
+        let remaining = self.timing.dots_per_frame() * scale - self.scaled_dot;
+        remaining.div_ceil(dots)
+    }
+
+    // Whether the visible picture has been completed since the last call,
+    // which is when a frontend should present the framebuffer. Independent
+    // of whether NMI is enabled.
+    pub fn take_frame_ready(&mut self) -> bool {
+        core::mem::take(&mut self.is_frame_ready)
+    }
+
+    // The NMI output: low while the vblank flag is set and PPUCTRL enables NMI.
+    pub fn is_nmi_asserted(&self) -> bool {
+        PpuCtrl::from(self.ctrl).is_nmi_enabled && self.is_vblank
+    }
+
+    // Scanline within the current frame, 0 being the first visible one.
+    pub fn scanline(&self) -> u32 {
+        let (_, scale) = self.timing.ppu_dots_per_cpu_cycle;
+        self.scaled_dot / scale / self.timing.dots_per_scanline
+    }
+
+    // Frames an open bus bit holds its value for; None keeps bits forever,
+    // which makes open bus reads independent of timing.
+    pub fn set_open_bus_decay(&mut self, frames: Option<usize>) {
+        self.open_bus_decay_frames = frames;
+    }
+
+    // Registers, OAM, palette RAM and timing position for save states. The framebuffer is
+    // output rather than state, and the timing and open bus settings belong
+    // to the emulator's configuration.
+    pub fn save_state(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.scaled_dot.to_le_bytes());
+        bytes.extend_from_slice(&(self.frame as u64).to_le_bytes());
+        bytes.push(self.io_latch);
+        for frames in self.io_latch_frames {
+            bytes.extend_from_slice(&(frames as u64).to_le_bytes());
+        }
+        bytes.extend_from_slice(&[
+            self.ctrl,
+            self.mask,
+            self.is_frame_ready as u8,
+            self.is_vblank as u8,
+            self.is_sprite_overflow as u8,
+            self.is_sprite_zero_hit as u8,
+        ]);
+        bytes.extend_from_slice(&self.oam);
+        bytes.extend_from_slice(&self.palette);
+        bytes.push(self.oam_addr);
+        bytes.extend_from_slice(&self.vram_addr.to_le_bytes());
+        bytes.extend_from_slice(&self.temp_addr.to_le_bytes());
+        bytes.extend_from_slice(&[self.fine_x, self.is_second_write as u8]);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Option<()> {
+        let (_, scale) = self.timing.ppu_dots_per_cpu_cycle;
+        let scaled_dot = state.u32()?;
+        if scaled_dot >= self.timing.dots_per_frame() * scale {
+            return None;
+        }
+        self.scaled_dot = scaled_dot;
+        self.frame = state.u64()? as usize;
+        self.io_latch = state.u8()?;
+        for frames in self.io_latch_frames.iter_mut() {
+            *frames = state.u64()? as usize;
+        }
+        self.ctrl = state.u8()?;
+        self.mask = state.u8()?;
+        self.is_frame_ready = state.bool()?;
+        self.is_vblank = state.bool()?;
+        self.is_sprite_overflow = state.bool()?;
+        self.is_sprite_zero_hit = state.bool()?;
+        self.oam = state.array()?;
+        self.palette = state.array()?;
+        self.oam_addr = state.u8()?;
+        self.vram_addr = state.u16()?;
+        self.temp_addr = state.u16()?;
+        self.fine_x = state.u8()?;
+        self.is_second_write = state.bool()?;
+        Some(())
+    }
+
+    // $2000-$2007 and mirrors. PPUCTRL and PPUMASK are latched for ctrl and
+    // mask. PPUSTATUS drives its top three bits; every other read sees open bus.
+    pub fn read_register(&mut self, address: u16) -> u8 {
+        let value = self.peek_register(address);
+        if address & 0x2007 == 0x2002 {
+            self.is_second_write = false;
+            self.is_vblank = false;
+        }
+        value
+    }
+
+    pub fn peek_register(&self, address: u16) -> u8 {
+        match address & 0x2007 {
+            0x2002 => self.status().bits() | self.open_bus() & 0x1F,
+            _ => self.open_bus(),
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, data: u8) {
+        self.io_latch = data;
+        self.io_latch_frames = [self.frame; 8];
+        match address & 0x2007 {
+            0x2000 => {
+                self.ctrl = data;
+                // nametable select
+                self.temp_addr = self.temp_addr & !0x0C00 | ((data & 0x03) as u16) << 10;
+            }
+            0x2001 => self.mask = data,
+            0x2003 => self.oam_addr = data,
+            0x2004 => {
+                self.oam[self.oam_addr as usize] = data;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            }
+            // coarse X and fine X, then coarse Y and fine Y
+            0x2005 if !self.is_second_write => {
+                self.temp_addr = self.temp_addr & !0x001F | (data >> 3) as u16;
+                self.fine_x = data & 0x07;
+                self.is_second_write = true;
+            }
+            0x2005 => {
+                self.temp_addr = self.temp_addr & !0x73E0
+                    | ((data & 0x07) as u16) << 12
+                    | ((data & 0xF8) as u16) << 2;
+                self.is_second_write = false;
+            }
+            // high byte, with bit 14 cleared, then low byte, which copies t to v
+            0x2006 if !self.is_second_write => {
+                self.temp_addr = self.temp_addr & 0x00FF | ((data & 0x3F) as u16) << 8;
+                self.is_second_write = true;
+            }
+            0x2006 => {
+                self.temp_addr = self.temp_addr & 0xFF00 | data as u16;
+                self.vram_addr = self.temp_addr;
+                self.is_second_write = false;
+            }
+            // only palette RAM is connected so far
+            0x2007 => {
+                if self.vram_addr & 0x3FFF >= PALETTE_ADDR {
+                    self.palette[palette_index(self.vram_addr)] = data & 0x3F;
+                }
+                self.vram_addr = self.vram_addr.wrapping_add(self.ctrl().vram_increment) & 0x7FFF;
+            }
+            _ => (),
+        }
+    }
+
+    // The 32 palette entries, $3F00 first.
+    pub fn palette(&self) -> &[u8; PALETTE_SIZE] {
+        &self.palette
+    }
+
+    // The current VRAM address, v.
+    pub fn vram_addr(&self) -> u16 {
+        self.vram_addr
+    }
+
+    // The temporary VRAM address, t, which holds the scroll position
+    // between frames.
+    pub fn temp_addr(&self) -> u16 {
+        self.temp_addr
+    }
+
+    pub fn fine_x(&self) -> u8 {
+        self.fine_x
+    }
+
+    // The w toggle: set after the first write to PPUSCROLL or PPUADDR.
+    pub fn is_second_write(&self) -> bool {
+        self.is_second_write
+    }
+
+    pub fn ctrl(&self) -> PpuCtrl {
+        PpuCtrl::from(self.ctrl)
+    }
+
+    pub fn mask(&self) -> PpuMask {
+        PpuMask::from(self.mask)
+    }
+
+    // What a read of PPUSTATUS would return, without its side effects.
+    pub fn status(&self) -> PpuStatus {
+        PpuStatus {
+            is_sprite_overflow: self.is_sprite_overflow,
+            is_sprite_zero_hit: self.is_sprite_zero_hit,
+            is_vblank: self.is_vblank,
+        }
+    }
+
+    pub fn oam(&self) -> &[u8; OAM_SIZE] {
+        &self.oam
+    }
+
+    pub fn set_sprite_overflow_mode(&mut self, mode: SpriteOverflowMode) {
+        self.sprite_overflow_mode = mode;
+    }
+
+    // Whether sprite evaluation for scanline sets the overflow flag, under
+    // the configured mode and the sprite height from PPUCTRL.
+    pub fn sprite_overflow(&self, scanline: u16) -> bool {
+        let height = self.ctrl().sprite_height as u16;
+        let is_in_range = |y: u8| scanline.wrapping_sub(y as u16) < height;
+        if self.sprite_overflow_mode == SpriteOverflowMode::Correct {
+            let found = self.oam.chunks(4).filter(|sprite| is_in_range(sprite[0])).count();
+            return found > SPRITES_PER_SCANLINE;
+        }
+        let mut n = 0;
+        let mut found = 0;
+        while n < SPRITE_COUNT && found < SPRITES_PER_SCANLINE {
+            if is_in_range(self.oam[n * 4]) {
+                found += 1;
+            }
+            n += 1;
+        }
+        let mut m = 0;
+        while n < SPRITE_COUNT {
+            if is_in_range(self.oam[n * 4 + m]) {
+                return true;
+            }
+            n += 1;
+            m = (m + 1) % 4;
+        }
+        false
+    }
+
+    fn open_bus(&self) -> u8 {
+        let Some(decay_frames) = self.open_bus_decay_frames else {
+            return self.io_latch;
+        };
+        (0..8)
+            .filter(|bit| self.frame - self.io_latch_frames[*bit] < decay_frames)
+            .fold(0, |value, bit| value | self.io_latch & 1 << bit)
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    pub fn to_rgba(&self, palette: &Palette) -> Vec<u8> {
+        self.framebuffer
+            .iter()
+            .flat_map(|index| {
+                let [r, g, b] = palette.to_rgb(*index);
+                [r, g, b, 0xFF]
+            })
+            .collect()
+    }
+
+    // FNV-1a over the framebuffer, stable across runs and builds so it can
+    // be pinned as a golden value in regression tests.
+    pub fn frame_hash(&self) -> u64 {
+        self.framebuffer.iter().fold(FNV_OFFSET_BASIS, |hash, pixel| {
+            (hash ^ *pixel as u64).wrapping_mul(FNV_PRIME)
+        })
+    }
+}
+
+// $3F10, $3F14, $3F18 and $3F1C mirror the entries 16 below them.
+fn palette_index(address: u16) -> usize {
+    let index = address as usize & (PALETTE_SIZE - 1);
+    if index & 0x13 == 0x10 {
+        index & 0x0F
+    } else {
+        index
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_frames(ppu: &mut Ppu, frames: usize) {
+        for _ in 0..frames * ppu.timing.cycles_per_frame() {
+            ppu.clock();
+        }
+    }
+
+    #[test]
+    fn test_open_bus_without_decay() {
+        let mut ppu = Ppu::new();
+        ppu.set_open_bus_decay(None);
+        ppu.write_register(0x2000, 0xA5);
+        run_frames(&mut ppu, 100);
+        assert_eq!(ppu.frame(), 100);
+        assert_eq!(ppu.read_register(0x2000), 0xA5);
+    }
+
+    #[test]
+    fn test_status_flags() {
+        let mut ppu = Ppu::new();
+        ppu.set_open_bus_decay(None);
+        // NMI enabled, which must not read back as vblank
+        ppu.write_register(0x2000, 0x80);
+        assert!(!ppu.status().is_vblank);
+        assert_eq!(ppu.read_register(0x2002), 0x00);
+        // nine sprites on scanline 20 and the rest off screen, with sprites shown
+        let mut sprites = [[0xFF, 0, 0, 0]; SPRITE_COUNT];
+        sprites[..9].fill([20, 0, 0, 0]);
+        write_oam(&mut ppu, &sprites);
+        ppu.write_register(0x2001, 0x10);
+        while ppu.scanline() < VBLANK_SCANLINE {
+            ppu.clock();
+        }
+        ppu.clock();
+        let status = ppu.status();
+        assert!(status.is_vblank && status.is_sprite_overflow && !status.is_sprite_zero_hit);
+        assert_eq!(ppu.peek_register(0x2002), 0xA0 | 0x10);
+        // reading clears vblank but not overflow
+        assert_eq!(ppu.read_register(0x2002), 0xA0 | 0x10);
+        assert_eq!(ppu.status().bits(), 0x20);
+        while ppu.scanline() != 0 {
+            ppu.clock();
+        }
+        assert_eq!(ppu.status().bits(), 0x00);
+    }
+
+    #[test]
+    fn test_nmi_output() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, 0x80);
+        while ppu.scanline() <= POST_RENDER_SCANLINE {
+            assert!(!ppu.is_nmi_asserted());
+            ppu.clock();
+        }
+        assert!(ppu.is_nmi_asserted());
+        ppu.write_register(0x2000, 0x00);
+        assert!(!ppu.is_nmi_asserted());
+        ppu.write_register(0x2000, 0x80);
+        while ppu.scanline() != 0 {
+            ppu.clock();
+        }
+        assert!(!ppu.is_nmi_asserted());
+    }
+
+    #[test]
+    fn test_pal_frame_length() {
+        let mut ppu = Ppu::new();
+        ppu.set_timing(timing::PAL);
+        for _ in 0..timing::PAL.cycles_per_frame() - 1 {
+            ppu.clock();
+        }
+        assert_eq!(ppu.frame(), 0);
+        ppu.clock();
+        assert_eq!(ppu.frame(), 1);
+    }
+
+    #[test]
+    fn test_cycles_to_next_scanline() {
+        let mut ppu = Ppu::new();
+        // 341 dots at 3 per cycle
+        assert_eq!(ppu.cycles_to_next_scanline(), 114);
+        for _ in 0..114 {
+            ppu.clock();
+        }
+        assert_eq!(ppu.scanline(), 1);
+        // one dot into the scanline
+        assert_eq!(ppu.cycles_to_next_scanline(), 114);
+        for _ in 0..113 {
+            ppu.clock();
+        }
+        assert_eq!(ppu.scanline(), 1);
+        assert_eq!(ppu.cycles_to_next_scanline(), 1);
+    }
+
+    #[test]
+    fn test_frame_ready() {
+        let mut ppu = Ppu::new();
+        let mut ready_count = 0;
+        for _ in 0..3 * timing::NTSC.cycles_per_frame() {
+            ppu.clock();
+            if ppu.take_frame_ready() {
+                assert_eq!(ppu.scanline(), POST_RENDER_SCANLINE);
+                ready_count += 1;
+            }
+        }
+        assert_eq!(ready_count, 3);
+        assert!(!ppu.take_frame_ready());
+    }
+
+    #[test]
+    fn test_open_bus_decay() {
+        let mut ppu = Ppu::new();
+        ppu.set_open_bus_decay(Some(10));
+        ppu.write_register(0x2000, 0xA5);
+        run_frames(&mut ppu, 9);
+        assert_eq!(ppu.read_register(0x2000), 0xA5);
+        run_frames(&mut ppu, 1);
+        assert_eq!(ppu.read_register(0x2000), 0x00);
+        ppu.write_register(0x2001, 0x0F);
+        assert_eq!(ppu.peek_register(0x2007), 0x0F);
+    }
+
+    #[test]
+    fn test_register_decode() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, 0b1011_0110);
+        assert_eq!(
+            ppu.ctrl(),
+            PpuCtrl {
+                nametable_base: 0x2800,
+                vram_increment: 32,
+                sprite_pattern_table: 0x0000,
+                background_pattern_table: 0x1000,
+                sprite_height: 16,
+                is_nmi_enabled: true,
+            }
+        );
+        // mirrors decode the same register
+        ppu.write_register(0x3FF9, 0x1E);
+        let mask = ppu.mask();
+        assert!(mask.show_background && mask.show_sprites && mask.show_sprites_left);
+        assert!(!mask.is_greyscale && !mask.emphasize_red);
+        assert_eq!(ppu.ctrl().nametable_base, 0x2800);
+        assert_eq!(PpuStatus::from(0xC0), PpuStatus {
+            is_sprite_overflow: false,
+            is_sprite_zero_hit: true,
+            is_vblank: true,
+        });
+    }
+
+    fn write_oam(ppu: &mut Ppu, sprites: &[[u8; 4]]) {
+        ppu.write_register(0x2003, 0x00);
+        for byte in sprites.iter().flatten() {
+            ppu.write_register(0x2004, *byte);
+        }
+    }
+
+    fn write_oam_at(ppu: &mut Ppu, sprite: u8, bytes: [u8; 4]) {
+        ppu.write_register(0x2003, sprite * 4);
+        for byte in bytes {
+            ppu.write_register(0x2004, byte);
+        }
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut ppu = Ppu::new();
+        // everything else is off screen
+        write_oam(&mut ppu, &[[0xFF; 4]; 64]);
+        write_oam(&mut ppu, &[[0x10, 0x00, 0x00, 0x00]; 9]);
+        assert_eq!(ppu.oam()[8 * 4], 0x10);
+        ppu.set_sprite_overflow_mode(SpriteOverflowMode::Hardware);
+        assert!(ppu.sprite_overflow(0x14));
+        assert!(!ppu.sprite_overflow(0x18));
+        ppu.set_sprite_overflow_mode(SpriteOverflowMode::Correct);
+        assert!(ppu.sprite_overflow(0x14));
+        // taller sprites reach further down
+        ppu.write_register(0x2000, 0x20);
+        assert!(ppu.sprite_overflow(0x18));
+    }
+
+    #[test]
+    fn test_sprite_overflow_hardware_bug() {
+        let mut ppu = Ppu::new();
+        write_oam(&mut ppu, &[[0xFF; 4]; 64]);
+        // eight sprites on scanline $20, a ninth off it, then a sprite whose
+        // tile byte happens to be in range
+        write_oam(&mut ppu, &[[0x20, 0x00, 0x00, 0x00]; 8]);
+        write_oam_at(&mut ppu, 9, [0xFF, 0x1E, 0x00, 0x00]);
+        ppu.set_sprite_overflow_mode(SpriteOverflowMode::Correct);
+        assert!(!ppu.sprite_overflow(0x20));
+        ppu.set_sprite_overflow_mode(SpriteOverflowMode::Hardware);
+        assert!(ppu.sprite_overflow(0x20));
+
+        // the diagonal scan can also miss a ninth sprite that is in range
+        write_oam_at(&mut ppu, 9, [0x20, 0xFF, 0xFF, 0xFF]);
+        write_oam_at(&mut ppu, 10, [0xFF; 4]);
+        assert!(!ppu.sprite_overflow(0x20));
+        ppu.set_sprite_overflow_mode(SpriteOverflowMode::Correct);
+        assert!(ppu.sprite_overflow(0x20));
+    }
+
+    #[test]
+    fn test_ppuaddr_writes() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2006, 0xFF);
+        // bit 14 is cleared and v waits for the second write
+        assert_eq!((ppu.temp_addr(), ppu.vram_addr()), (0x3F00, 0x0000));
+        ppu.write_register(0x2006, 0x10);
+        assert_eq!((ppu.temp_addr(), ppu.vram_addr()), (0x3F10, 0x3F10));
+        // a PPUSTATUS read resets the toggle mid-sequence
+        ppu.write_register(0x2006, 0x21);
+        ppu.read_register(0x2002);
+        ppu.write_register(0x2006, 0x23);
+        ppu.write_register(0x2006, 0xC0);
+        assert_eq!(ppu.vram_addr(), 0x23C0);
+    }
+
+    #[test]
+    fn test_ppuscroll_writes() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, 0x02);
+        assert_eq!(ppu.temp_addr(), 0x0800);
+        // X = $7D: coarse 15, fine 5
+        ppu.write_register(0x2005, 0x7D);
+        assert_eq!((ppu.temp_addr(), ppu.fine_x()), (0x080F, 5));
+        // Y = $5E: coarse 11, fine 6
+        ppu.write_register(0x2005, 0x5E);
+        assert_eq!(ppu.temp_addr(), 0x6000 | 0x0800 | 11 << 5 | 0x0F);
+        assert_eq!(ppu.vram_addr(), 0x0000);
+        // PPUSCROLL and PPUADDR share the toggle
+        ppu.write_register(0x2005, 0x00);
+        ppu.write_register(0x2006, 0x04);
+        assert_eq!(ppu.vram_addr(), 0x6904);
+    }
+
+    #[test]
+    fn test_palette_writes() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2006, 0x3F);
+        ppu.write_register(0x2006, 0x0F);
+        ppu.write_register(0x2007, 0x16);
+        ppu.write_register(0x2007, 0xEA);
+        assert_eq!(ppu.vram_addr(), 0x3F11);
+        assert_eq!((ppu.palette()[0x0F], ppu.palette()[0x10]), (0x16, 0x00));
+        // $3F10 is $3F00
+        assert_eq!(ppu.palette()[0x00], 0x2A);
+    }
+
+    #[test]
+    fn test_render_disabled_keeps_framebuffer() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2006, 0x3F);
+        ppu.write_register(0x2006, 0x00);
+        ppu.write_register(0x2007, 0x16);
+        ppu.set_render_enabled(false);
+        let run_frame = |ppu: &mut Ppu| (0..ppu.cycles_to_next_frame()).for_each(|_| ppu.clock());
+        run_frame(&mut ppu);
+        assert_eq!(ppu.frame(), 1);
+        assert!(ppu.framebuffer().iter().all(|&pixel| pixel == 0x00));
+        ppu.set_render_enabled(true);
+        run_frame(&mut ppu);
+        assert_eq!(ppu.frame(), 2);
+        assert!(ppu.framebuffer().iter().all(|&pixel| pixel == 0x16));
+    }
+
+    #[test]
+    fn test_frame_hash() {
+        let mut ppu = Ppu::new();
+        let blank_hash = ppu.frame_hash();
+        assert_eq!(blank_hash, Ppu::new().frame_hash());
+        ppu.framebuffer[SCREEN_WIDTH * 10 + 20] = 0x16;
+        assert_ne!(ppu.frame_hash(), blank_hash);
+    }
+}
@@ -1,19 +1,34 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
 use std::fs::{metadata, File};
+#[cfg(feature = "std")]
 use std::io::{self, Read};
+#[cfg(feature = "std")]
 use std::path;
 
-use crate::mapper;
+use crate::checksum;
+use crate::mapper::{self, Mapper, Mirroring};
+use crate::signal::IrqLine;
+use crate::state::StateReader;
 
 const KB: usize = 1024;
 
+const PRG_ROM_BANK_SIZE: usize = 16 * KB;
+const CHR_ROM_BANK_SIZE: usize = 8 * KB;
+const PRG_RAM_BANK_SIZE: usize = 8 * KB;
+const EXPONENT_SIZE_MARKER: u8 = 0x0F;
+
 const HEADER_SIZE: usize = 16;
 const TRAINER_SIZE: usize = 512;
 const HEADER_TITLE: &[u8; 4] = b"NES\x1A";
+// title blocks some dumpers append after the CHR data
+const FOOTER_SIZES: [usize; 2] = [127, 128];
 
 const FLAG6_MIRRORING: u8 = 1 << 0;
 const FLAG6_BATTERY: u8 = 1 << 1;
 const FLAG6_TRAINER: u8 = 1 << 2;
-const FLAG6_ALTER_NT_LAYOUT: u8 = 1 << 3;
 const FLAG6_MAPPER_LOWER_BITS: u8 = 0xF0;
 
 const FLAG7_VS_UNISYSTEM: u8 = 1 << 0;
@@ -25,64 +40,228 @@ const FLAG9_TV_SYSTEM: u8 = 1 << 0;
 const FLAG9_RESERVED_BITS: u8 = 0xFE;
 
 #[derive(Debug)]
-enum RomFormat {
+pub enum RomError {
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    UnknownFormat,
+    // starts with "NES" but the fourth byte, given here, isn't $1A
+    BadMagic(u8),
+    UnsupportedFormat,
+    ReservedBits,
+    InvalidPadding,
+    SizeMismatch { expected: usize, actual: usize },
+    UnsupportedMapper(u8),
+    // the header gives zero PRG ROM banks
+    MissingPrgRom,
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            RomError::Io(err) => write!(f, "{err}"),
+            RomError::UnknownFormat => write!(f, "unknown rom format"),
+            RomError::BadMagic(byte) => {
+                write!(f, "bad ines magic: expected 0x1A after \"NES\", got {byte:#04X}")
+            }
+            RomError::UnsupportedFormat => write!(f, "nes 2.0 roms not supported"),
+            RomError::ReservedBits => write!(f, "reserved bits is not zero"),
+            RomError::InvalidPadding => write!(f, "invalid padding value"),
+            RomError::SizeMismatch { expected, actual } => {
+                write!(f, "invalid rom size: expected {expected} bytes, got {actual}")
+            }
+            RomError::UnsupportedMapper(id) => {
+                write!(f, "unsupported mapper {id}, supported: {:?}", mapper::supported_ids())
+            }
+            RomError::MissingPrgRom => write!(f, "rom has no prg rom banks"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for RomError {
+    fn from(err: io::Error) -> Self {
+        RomError::Io(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RomFormat {
     Unknown,
     Ines,
+    // old dumps with garbage (often a ripper's signature) in bytes 7-15
+    ArchaicInes,
     Nes20,
 }
 
-#[derive(Debug)]
-enum Mirroring {
-    Horizontal,
-    Vertical,
-}
-
-#[derive(Debug)]
-enum ConsoleType {
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConsoleType {
     Default,
     VsUnisystem,
     Playchoice10,
 }
 
-#[derive(Debug)]
-enum TvSystem {
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TvSystem {
     NTSC,
     PAL,
 }
 
+// What the header says about a cartridge, for showing ROM info.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderSummary {
+    pub format: RomFormat,
+    pub mapper_id: u8,
+    pub prg_rom_banks: u8,
+    pub chr_rom_banks: u8,
+    pub mirroring: Mirroring,
+    pub has_battery: bool,
+    pub tv_system: TvSystem,
+    pub console_type: ConsoleType,
+}
+
 #[derive(Debug)]
 pub struct Cartridge {
     format: RomFormat,
+    mapper_id: u8,
     pub size: usize,
-    mirroring: Mirroring,
     console_type: ConsoleType,
     tv_system: TvSystem,
     has_battery: bool,
-    has_trainer: bool,
-    has_alter_nt: bool,
     prg_rom_banks: u8,
     chr_rom_banks: u8,
-    prg_ram_banks: u8,
-    prg_rom_size: usize,
-    chr_rom_size: usize,
-    mapper: mapper::Mapper0,
+    prg_ram_size: usize,
+    footer_size: usize,
+    // trailing bytes that were not a known footer, dropped on load
+    extra_bytes: usize,
+    // of the PRG and CHR ROM, without header, trainer or footer
+    crc32: u32,
+    mapper: Box<dyn Mapper>,
+    // forced mirroring for dumps whose header has it wrong
+    mirroring_override: Option<Mirroring>,
 }
 
 impl Cartridge {
     pub fn read(&self, address: u16) -> u8 {
         self.mapper.read(address)
     }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        self.mapper.write(address, data)
+    }
+
+    pub fn is_writable(&self, address: u16) -> bool {
+        self.mapper.is_writable(address)
+    }
+
+    pub fn power_on(&mut self) {
+        self.mapper.power_on();
+    }
+
+    pub fn clock(&mut self, irq: &mut IrqLine) {
+        self.mapper.clock(irq);
+    }
+
+    pub fn save_state(&self, bytes: &mut Vec<u8>) {
+        self.mapper.save_state(bytes);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Option<()> {
+        self.mapper.load_state(state)
+    }
+
+    // Lets tests stand in a mapper with behavior none of the supported ones have.
+    #[cfg(all(test, feature = "std"))]
+    pub(crate) fn set_mapper(&mut self, mapper: Box<dyn Mapper>) {
+        self.mapper = mapper;
+    }
+
+    // Battery-backed or work RAM at $6000-$7FFF, in bytes.
+    pub fn prg_ram_size(&self) -> usize {
+        self.prg_ram_size
+    }
+
+    pub fn tv_system(&self) -> TvSystem {
+        self.tv_system
+    }
+
+    pub fn console_type(&self) -> ConsoleType {
+        self.console_type
+    }
+
+    // Whether the dump had a recognized footer after the ROM data, which was ignored.
+    pub fn has_footer(&self) -> bool {
+        self.footer_size != 0
+    }
+
+    // Bytes after the ROM data that were not a recognized footer and were
+    // truncated; a bad dump or a wrong header if nonzero.
+    pub fn extra_bytes(&self) -> usize {
+        self.extra_bytes
+    }
+
+    // Identifies the game independently of its header, the same way ROM
+    // databases do.
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    pub fn header_summary(&self) -> HeaderSummary {
+        HeaderSummary {
+            format: self.format,
+            mapper_id: self.mapper_id,
+            prg_rom_banks: self.prg_rom_banks,
+            chr_rom_banks: self.chr_rom_banks,
+            mirroring: self.mirroring(),
+            has_battery: self.has_battery,
+            tv_system: self.tv_system,
+            console_type: self.console_type,
+        }
+    }
+
+    // The PRG and CHR data as loaded, for tools that analyze or patch it;
+    // unlike read, this ignores banking. chr_rom is empty for CHR RAM carts.
+    pub fn prg_rom(&self) -> &[u8] {
+        self.mapper.prg_rom()
+    }
+
+    pub fn chr_rom(&self) -> &[u8] {
+        self.mapper.chr().rom()
+    }
+
+    pub fn bank_mapping(&self) -> mapper::BankMapping {
+        self.mapper.bank_mapping()
+    }
+
+    // Mappers can switch the nametable layout at runtime, so this is the
+    // live value rather than the header bit.
+    pub fn mirroring(&self) -> Mirroring {
+        match self.mirroring_override {
+            Some(mirroring) if self.mapper.is_mirroring_from_header() => mirroring,
+            _ => self.mapper.mirroring(),
+        }
+    }
+
+    // Takes precedence over the header's mirroring. Ignored by mappers that
+    // switch mirroring themselves, as the header doesn't decide it there.
+    pub fn set_mirroring_override(&mut self, mirroring: Option<Mirroring>) {
+        self.mirroring_override = mirroring;
+    }
 }
 
-pub fn read(rom_path: &str) -> Result<Cartridge, String> {
-    let buffer = match read_file(rom_path) {
-        Ok(buff) => buff,
-        Err(err) => return Err(err.to_string()),
-    };
-    match get_rom_format(&buffer) {
-        RomFormat::Ines => Ok(parse_ines(&buffer)?),
-        RomFormat::Nes20 => Err("nes 2.0 roms not supported ".to_string()),
-        RomFormat::Unknown => Err("unknown rom format".to_string()),
+#[cfg(feature = "std")]
+pub fn read(rom_path: &str) -> Result<Cartridge, RomError> {
+    from_bytes(&read_file(rom_path)?)
+}
+
+pub fn from_bytes(buffer: &[u8]) -> Result<Cartridge, RomError> {
+    match get_rom_format(buffer) {
+        format @ (RomFormat::Ines | RomFormat::ArchaicInes) => Ok(parse_ines(buffer, format)?),
+        RomFormat::Nes20 => Err(RomError::UnsupportedFormat),
+        RomFormat::Unknown => match buffer {
+            [b'N', b'E', b'S', byte, ..] if *byte != HEADER_TITLE[3] => Err(RomError::BadMagic(*byte)),
+            _ => Err(RomError::UnknownFormat),
+        },
     }
 }
 
@@ -94,12 +273,14 @@ fn is_ines_header(buffer: &[u8]) -> bool {
     }
 }
 
-fn parse_ines(buffer: &[u8]) -> Result<Cartridge, String> {
+// Archaic headers only have bytes 4-6 trusted; the rest is treated as zero.
+fn parse_ines(buffer: &[u8], format: RomFormat) -> Result<Cartridge, RomError> {
+    let is_archaic = format == RomFormat::ArchaicInes;
     let mut iter = buffer.iter().skip(HEADER_TITLE.len());
     let prg_rom_banks = iter.next().unwrap();
     let chr_rom_banks = iter.next().unwrap();
-    let prg_rom_size = KB * 16 * (*prg_rom_banks as usize);
-    let chr_rom_size = KB * 8 * (*chr_rom_banks as usize);
+    let prg_rom_size = compute_prg_size(*prg_rom_banks, 0);
+    let chr_rom_size = compute_chr_size(*chr_rom_banks, 0);
     let mut total_size = HEADER_SIZE + prg_rom_size + chr_rom_size;
 
     let flags6 = iter.next().unwrap();
@@ -109,10 +290,11 @@ fn parse_ines(buffer: &[u8]) -> Result<Cartridge, String> {
     };
     let has_battery = (flags6 & FLAG6_BATTERY) != 0;
     let has_trainer = (flags6 & FLAG6_TRAINER) != 0;
-    let has_alter_nt_layout = (flags6 & FLAG6_ALTER_NT_LAYOUT) != 0;
     let mut mapper_id = (flags6 & FLAG6_MAPPER_LOWER_BITS) >> 4;
 
-    let flags7 = iter.next().unwrap();
+    // archaic dumps often have a ripper's signature from byte 7 on, which
+    // would otherwise end up in the mapper's high nibble
+    let flags7 = if is_archaic { &0 } else { iter.next().unwrap() };
     let is_vs_unisystem = (flags7 & FLAG7_VS_UNISYSTEM) != 0;
     let is_playchoice10 = (flags7 & FLAG7_PLAYCHOICE_10) != 0;
     let console_type = 
@@ -125,65 +307,108 @@ fn parse_ines(buffer: &[u8]) -> Result<Cartridge, String> {
         };
     mapper_id |= flags7 & FLAG7_MAPPER_UPPER_BITS;
 
-    let prg_ram_banks = iter.next().unwrap();
+    if is_archaic {
+        iter.nth(8);
+    }
+    let prg_ram_banks = if is_archaic { &0 } else { iter.next().unwrap() };
 
-    let flags9 = iter.next().unwrap();
+    let flags9 = if is_archaic { &0 } else { iter.next().unwrap() };
     let tv_system = if flags9 & FLAG9_TV_SYSTEM == 0 {
         TvSystem::NTSC
     } else {
         TvSystem::PAL
     };
     if flags9 & FLAG9_RESERVED_BITS != 0 {
-        return Err("reserved bits is not zero".to_string());
+        return Err(RomError::ReservedBits);
     }
-    let mut iter = iter.skip(1);
-    for _ in 0..5 {
-        if *iter.next().unwrap() != 0 {
-            return Err("invalid padding value".to_string());
+    let mut iter = iter.skip(if is_archaic { 0 } else { 1 });
+    if !is_archaic {
+        for _ in 0..5 {
+            if *iter.next().unwrap() != 0 {
+                return Err(RomError::InvalidPadding);
+            }
         }
     }
-    let iter = if has_trainer {
-        total_size += TRAINER_SIZE;
-        iter.skip(TRAINER_SIZE)
-    } else {
-        iter.skip(0)
-    };
+    let trainer_size = if has_trainer { TRAINER_SIZE } else { 0 };
+    total_size += trainer_size;
+    let iter = iter.skip(trainer_size);
 
-    if total_size != buffer.len() {
-        return Err("invalid buffer size".to_string());
+    if buffer.len() < total_size {
+        return Err(RomError::SizeMismatch {
+            expected: total_size,
+            actual: buffer.len(),
+        });
+    }
+    if prg_rom_size == 0 {
+        return Err(RomError::MissingPrgRom);
     }
+    let extra_size = buffer.len() - total_size;
+    let footer_size = if FOOTER_SIZES.contains(&extra_size) { extra_size } else { 0 };
 
     let prg_it = iter.clone().take(prg_rom_size).cloned();
-    let chr_it = iter.clone().skip(prg_rom_size).cloned();
+    let chr_it = iter.clone().skip(prg_rom_size).take(chr_rom_size).cloned();
     let prg_rom: Vec<u8> = Vec::from_iter(prg_it);
     let chr_rom: Vec<u8> = Vec::from_iter(chr_it);
+    let prg_ram_size = compute_prg_ram_size(*prg_ram_banks, None);
+    let crc32 = checksum::crc32(&[prg_rom.as_slice(), chr_rom.as_slice()].concat());
 
-    let mapper = match mapper_id {
-        0 => mapper::Mapper0::new(prg_rom, chr_rom),
-        _ => return Err("unsupported mapper".to_string()),
-    };
+    let chr = mapper::ChrMemory::new(chr_rom);
+    let mapper = mapper::from_id(mapper_id, prg_rom, chr, prg_ram_size, mirroring)
+        .ok_or(RomError::UnsupportedMapper(mapper_id))?;
     Ok(Cartridge {
-        format: RomFormat::Ines,
+        format,
+        mapper_id,
         size: total_size,
-        mirroring: mirroring,
-        console_type: console_type,
-        tv_system: tv_system,
-        has_battery: has_battery,
-        has_trainer: has_trainer,
-        has_alter_nt: has_alter_nt_layout,
+        console_type,
+        tv_system,
+        has_battery,
         prg_rom_banks: *prg_rom_banks,
         chr_rom_banks: *chr_rom_banks,
-        prg_ram_banks: *prg_ram_banks,
-        prg_rom_size: prg_rom_size,
-        chr_rom_size: chr_rom_size,
-        mapper: mapper,
+        prg_ram_size,
+        footer_size,
+        extra_bytes: extra_size - footer_size,
+        crc32,
+        mapper,
+        mirroring_override: None,
     })
 }
 
-fn parse_nes20(_buffer: &[u8]) -> Result<Cartridge, String> {
-    panic!("NES 2.0 rom format is not implemented");
+// PRG ROM size in bytes from header byte 4 and, for NES 2.0, the low nibble
+// of byte 9 (always 0 for iNES).
+pub fn compute_prg_size(banks_lsb: u8, banks_msb: u8) -> usize {
+    compute_rom_size(banks_lsb, banks_msb, PRG_ROM_BANK_SIZE)
+}
+
+// CHR ROM size in bytes from header byte 5 and, for NES 2.0, the high nibble
+// of byte 9 (always 0 for iNES).
+pub fn compute_chr_size(banks_lsb: u8, banks_msb: u8) -> usize {
+    compute_rom_size(banks_lsb, banks_msb, CHR_ROM_BANK_SIZE)
 }
 
+// An MSB nibble of 0xF switches NES 2.0 to exponent-multiplier notation:
+// the LSB is EEEEEEMM and the size is 2^E * (MM * 2 + 1) bytes.
+fn compute_rom_size(banks_lsb: u8, banks_msb: u8, bank_size: usize) -> usize {
+    if banks_msb == EXPONENT_SIZE_MARKER {
+        let exponent = (banks_lsb >> 2) as u32;
+        let multiplier = (banks_lsb & 0x03) as usize * 2 + 1;
+        2usize.saturating_pow(exponent).saturating_mul(multiplier)
+    } else {
+        ((banks_msb as usize) << 8 | banks_lsb as usize) * bank_size
+    }
+}
+
+// iNES byte 8 counts 8KB banks, with 0 meaning one bank for compatibility
+// with dumps that predate the field. NES 2.0 gives an explicit shift count
+// instead (64 << shift bytes, 0 for none), which takes precedence.
+pub fn compute_prg_ram_size(ines_banks: u8, nes20_shift: Option<u8>) -> usize {
+    match nes20_shift {
+        Some(0) => 0,
+        Some(shift) => 64usize << shift,
+        None => ines_banks.max(1) as usize * PRG_RAM_BANK_SIZE,
+    }
+}
+
+#[cfg(feature = "std")]
 fn read_file(rom_path: &str) -> Result<Vec<u8>, io::Error> {
     let path = path::Path::new(rom_path);
     let mut file = File::open(path)?;
@@ -199,7 +424,250 @@ fn get_rom_format(buffer: &[u8]) -> RomFormat {
     }
     if (buffer[7] & FLAG7_ROM_FORMAT_BITS) == 0x08 {
         RomFormat::Nes20
+    } else if buffer[12..HEADER_SIZE].iter().any(|byte| *byte != 0) {
+        RomFormat::ArchaicInes
     } else {
         RomFormat::Ines
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_ines(prg_rom_banks: u8, chr_rom_banks: u8) -> Vec<u8> {
+        let mut buffer = HEADER_TITLE.to_vec();
+        buffer.extend([prg_rom_banks, chr_rom_banks, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        buffer.resize(
+            HEADER_SIZE + KB * 16 * prg_rom_banks as usize + KB * 8 * chr_rom_banks as usize,
+            0,
+        );
+        buffer
+    }
+
+    #[test]
+    fn test_compute_size_banks() {
+        assert_eq!(compute_prg_size(0, 0), 0);
+        assert_eq!(compute_prg_size(2, 0), 32 * KB);
+        assert_eq!(compute_chr_size(1, 0), 8 * KB);
+        assert_eq!(compute_prg_size(0x00, 0x01), 256 * 16 * KB);
+        assert_eq!(compute_chr_size(0x10, 0x02), 0x210 * 8 * KB);
+    }
+
+    #[test]
+    fn test_compute_size_exponent() {
+        // 2^0 * 1
+        assert_eq!(compute_prg_size(0x00, 0x0F), 1);
+        // 2^4 * 3
+        assert_eq!(compute_chr_size(0b0001_0001, 0x0F), 48);
+        // 2^20 * 7
+        assert_eq!(compute_prg_size(0b0101_0011, 0x0F), 7 * 1024 * KB);
+        assert_eq!(compute_prg_size(0xFF, 0x0F), usize::MAX);
+    }
+
+    #[test]
+    fn test_rom_data() {
+        let mut buffer = build_ines(2, 1);
+        buffer[HEADER_SIZE] = 0xA2;
+        buffer[HEADER_SIZE + 32 * KB] = 0x3C;
+        let cartridge = from_bytes(&buffer).unwrap();
+        assert_eq!(cartridge.prg_rom().len(), 32 * KB);
+        assert_eq!(cartridge.chr_rom().len(), 8 * KB);
+        assert_eq!(cartridge.prg_rom()[0], 0xA2);
+        assert_eq!(cartridge.chr_rom()[0], 0x3C);
+
+        let cartridge = from_bytes(&build_ines(1, 0)).unwrap();
+        assert_eq!(cartridge.prg_rom().len(), 16 * KB);
+        assert!(cartridge.chr_rom().is_empty());
+    }
+
+    #[test]
+    fn test_from_bytes_core() {
+        use crate::bus::{Bus, CpuBus};
+        use crate::cpu::Cpu;
+
+        let mut buffer = build_ines(1, 1);
+        // LDX #$05; DEX; JAM
+        buffer[HEADER_SIZE..HEADER_SIZE + 4].copy_from_slice(&[0xA2, 0x05, 0xCA, 0x02]);
+        // reset vector -> $C000, the single bank is mirrored at $8000-$FFFF
+        buffer[HEADER_SIZE + 0x3FFC..HEADER_SIZE + 0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        let mut cpu = Cpu::new(CpuBus::new(from_bytes(&buffer).unwrap()));
+        cpu.power_on(None);
+        assert_eq!(cpu.pc(), 0xC000);
+        cpu.step_many(10);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.pc(), 0xC003);
+        assert_eq!(cpu.bus().peek(0x8001), 0x05);
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        let mut buffer = build_ines(1, 1);
+        buffer[3] = 0x00;
+        assert!(matches!(from_bytes(&buffer), Err(RomError::BadMagic(0x00))));
+        buffer[0] = b'X';
+        assert!(matches!(from_bytes(&buffer), Err(RomError::UnknownFormat)));
+        // a truncated header is not a magic problem
+        assert!(matches!(from_bytes(HEADER_TITLE), Err(RomError::UnknownFormat)));
+    }
+
+    #[test]
+    fn test_size_too_small() {
+        let mut buffer = build_ines(1, 1);
+        buffer.pop();
+        match parse_ines(&buffer, RomFormat::Ines) {
+            Err(RomError::SizeMismatch { expected, actual }) => {
+                assert_eq!(expected, HEADER_SIZE + 24 * KB);
+                assert_eq!(actual, HEADER_SIZE + 24 * KB - 1);
+            }
+            other => panic!("expected size mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_missing_prg_rom() {
+        let buffer = build_ines(0, 1);
+        assert!(matches!(from_bytes(&buffer), Err(RomError::MissingPrgRom)));
+    }
+
+    #[test]
+    fn test_size_too_large() {
+        let mut buffer = build_ines(1, 1);
+        buffer.extend([0xFF; 3]);
+        let cartridge = parse_ines(&buffer, RomFormat::Ines).unwrap();
+        assert_eq!(cartridge.size, HEADER_SIZE + 24 * KB);
+        assert!(!cartridge.has_footer());
+        assert_eq!(cartridge.extra_bytes(), 3);
+    }
+
+    #[test]
+    fn test_footer() {
+        let mut buffer = build_ines(1, 1);
+        buffer[HEADER_SIZE] = 0xEA;
+        buffer.extend([b'T'; 128]);
+        let cartridge = from_bytes(&buffer).unwrap();
+        assert!(cartridge.has_footer());
+        assert_eq!(cartridge.extra_bytes(), 0);
+        assert_eq!(cartridge.size, HEADER_SIZE + 24 * KB);
+        assert_eq!(cartridge.read(0x8000), 0xEA);
+    }
+
+    #[test]
+    fn test_archaic_ines() {
+        let mut buffer = build_ines(1, 1);
+        buffer[7..HEADER_SIZE].copy_from_slice(b"DiskDude!");
+        assert_eq!(get_rom_format(&buffer), RomFormat::ArchaicInes);
+        // 'D' in byte 7 would otherwise select mapper 0x40
+        let cartridge = from_bytes(&buffer).unwrap();
+        assert_eq!(cartridge.format, RomFormat::ArchaicInes);
+        assert_eq!(cartridge.prg_ram_size(), 8 * KB);
+        assert_eq!(cartridge.size, HEADER_SIZE + 24 * KB);
+    }
+
+    #[test]
+    fn test_archaic_mapper_low_nibble() {
+        let mut buffer = build_ines(2, 1);
+        buffer[6] = 0x21;
+        buffer[7..HEADER_SIZE].copy_from_slice(b"NI2.1\0\0\0\x01");
+        let cartridge = from_bytes(&buffer).unwrap();
+        assert_eq!(cartridge.mapper_id, 2);
+        assert_eq!(cartridge.mirroring(), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_prg_ram_inferred() {
+        use crate::bus::{Bus, CpuBus};
+
+        let cartridge = from_bytes(&build_ines(1, 1)).unwrap();
+        assert_eq!(cartridge.prg_ram_size(), 8 * KB);
+        let mut bus = CpuBus::new(cartridge);
+        bus.write(0x6000, 0x12);
+        bus.write(0x7FFF, 0x34);
+        assert_eq!(bus.peek(0x6000), 0x12);
+        assert_eq!(bus.peek(0x7FFF), 0x34);
+    }
+
+    #[test]
+    fn test_compute_prg_ram_size() {
+        assert_eq!(compute_prg_ram_size(0, None), 8 * KB);
+        assert_eq!(compute_prg_ram_size(4, None), 32 * KB);
+        assert_eq!(compute_prg_ram_size(0, Some(7)), 8 * KB);
+        assert_eq!(compute_prg_ram_size(1, Some(0)), 0);
+    }
+
+    #[test]
+    fn test_mirroring_from_mapper() {
+        let mut buffer = build_ines(1, 1);
+        buffer[6] |= FLAG6_MIRRORING;
+        assert_eq!(from_bytes(&buffer).unwrap().mirroring(), Mirroring::Vertical);
+
+        let mut buffer = build_ines(2, 0);
+        buffer[6] |= 7 << 4;
+        let mut cartridge = from_bytes(&buffer).unwrap();
+        assert_eq!(cartridge.mirroring(), Mirroring::SingleScreenLower);
+        cartridge.write(0x8000, 0x10);
+        assert_eq!(cartridge.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_mirroring_override() {
+        let mut cartridge = from_bytes(&build_ines(1, 1)).unwrap();
+        assert_eq!(cartridge.mirroring(), Mirroring::Horizontal);
+        cartridge.set_mirroring_override(Some(Mirroring::Vertical));
+        assert_eq!(cartridge.mirroring(), Mirroring::Vertical);
+        cartridge.set_mirroring_override(None);
+        assert_eq!(cartridge.mirroring(), Mirroring::Horizontal);
+
+        // AxROM picks its own nametable
+        let mut buffer = build_ines(2, 0);
+        buffer[6] |= 7 << 4;
+        let mut cartridge = from_bytes(&buffer).unwrap();
+        cartridge.set_mirroring_override(Some(Mirroring::Vertical));
+        assert_eq!(cartridge.mirroring(), Mirroring::SingleScreenLower);
+    }
+
+    #[test]
+    fn test_uxrom_reset_vector_in_fixed_bank() {
+        use crate::bus::{Bus, CpuBus};
+        use crate::cpu::Cpu;
+
+        let mut buffer = build_ines(4, 0);
+        buffer[6] |= 2 << 4;
+        for bank in 0..4 {
+            let bank_start = HEADER_SIZE + bank * 16 * KB;
+            buffer[bank_start] = bank as u8;
+            // reset vector -> $C000 + bank
+            buffer[bank_start + 0x3FFC..bank_start + 0x3FFE].copy_from_slice(&[bank as u8, 0xC0]);
+        }
+        let mut cartridge = from_bytes(&buffer).unwrap();
+        cartridge.write(0x8000, 2);
+        let mut cpu = Cpu::new(CpuBus::new(cartridge));
+        cpu.power_on(None);
+        assert_eq!(cpu.pc(), 0xC003);
+        assert_eq!(cpu.bus().peek(0x8000), 0);
+        assert_eq!(cpu.bus().peek(0xC000), 3);
+        cpu.bus_mut().write(0x8000, 1);
+        assert_eq!(cpu.bus().peek(0x8000), 1);
+    }
+
+    #[test]
+    fn test_header_summary() {
+        let mut buffer = build_ines(2, 1);
+        buffer[6] |= FLAG6_MIRRORING | FLAG6_BATTERY;
+        buffer[7] |= FLAG7_VS_UNISYSTEM;
+        let summary = from_bytes(&buffer).unwrap().header_summary();
+        assert_eq!(
+            summary,
+            HeaderSummary {
+                format: RomFormat::Ines,
+                mapper_id: 0,
+                prg_rom_banks: 2,
+                chr_rom_banks: 1,
+                mirroring: Mirroring::Vertical,
+                has_battery: true,
+                tv_system: TvSystem::NTSC,
+                console_type: ConsoleType::VsUnisystem,
+            }
+        );
+    }
+}
@@ -0,0 +1,121 @@
+// Interrupt lines between the CPU and the components that drive them. The bus
+// owns the lines; mappers are handed the IRQ line by reference when clocked,
+// and the PPU and APU outputs are copied onto the lines after each cycle.
+
+use alloc::vec::Vec;
+
+use crate::state::StateReader;
+
+// Devices that can pull the IRQ line low.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IrqSource {
+    Mapper,
+    Apu,
+}
+
+impl IrqSource {
+    const fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+// The shared IRQ line is wired-OR: it stays asserted while any source holds it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct IrqLine {
+    sources: u8,
+}
+
+impl IrqLine {
+    pub fn set(&mut self, source: IrqSource, is_asserted: bool) {
+        if is_asserted {
+            self.sources |= source.mask();
+        } else {
+            self.sources &= !source.mask();
+        }
+    }
+
+    pub fn is_asserted(&self) -> bool {
+        self.sources != 0
+    }
+
+    pub fn is_asserted_by(&self, source: IrqSource) -> bool {
+        self.sources & source.mask() != 0
+    }
+}
+
+// The PPU's NMI output. NMI is edge triggered, so a rising edge is latched
+// until the CPU takes it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct NmiLine {
+    is_asserted: bool,
+    is_edge_pending: bool,
+}
+
+impl NmiLine {
+    pub fn set(&mut self, is_asserted: bool) {
+        if is_asserted && !self.is_asserted {
+            self.is_edge_pending = true;
+        }
+        self.is_asserted = is_asserted;
+    }
+
+    pub fn is_asserted(&self) -> bool {
+        self.is_asserted
+    }
+
+    pub fn take_edge(&mut self) -> bool {
+        core::mem::take(&mut self.is_edge_pending)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Signals {
+    pub irq: IrqLine,
+    pub nmi: NmiLine,
+}
+
+impl Signals {
+    pub fn save_state(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&[
+            self.irq.sources,
+            self.nmi.is_asserted as u8,
+            self.nmi.is_edge_pending as u8,
+        ]);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Option<()> {
+        self.irq.sources = state.u8()?;
+        self.nmi.is_asserted = state.bool()?;
+        self.nmi.is_edge_pending = state.bool()?;
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irq_line_wired_or() {
+        let mut irq = IrqLine::default();
+        irq.set(IrqSource::Mapper, true);
+        irq.set(IrqSource::Apu, true);
+        irq.set(IrqSource::Mapper, false);
+        assert!(irq.is_asserted());
+        assert!(!irq.is_asserted_by(IrqSource::Mapper));
+        irq.set(IrqSource::Apu, false);
+        assert!(!irq.is_asserted());
+    }
+
+    #[test]
+    fn test_nmi_line_edge() {
+        let mut nmi = NmiLine::default();
+        nmi.set(true);
+        nmi.set(true);
+        assert!(nmi.take_edge());
+        assert!(!nmi.take_edge());
+        nmi.set(false);
+        nmi.set(true);
+        assert!(nmi.take_edge());
+    }
+}
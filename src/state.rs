@@ -0,0 +1,101 @@
+// Helpers for the device part of save states. Each component appends its
+// fields to a byte vector in a fixed little-endian order and reads them back
+// in the same order through StateReader.
+
+use alloc::vec::Vec;
+
+// Appends data with its length in front, for variable-sized memories.
+pub fn write_sized(bytes: &mut Vec<u8>, data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(data);
+}
+
+// Every read returns None once the bytes run out.
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.bytes.len() {
+            return None;
+        }
+        let (head, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(head)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.bytes(N)?.try_into().ok()
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.array::<1>()?[0])
+    }
+
+    // Anything but 0 or 1 is corrupt.
+    pub fn bool(&mut self) -> Option<bool> {
+        match self.u8()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.array()?))
+    }
+
+    // Data written by write_sized.
+    pub fn sized(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    // Reads data written by write_sized into memory of the same length.
+    pub fn sized_into(&mut self, memory: &mut [u8]) -> Option<()> {
+        let data = self.sized()?;
+        if data.len() != memory.len() {
+            return None;
+        }
+        memory.copy_from_slice(data);
+        Some(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reader() {
+        let mut bytes = vec![0x01, 0x34, 0x12];
+        write_sized(&mut bytes, &[0xAA, 0xBB]);
+        let mut reader = StateReader::new(&bytes);
+        assert_eq!(reader.bool(), Some(true));
+        assert_eq!(reader.u16(), Some(0x1234));
+        let mut memory = [0; 2];
+        assert_eq!(reader.sized_into(&mut memory), Some(()));
+        assert_eq!(memory, [0xAA, 0xBB]);
+        assert!(reader.is_empty());
+        assert_eq!(reader.u8(), None);
+        assert_eq!(StateReader::new(&[2]).bool(), None);
+    }
+}
@@ -0,0 +1,57 @@
+// Clock relationships of the two TV systems.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RegionTiming {
+    pub cpu_hz: u32,
+    // PPU dots per CPU cycle as numerator and denominator: 3/1 on NTSC,
+    // 16/5 (3.2) on PAL
+    pub ppu_dots_per_cpu_cycle: (u32, u32),
+    pub scanlines_per_frame: u32,
+    pub dots_per_scanline: u32,
+}
+
+pub const NTSC: RegionTiming = RegionTiming {
+    cpu_hz: 1_789_773,
+    ppu_dots_per_cpu_cycle: (3, 1),
+    scanlines_per_frame: 262,
+    dots_per_scanline: 341,
+};
+
+pub const PAL: RegionTiming = RegionTiming {
+    cpu_hz: 1_662_607,
+    ppu_dots_per_cpu_cycle: (16, 5),
+    scanlines_per_frame: 312,
+    dots_per_scanline: 341,
+};
+
+impl RegionTiming {
+    pub const fn dots_per_frame(&self) -> u32 {
+        self.scanlines_per_frame * self.dots_per_scanline
+    }
+
+    // CPU cycles in a frame, rounded up since frames don't end on a cycle boundary.
+    pub const fn cycles_per_frame(&self) -> usize {
+        let (dots, cycles) = self.ppu_dots_per_cpu_cycle;
+        (self.dots_per_frame() * cycles).div_ceil(dots) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ntsc() {
+        assert_eq!(NTSC.cpu_hz, 1_789_773);
+        assert_eq!(NTSC.dots_per_frame(), 89_342);
+        // 29780.67
+        assert_eq!(NTSC.cycles_per_frame(), 29_781);
+    }
+
+    #[test]
+    fn test_pal() {
+        assert_eq!(PAL.cpu_hz, 1_662_607);
+        assert_eq!(PAL.dots_per_frame(), 106_392);
+        // 33247.5
+        assert_eq!(PAL.cycles_per_frame(), 33_248);
+    }
+}
@@ -0,0 +1,530 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::cpu::TraceEntry;
+use crate::timing::{self, RegionTiming};
+
+const COLOR_RESET: &str = "\x1b[0m";
+const COLOR_MNEMONIC: &str = "\x1b[36m";
+const COLOR_CHANGED: &str = "\x1b[1;33m";
+const COLOR_FLAGS: &str = "\x1b[1;35m";
+
+// Which executed instructions make it into the trace. With no criteria set every
+// instruction is emitted, otherwise an instruction matching any criterion is.
+#[derive(Debug, Default)]
+pub struct TraceFilter {
+    pub pc_range: Option<RangeInclusive<u16>>,
+    pub opcodes: Vec<u8>,
+    pub mnemonics: Vec<String>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, trace: &TraceEntry) -> bool {
+        if self.pc_range.is_none() && self.opcodes.is_empty() && self.mnemonics.is_empty() {
+            return true;
+        }
+        self.pc_range.as_ref().is_some_and(|range| range.contains(&trace.pc))
+            || trace.opcode.is_some_and(|opcode| self.opcodes.contains(&opcode))
+            || self.mnemonics.iter().any(|mnemonic| mnemonic.eq_ignore_ascii_case(&trace.mnemonic))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum TraceFormat {
+    #[default]
+    Text,
+    // one JSON object per line
+    Json,
+    // the layout of nestest.log, for diffing against the golden log
+    Nestest,
+}
+
+// What the last column of the text format counts.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum CycleColumn {
+    // CYC=, CPU cycles since power on
+    #[default]
+    Cpu,
+    // DOT=, the same time in PPU dots at the region's ratio
+    PpuDots,
+}
+
+#[derive(Debug, Default)]
+pub struct TraceOptions {
+    pub format: TraceFormat,
+    pub color: bool,
+    pub filter: TraceFilter,
+    // number of instructions to execute, unbounded when None
+    pub limit: Option<usize>,
+    // run untraced until PC first reaches this address
+    pub arm_pc: Option<u16>,
+    // number of instructions to trace once armed, unbounded when None
+    pub armed_limit: Option<usize>,
+    pub cycle_column: CycleColumn,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RegisterChanges {
+    pub a: bool,
+    pub x: bool,
+    pub y: bool,
+    pub p: bool,
+    pub sp: bool,
+}
+
+impl RegisterChanges {
+    pub fn between(prev: &TraceEntry, curr: &TraceEntry) -> Self {
+        Self {
+            a: prev.a != curr.a,
+            x: prev.x != curr.x,
+            y: prev.y != curr.y,
+            p: prev.p != curr.p,
+            sp: prev.sp != curr.sp,
+        }
+    }
+}
+
+// First mismatch between two traces, as found by compare_traces.
+#[derive(Debug, PartialEq)]
+pub struct TraceDiff {
+    pub index: usize,
+    pub cycles: usize,
+    pub field: &'static str,
+    pub ours: String,
+    pub reference: String,
+}
+
+impl fmt::Display for TraceDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "instruction {} cycle {}: {} ours={} ref={}",
+            self.index, self.cycles, self.field, self.ours, self.reference
+        )
+    }
+}
+
+// Compares the traces field by field with the same leniency as TraceEntry's
+// PartialEq, reporting the first differing field. A trace that ends early
+// differs in "length".
+pub fn compare_traces(ours: &[TraceEntry], reference: &[TraceEntry]) -> Option<TraceDiff> {
+    for (index, (our, other)) in ours.iter().zip(reference).enumerate() {
+        let fields: [(&'static str, String, String); 10] = [
+            ("PC", format!("{:04X}", our.pc), format!("{:04X}", other.pc)),
+            ("opcode", format_opcode(our.opcode), format_opcode(other.opcode)),
+            ("mnemonic", our.mnemonic.clone(), other.mnemonic.clone()),
+            (
+                "address",
+                format!("{:?}", our.operand_address),
+                format!("{:?}", other.operand_address),
+            ),
+            ("A", format!("{:02X}", our.a), format!("{:02X}", other.a)),
+            ("X", format!("{:02X}", our.x), format!("{:02X}", other.x)),
+            ("Y", format!("{:02X}", our.y), format!("{:02X}", other.y)),
+            ("P", format!("{:02X}", our.p), format!("{:02X}", other.p)),
+            ("SP", format!("{:02X}", our.sp), format!("{:02X}", other.sp)),
+            ("CYC", our.cycles.to_string(), other.cycles.to_string()),
+        ];
+        for (field, ours_value, reference_value) in fields {
+            // reference logs only annotate some instructions
+            if field == "address" && (our.operand_address.is_none() || other.operand_address.is_none()) {
+                continue;
+            }
+            if ours_value != reference_value {
+                return Some(TraceDiff {
+                    index,
+                    cycles: other.cycles,
+                    field,
+                    ours: ours_value,
+                    reference: reference_value,
+                });
+            }
+        }
+    }
+    if ours.len() != reference.len() {
+        let index = ours.len().min(reference.len());
+        return Some(TraceDiff {
+            index,
+            cycles: index.checked_sub(1).map_or(0, |last| reference[last].cycles),
+            field: "length",
+            ours: ours.len().to_string(),
+            reference: reference.len().to_string(),
+        });
+    }
+    None
+}
+
+fn colorize(text: String, color: &str, is_enabled: bool) -> String {
+    if is_enabled {
+        format!("{color}{text}{COLOR_RESET}")
+    } else {
+        text
+    }
+}
+
+// The flags of p as NV-BDIZC, set ones upper case and colored, clear ones
+// lower case.
+fn format_flags(p: u8) -> String {
+    "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(|(bit, name)| match p & 0x80 >> bit {
+            0 => name.to_ascii_lowercase().to_string(),
+            _ => colorize(name.to_string(), COLOR_FLAGS, true),
+        })
+        .collect()
+}
+
+pub fn format_trace(trace: &TraceEntry, prev: Option<&TraceEntry>, color: bool) -> String {
+    format_text(trace, prev, color, format!("CYC={}", trace.cycles))
+}
+
+// format_trace with the cycle count converted to PPU dots.
+pub fn format_trace_dots(
+    trace: &TraceEntry,
+    prev: Option<&TraceEntry>,
+    color: bool,
+    timing: &RegionTiming,
+) -> String {
+    let (dots, cycles) = timing.ppu_dots_per_cpu_cycle;
+    let ppu_dots = trace.cycles as u64 * dots as u64 / cycles as u64;
+    format_text(trace, prev, color, format!("DOT={ppu_dots}"))
+}
+
+fn format_text(trace: &TraceEntry, prev: Option<&TraceEntry>, color: bool, cycle_column: String) -> String {
+    let changes = match prev {
+        Some(prev) if color => RegisterChanges::between(prev, trace),
+        _ => RegisterChanges::default(),
+    };
+    let operand = match trace.operand {
+        Some(op) => format!("{:02X}", op),
+        None => match trace.operand_address {
+            Some(addr) => format!("{addr:04X}"),
+            None => "".to_string(),
+        },
+    };
+    // colored traces spell out the flags after P
+    let mut p = colorize(format!("P={:02X}", trace.p), COLOR_CHANGED, changes.p);
+    if color {
+        p = format!("{p} {}", format_flags(trace.p));
+    }
+    format!(
+        "{pc:04X} {opcode:2} {mnemonic} {operand:<8} \
+              {a} {x} {y} {p} {sp} \
+              {cycle_column}",
+        pc = trace.pc,
+        opcode = format_opcode(trace.opcode),
+        mnemonic = colorize(format!("{:>4}", trace.mnemonic), COLOR_MNEMONIC, color),
+        operand = operand,
+        a = colorize(format!("A={:02X}", trace.a), COLOR_CHANGED, changes.a),
+        x = colorize(format!("X={:02X}", trace.x), COLOR_CHANGED, changes.x),
+        y = colorize(format!("Y={:02X}", trace.y), COLOR_CHANGED, changes.y),
+        p = p,
+        sp = colorize(format!("SP={:02X}", trace.sp), COLOR_CHANGED, changes.sp),
+        cycle_column = cycle_column
+    )
+}
+
+// A nestest.log line for trace, given the instruction bytes and disassembly
+// taken before it ran. The PPU column is derived from the cycle count at the
+// NTSC ratio, as the golden log's is.
+pub fn format_trace_nestest(trace: &TraceEntry, bytes: &[u8], disassembly: &str) -> String {
+    let (dots_per_cycle, _) = timing::NTSC.ppu_dots_per_cpu_cycle;
+    let dot = trace.cycles * dots_per_cycle as usize;
+    let dots_per_scanline = timing::NTSC.dots_per_scanline as usize;
+    let scanlines = timing::NTSC.scanlines_per_frame as usize;
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+    format!(
+        "{pc:04X}  {bytes:<8}  {disassembly:<31} A:{a:02X} X:{x:02X} Y:{y:02X} P:{p:02X} SP:{sp:02X} \
+         PPU:{scanline:>3},{dot:>3} CYC:{cycles}",
+        pc = trace.pc,
+        bytes = bytes.join(" "),
+        a = trace.a,
+        x = trace.x,
+        y = trace.y,
+        p = trace.p,
+        sp = trace.sp,
+        scanline = dot / dots_per_scanline % scanlines,
+        dot = dot % dots_per_scanline,
+        cycles = trace.cycles
+    )
+}
+
+// Blank for interrupt sequences, which have no opcode.
+fn format_opcode(opcode: Option<u8>) -> String {
+    opcode.map_or(String::new(), |opcode| format!("{opcode:02X}"))
+}
+
+// A value of the JSON trace. Both format_trace_json and the serde
+// Serialize impl are built from json_fields, so they share one shape.
+#[derive(Debug, PartialEq)]
+enum JsonValue {
+    String(String),
+    Number(usize),
+    Null,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for JsonValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            JsonValue::String(value) => serializer.serialize_str(value),
+            JsonValue::Number(value) => serializer.serialize_u64(*value as u64),
+            JsonValue::Null => serializer.serialize_none(),
+        }
+    }
+}
+
+fn json_hex(value: Option<String>) -> JsonValue {
+    value.map_or(JsonValue::Null, JsonValue::String)
+}
+
+// Registers and addresses are hex strings as in the text trace, cycles a
+// decimal number. The operand is padded to operand_size bytes, so $10 and
+// $0010 stay apart.
+fn json_fields(trace: &TraceEntry) -> [(&'static str, JsonValue); 12] {
+    let operand_digits = 2 * trace.operand_size as usize;
+    [
+        ("pc", JsonValue::String(format!("{:04X}", trace.pc))),
+        ("opcode", json_hex(trace.opcode.map(|opcode| format!("{opcode:02X}")))),
+        ("mnemonic", JsonValue::String(trace.mnemonic.clone())),
+        ("operand", json_hex(trace.operand.map(|op| format!("{op:0operand_digits$X}")))),
+        ("operand_size", JsonValue::Number(trace.operand_size as usize)),
+        (
+            "operand_address",
+            json_hex(
+                trace
+                    .operand_address
+                    .filter(|addr| *addr >= 0)
+                    .map(|addr| format!("{addr:04X}")),
+            ),
+        ),
+        ("a", JsonValue::String(format!("{:02X}", trace.a))),
+        ("x", JsonValue::String(format!("{:02X}", trace.x))),
+        ("y", JsonValue::String(format!("{:02X}", trace.y))),
+        ("p", JsonValue::String(format!("{:02X}", trace.p))),
+        ("sp", JsonValue::String(format!("{:02X}", trace.sp))),
+        ("cycles", JsonValue::Number(trace.cycles)),
+    ]
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c < ' ' => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+pub fn format_trace_json(trace: &TraceEntry) -> String {
+    let fields: Vec<String> = json_fields(trace)
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                JsonValue::String(value) => json_string(&value),
+                JsonValue::Number(value) => value.to_string(),
+                JsonValue::Null => "null".to_string(),
+            };
+            format!("{}:{value}", json_string(key))
+        })
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+// Serializes to the same object format_trace_json writes.
+#[cfg(feature = "serde")]
+impl serde::Serialize for TraceEntry {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let fields = json_fields(self);
+        let mut map = serializer.serialize_map(Some(fields.len()))?;
+        for (key, value) in &fields {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace_entry(a: u8, x: u8, p: u8) -> TraceEntry {
+        TraceEntry {
+            opcode: Some(0xEA),
+            mnemonic: "NOP".to_string(),
+            operand: None,
+            operand_size: 0,
+            operand_address: None,
+            operand_value: None,
+            a,
+            x,
+            y: 0,
+            p,
+            pc: 0xC000,
+            sp: 0xFD,
+            cycles: 7,
+        }
+    }
+
+    #[test]
+    fn test_compare_traces() {
+        let reference: Vec<TraceEntry> = (0..5).map(|a| trace_entry(a, 0x10, 0x24)).collect();
+        let mut ours: Vec<TraceEntry> = (0..5).map(|a| trace_entry(a, 0x10, 0x24)).collect();
+        assert_eq!(compare_traces(&ours, &reference), None);
+        ours[3].p = 0x26;
+        ours[3].x = 0x11;
+        let diff = compare_traces(&ours, &reference).unwrap();
+        assert_eq!(diff.index, 3);
+        assert_eq!(diff.field, "X");
+        assert_eq!(diff.to_string(), "instruction 3 cycle 7: X ours=11 ref=10");
+        let diff = compare_traces(&reference[..2], &reference).unwrap();
+        assert_eq!((diff.index, diff.field), (2, "length"));
+    }
+
+    #[test]
+    fn test_register_changes() {
+        let prev = trace_entry(0x00, 0x10, 0x24);
+        let curr = trace_entry(0x01, 0x10, 0x26);
+        let changes = RegisterChanges::between(&prev, &curr);
+        assert_eq!(
+            changes,
+            RegisterChanges { a: true, x: false, y: false, p: true, sp: false }
+        );
+        assert_eq!(RegisterChanges::between(&curr, &curr), RegisterChanges::default());
+    }
+
+    #[test]
+    fn test_format_trace_plain() {
+        let prev = trace_entry(0x00, 0x10, 0x24);
+        let curr = trace_entry(0x01, 0x10, 0x26);
+        let line = format_trace(&curr, Some(&prev), false);
+        assert!(!line.contains('\x1b'));
+        assert_eq!(line, "C000 EA  NOP          A=01 X=10 Y=00 P=26 SP=FD CYC=7");
+        let line = format_trace(&curr, Some(&prev), true);
+        assert!(line.contains(&format!("{COLOR_CHANGED}A=01{COLOR_RESET}")));
+        assert!(line.contains("X=10 "));
+        assert!(line.contains(&format!("{COLOR_CHANGED}P=26{COLOR_RESET} ")));
+        let set = |name: &str| format!("{COLOR_FLAGS}{name}{COLOR_RESET}");
+        assert!(line.contains(&format!("nv{}bd{}{}c ", set("-"), set("I"), set("Z"))));
+    }
+
+    #[test]
+    fn test_format_trace_dots() {
+        let mut entry = trace_entry(0x00, 0x00, 0x24);
+        entry.cycles = 10;
+        assert!(format_trace(&entry, None, false).ends_with(" CYC=10"));
+        assert!(format_trace_dots(&entry, None, false, &timing::NTSC).ends_with(" DOT=30"));
+        assert!(format_trace_dots(&entry, None, false, &timing::PAL).ends_with(" DOT=32"));
+    }
+
+    #[test]
+    fn test_format_trace_json() {
+        let mut entry = trace_entry(0x01, 0x10, 0x26);
+        entry.opcode = Some(0xAD);
+        entry.mnemonic = "LDA".to_string();
+        entry.operand = Some(0x0200);
+        entry.operand_size = 2;
+        entry.operand_address = Some(0x0200);
+        entry.cycles = 1234;
+        assert_eq!(
+            format_trace_json(&entry),
+            "{\"pc\":\"C000\",\"opcode\":\"AD\",\"mnemonic\":\"LDA\",\"operand\":\"0200\",\
+             \"operand_size\":2,\"operand_address\":\"0200\",\"a\":\"01\",\"x\":\"10\",\"y\":\"00\",\
+             \"p\":\"26\",\"sp\":\"FD\",\"cycles\":1234}"
+        );
+        // LDA $0010 and LDA $10
+        entry.operand = Some(0x0010);
+        assert!(format_trace_json(&entry).contains("\"operand\":\"0010\",\"operand_size\":2"));
+        entry.opcode = Some(0xA5);
+        entry.operand_size = 1;
+        assert!(format_trace_json(&entry).contains("\"operand\":\"10\",\"operand_size\":1"));
+        let entry = trace_entry(0x00, 0x00, 0x24);
+        let json = format_trace_json(&entry);
+        assert!(json.contains("\"operand\":null,\"operand_size\":0,\"operand_address\":null"));
+    }
+
+    #[test]
+    fn test_format_trace_json_escapes() {
+        let mut entry = trace_entry(0x00, 0x00, 0x24);
+        entry.mnemonic = "\"\\\n\u{1}".to_string();
+        assert!(format_trace_json(&entry).contains("\"mnemonic\":\"\\\"\\\\\\n\\u0001\","));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize_matches_json_trace() {
+        let mut entry = trace_entry(0x01, 0x10, 0x26);
+        entry.mnemonic = "\"LDA\"".to_string();
+        entry.operand = Some(0x10);
+        entry.operand_size = 1;
+        assert_eq!(serde_json::to_string(&entry).unwrap(), format_trace_json(&entry));
+        entry.opcode = None;
+        entry.operand = None;
+        assert_eq!(serde_json::to_string(&entry).unwrap(), format_trace_json(&entry));
+    }
+
+    #[test]
+    fn test_format_trace_nestest() {
+        let mut entry = trace_entry(0x00, 0x00, 0x24);
+        entry.opcode = Some(0x4C);
+        entry.mnemonic = "JMP".to_string();
+        assert_eq!(
+            format_trace_nestest(&entry, &[0x4C, 0xF5, 0xC5], "JMP $C5F5"),
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7"
+        );
+        entry.cycles = 10428;
+        assert!(format_trace_nestest(&entry, &[0xEA], "NOP").ends_with("PPU: 91,253 CYC:10428"));
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let entry = trace_entry(0x00, 0x10, 0x24);
+        assert!(TraceFilter::default().matches(&entry));
+        let filter = TraceFilter {
+            pc_range: Some(0xC100..=0xC200),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&entry));
+        let filter = TraceFilter {
+            pc_range: Some(0xC100..=0xC200),
+            opcodes: vec![0xEA],
+            ..Default::default()
+        };
+        assert!(filter.matches(&entry));
+        let filter = TraceFilter {
+            mnemonics: vec!["nop".to_string()],
+            ..Default::default()
+        };
+        assert!(filter.matches(&entry));
+    }
+
+    #[test]
+    fn test_filter_skips_interrupt_markers() {
+        let mut marker = trace_entry(0x00, 0x00, 0x24);
+        marker.opcode = None;
+        marker.mnemonic = "[NMI]".to_string();
+        let filter = TraceFilter {
+            opcodes: vec![0x00],
+            ..Default::default()
+        };
+        assert!(!filter.matches(&marker));
+        let mut brk = trace_entry(0x00, 0x00, 0x24);
+        brk.opcode = Some(0x00);
+        brk.mnemonic = "BRK".to_string();
+        assert!(filter.matches(&brk));
+        assert!(format_trace(&marker, None, false).starts_with("C000    [NMI]"));
+        assert!(format_trace_json(&marker).contains("\"opcode\":null,\"mnemonic\":\"[NMI]\""));
+    }
+}